@@ -0,0 +1,55 @@
+use bevyworld_lib::math_helpers::{closest_cell, ClosestCellIndex};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::Rng;
+use rand_pcg::Pcg64Mcg;
+use rand_seeder::Seeder;
+use voronoice::{BoundingBox, Point, Voronoi, VoronoiBuilder};
+
+const MAP_WIDTH: f64 = 1920.0;
+const MAP_HEIGHT: f64 = 1080.0;
+
+fn diagram(cell_count: usize) -> Voronoi {
+    let mut rng: Pcg64Mcg = Seeder::from("closest cell bench").make_rng();
+    let sites = (0..cell_count)
+        .map(|_| Point {
+            x: rng.gen_range(-MAP_WIDTH / 2.0..MAP_WIDTH / 2.0),
+            y: rng.gen_range(-MAP_HEIGHT / 2.0..MAP_HEIGHT / 2.0),
+        })
+        .collect();
+    VoronoiBuilder::default()
+        .set_sites(sites)
+        .set_bounding_box(BoundingBox::new_centered(MAP_WIDTH, MAP_HEIGHT))
+        .build()
+        .expect("Failed to create a voronoi diagram")
+}
+
+/// One row of a 1920x1080 map, the same points the generators look up.
+fn row_points() -> Vec<Point> {
+    (0..MAP_WIDTH as usize)
+        .map(|x| Point {
+            x: x as f64 - MAP_WIDTH / 2.0,
+            y: 0.0,
+        })
+        .collect()
+}
+
+fn bench_closest_cell(c: &mut Criterion) {
+    let points = row_points();
+    let mut group = c.benchmark_group("closest_cell");
+    for cell_count in [120, 1000, 5000] {
+        let diagram = diagram(cell_count);
+
+        group.bench_with_input(BenchmarkId::new("linear", cell_count), &diagram, |b, diagram| {
+            b.iter(|| points.iter().map(|point| closest_cell(black_box(point), diagram)).sum::<usize>())
+        });
+
+        let index = ClosestCellIndex::new(&diagram);
+        group.bench_with_input(BenchmarkId::new("grid_index", cell_count), &index, |b, index| {
+            b.iter(|| points.iter().map(|point| index.closest_cell(black_box(point))).sum::<usize>())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_closest_cell);
+criterion_main!(benches);
@@ -0,0 +1,690 @@
+//! Headless map generator.
+//!
+//! Runs one of the `bevyworld_lib::map_generators` without opening a window and
+//! writes the map layers to disk as PNGs, so maps can be batch generated on
+//! machines without a GPU.
+
+use bevyworld_lib::export::{export_png_layers, ElevationRamp};
+use bevyworld_lib::map_file::MapFile;
+use bevyworld_lib::map_generators::{
+    cube_map_faces, BiomeOptions, ContinentGrowth, ContinentOptions, DomainWarpOptions, ErosionOptions, FalloffCurve,
+    FalloffOptions, FalloffShape, GrayscaleMask, GridTopology, HeightMapOptions, HexLayout, HydrologyOptions, MapEdge,
+    MapGeneratorStrategies,
+    NoiseFunction, NoiseMapOptions, PlanetContinentOptions, PlanetOptions, PlanetProjection, PostProcessOptions,
+    ProgressSink, StencilOptions, WrapMode, CUBE_FACE_NAMES,
+};
+use bevyworld_lib::math_helpers;
+use bevy::log::tracing_subscriber::{self, EnvFilter};
+use rand_seeder::Seeder;
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Mutex;
+
+const USAGE: &str = "\
+Usage: mapgen --generator <noise_map|voronoi_continents|voronoi_heightmap|planet> --output <prefix> [options]
+
+Writes <prefix>_terrain.png and <prefix>_height.png, plus <prefix>_elevation.png
+when --color-ramp is given. Cube map planets also write the same layers for
+every face, as <prefix>_front_terrain.png and so on.
+
+Options:
+    --seed <string>              Seed string (default: \"Initial Seed\")
+    --width <n>                  Map width in tiles (default: 1920)
+    --height <n>                 Map height in tiles (default: 1080)
+    --grid <name>                Tile grid: square, hex (odd rows shoved) or hex-even (default: square)
+    --wrap <name>                Edges that wrap around: none, horizontal or torus (default: none).
+                                 Planets wrap the way their projection does
+    --projection <name>          Planet projection: equirectangular, mercator or cube-map
+                                 (default: equirectangular)
+    --cell-count <n>             Voronoi cell count (default: 120)
+    --land-percentage <f>        Voronoi continents land area percentage
+    --continents <n>             Voronoi continents number of continents. Planets grow
+                                 continents on the sphere when this or --land-percentage is given
+    --water-padding <f>          Voronoi continents water padding percentage
+    --lloyd-iterations <n>       Voronoi continents Lloyd relaxation iterations
+    --growth <name>              Voronoi continents growth (random, frontier, directional)
+    --growth-angle <f>           Direction of directional growth in degrees (default: 0)
+    --elongation <f>             How strongly directional growth sticks to its direction (default: 4)
+    --continent-weights <list>   Voronoi continents relative sizes, comma separated
+    --ocean-gap                  Keep water between voronoi or planet continents
+    --warp-amplitude <f>         Voronoi continents coastline warp in tiles (default: 40)
+    --warp-frequency <f>         Coastline warp noise frequency (default: 0.005)
+    --warp-octaves <n>           Coastline warp noise octaves (default: 4)
+    --warp-levels <n>            Times the coastline warp is applied to itself (default: 1)
+    --noise <name>               Noise map or planet fractal: fbm, ridged, billow or hybrid (default: fbm)
+    --octaves <n>                Noise map or planet octaves, at most 32 (default: 32, planets 8)
+    --frequency <f>              Noise map or planet frequency (default: 0.002, planets 0.004)
+    --lacunarity <f>             Noise map or planet frequency growth per octave (default: 2.094)
+    --persistence <f>            Noise map or planet amplitude falloff per octave (default: 0.5)
+    --falloff <shape>            Noise map falloff mask: none, square, radial, islands, archipelago,
+                                 edge or image (default: square)
+    --islands <n>                Islands of the islands and archipelago masks (default: 4 and 12)
+    --falloff-edge <edge>        Land edge of the edge mask: north, east, south or west (default: west)
+    --falloff-image <file>       Grayscale image for the image mask, white being land
+    --falloff-curve <name>       Falloff curve: linear, smoothstep or power (default: linear)
+    --falloff-exponent <f>       Exponent of the power curve (default: 2)
+    --falloff-strength <f>       How far the noise map is pulled towards the mask (default: 0.5)
+    --sea-level <f>              Noise map, planet or voronoi heightmap sea level
+    --stencil <file>             Grayscale sketch of where noise map or voronoi continents land goes
+    --stencil-strength <f>       How closely the map follows the stencil, 0 to 1 (default: 0.75)
+    --erosion <n>                Erode the terrain with this many rain droplets
+    --rivers <f>                 Add rivers where at least this many tiles drain through, and lakes
+    --biomes                     Classify land into biomes from temperature and moisture
+    --single-threaded            Generate on one thread, for debugging
+    --color-ramp <name>          Also write a color-ramped elevation layer (terrain, viridis, turbo)
+    --save <file>                Also save the map file (.ron for text, anything else is binary)
+";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum GeneratorName {
+    NoiseMap,
+    VoronoiContinents,
+    VoronoiHeightmap,
+    Planet,
+}
+
+#[derive(Debug, PartialEq)]
+struct Args {
+    generator: GeneratorName,
+    seed: String,
+    width: usize,
+    height: usize,
+    grid: GridTopology,
+    projection: PlanetProjection,
+    output: PathBuf,
+    cell_count: usize,
+    land_area_percentage: Option<f64>,
+    num_continents: Option<usize>,
+    water_padding_percentage: Option<f64>,
+    lloyd_iterations: Option<usize>,
+    growth: Option<ContinentGrowth>,
+    continent_weights: Option<Vec<f64>>,
+    ocean_gap: bool,
+    warp: DomainWarpOptions,
+    noise_function: Option<NoiseFunction>,
+    octaves: Option<usize>,
+    frequency: Option<f64>,
+    lacunarity: Option<f64>,
+    persistence: Option<f64>,
+    falloff: FalloffOptions,
+    sea_level: Option<f64>,
+    stencil: Option<StencilOptions>,
+    erosion: Option<usize>,
+    rivers: Option<f64>,
+    biomes: bool,
+    single_threaded: bool,
+    color_ramp: Option<ElevationRamp>,
+    save: Option<PathBuf>,
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{flag} needs a value"))?;
+    value.parse().map_err(|_| format!("Invalid value for {flag}: {value}"))
+}
+
+/// A map size, which has to be at least one tile.
+fn parse_size(flag: &str, value: Option<String>) -> Result<usize, String> {
+    match parse_value(flag, value)? {
+        0 => Err(format!("{flag} has to be at least 1")),
+        size => Ok(size),
+    }
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args, String> {
+    let mut generator = None;
+    let mut output = None;
+    let mut parsed = Args {
+        generator: GeneratorName::NoiseMap,
+        seed: "Initial Seed".to_string(),
+        width: 1920,
+        height: 1080,
+        grid: GridTopology::SQUARE,
+        projection: PlanetProjection::Equirectangular,
+        output: PathBuf::new(),
+        cell_count: 120,
+        land_area_percentage: None,
+        num_continents: None,
+        water_padding_percentage: None,
+        lloyd_iterations: None,
+        growth: None,
+        continent_weights: None,
+        ocean_gap: false,
+        warp: DomainWarpOptions::new(),
+        noise_function: None,
+        octaves: None,
+        frequency: None,
+        lacunarity: None,
+        persistence: None,
+        falloff: FalloffOptions::new(),
+        sea_level: None,
+        stencil: None,
+        erosion: None,
+        rivers: None,
+        biomes: false,
+        single_threaded: false,
+        color_ramp: None,
+        save: None,
+    };
+
+    let mut wrap = WrapMode::None;
+    let mut growth = None;
+    let mut growth_angle = 0.0;
+    let mut elongation = 4.0;
+    let mut falloff = None;
+    let mut islands = None;
+    let mut edge = MapEdge::default();
+    let mut falloff_image = None;
+    let mut curve = None;
+    let mut exponent = 2.0;
+    let mut stencil = None;
+    let mut stencil_strength = None;
+    let mut args = args.into_iter();
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--generator" => {
+                generator = Some(match args.next().as_deref() {
+                    Some("noise_map") => GeneratorName::NoiseMap,
+                    Some("voronoi_continents") => GeneratorName::VoronoiContinents,
+                    Some("voronoi_heightmap") => GeneratorName::VoronoiHeightmap,
+                    Some("planet") => GeneratorName::Planet,
+                    Some(other) => return Err(format!("Unknown generator: {other}")),
+                    None => return Err("--generator needs a value".to_string()),
+                })
+            }
+            "--seed" => parsed.seed = parse_value(&flag, args.next())?,
+            "--width" => parsed.width = parse_size(&flag, args.next())?,
+            "--height" => parsed.height = parse_size(&flag, args.next())?,
+            "--output" => output = Some(parse_value(&flag, args.next())?),
+            "--grid" => {
+                parsed.grid = match args.next().as_deref() {
+                    Some("square") => GridTopology::SQUARE,
+                    Some("hex") => GridTopology::hex(HexLayout::OddRows),
+                    Some("hex-even") => GridTopology::hex(HexLayout::EvenRows),
+                    Some(other) => return Err(format!("Unknown grid: {other}")),
+                    None => return Err("--grid needs a value".to_string()),
+                }
+            }
+            "--wrap" => {
+                wrap = match args.next().as_deref() {
+                    Some("none") => WrapMode::None,
+                    Some("horizontal") => WrapMode::Horizontal,
+                    Some("torus") => WrapMode::Torus,
+                    Some(other) => return Err(format!("Unknown wrap: {other}")),
+                    None => return Err("--wrap needs a value".to_string()),
+                }
+            }
+            "--projection" => {
+                parsed.projection = match args.next().as_deref() {
+                    Some("equirectangular") => PlanetProjection::Equirectangular,
+                    Some("mercator") => PlanetProjection::Mercator,
+                    Some("cube-map") => PlanetProjection::CubeMap,
+                    Some(other) => return Err(format!("Unknown projection: {other}")),
+                    None => return Err("--projection needs a value".to_string()),
+                }
+            }
+            "--cell-count" => parsed.cell_count = parse_value(&flag, args.next())?,
+            "--land-percentage" => parsed.land_area_percentage = Some(parse_value(&flag, args.next())?),
+            "--continents" => parsed.num_continents = Some(parse_value(&flag, args.next())?),
+            "--water-padding" => parsed.water_padding_percentage = Some(parse_value(&flag, args.next())?),
+            "--lloyd-iterations" => parsed.lloyd_iterations = Some(parse_value(&flag, args.next())?),
+            "--growth" => growth = Some(parse_value::<String>(&flag, args.next())?),
+            "--growth-angle" => growth_angle = parse_value(&flag, args.next())?,
+            "--elongation" => elongation = parse_value(&flag, args.next())?,
+            "--continent-weights" => {
+                let list: String = parse_value(&flag, args.next())?;
+                let weights: Result<Vec<f64>, _> = list.split(',').map(|weight| weight.trim().parse()).collect();
+                parsed.continent_weights = Some(weights.map_err(|_| format!("Invalid value for {flag}: {list}"))?);
+            }
+            "--ocean-gap" => parsed.ocean_gap = true,
+            "--warp-amplitude" => parsed.warp.set_amplitude(parse_value(&flag, args.next())?),
+            "--warp-frequency" => parsed.warp.set_frequency(parse_value(&flag, args.next())?),
+            "--warp-octaves" => parsed.warp.set_octaves(parse_value(&flag, args.next())?),
+            "--warp-levels" => parsed.warp.set_levels(parse_value(&flag, args.next())?),
+            "--noise" => {
+                parsed.noise_function = Some(match args.next().as_deref() {
+                    Some("fbm") => NoiseFunction::Fbm,
+                    Some("ridged") => NoiseFunction::Ridged,
+                    Some("billow") => NoiseFunction::Billow,
+                    Some("hybrid") => NoiseFunction::Hybrid,
+                    Some(other) => return Err(format!("Unknown noise: {other}")),
+                    None => return Err("--noise needs a value".to_string()),
+                })
+            }
+            "--octaves" => parsed.octaves = Some(parse_value(&flag, args.next())?),
+            "--frequency" => parsed.frequency = Some(parse_value(&flag, args.next())?),
+            "--lacunarity" => parsed.lacunarity = Some(parse_value(&flag, args.next())?),
+            "--persistence" => parsed.persistence = Some(parse_value(&flag, args.next())?),
+            "--falloff" => falloff = Some(parse_value::<String>(&flag, args.next())?),
+            "--islands" => islands = Some(parse_value(&flag, args.next())?),
+            "--falloff-edge" => {
+                edge = match args.next().as_deref() {
+                    Some("north") => MapEdge::North,
+                    Some("east") => MapEdge::East,
+                    Some("south") => MapEdge::South,
+                    Some("west") => MapEdge::West,
+                    Some(other) => return Err(format!("Unknown edge: {other}")),
+                    None => return Err("--falloff-edge needs a value".to_string()),
+                }
+            }
+            "--falloff-image" => falloff_image = Some(parse_value::<PathBuf>(&flag, args.next())?),
+            "--falloff-curve" => curve = Some(parse_value::<String>(&flag, args.next())?),
+            "--falloff-exponent" => exponent = parse_value(&flag, args.next())?,
+            "--falloff-strength" => parsed.falloff.set_strength(parse_value(&flag, args.next())?),
+            "--sea-level" => parsed.sea_level = Some(parse_value(&flag, args.next())?),
+            "--stencil" => stencil = Some(parse_value::<PathBuf>(&flag, args.next())?),
+            "--stencil-strength" => stencil_strength = Some(parse_value(&flag, args.next())?),
+            "--color-ramp" => {
+                parsed.color_ramp = Some(match args.next().as_deref() {
+                    Some("terrain") => ElevationRamp::Terrain,
+                    Some("viridis") => ElevationRamp::Viridis,
+                    Some("turbo") => ElevationRamp::Turbo,
+                    Some(other) => return Err(format!("Unknown color ramp: {other}")),
+                    None => return Err("--color-ramp needs a value".to_string()),
+                })
+            }
+            "--erosion" => parsed.erosion = Some(parse_value(&flag, args.next())?),
+            "--rivers" => parsed.rivers = Some(parse_value(&flag, args.next())?),
+            "--biomes" => parsed.biomes = true,
+            "--single-threaded" => parsed.single_threaded = true,
+            "--save" => parsed.save = Some(parse_value(&flag, args.next())?),
+            other => return Err(format!("Unknown argument: {other}")),
+        }
+    }
+
+    parsed.grid = parsed.grid.with_wrap(wrap);
+    parsed.growth = match growth.as_deref() {
+        None => None,
+        Some("random") => Some(ContinentGrowth::Random),
+        Some("frontier") => Some(ContinentGrowth::Frontier),
+        Some("directional") => Some(ContinentGrowth::Directional { angle: growth_angle, elongation }),
+        Some(other) => return Err(format!("Unknown growth: {other}")),
+    };
+    match falloff.as_deref() {
+        None => {}
+        Some("none") => parsed.falloff.set_shape(FalloffShape::None),
+        Some("square") => parsed.falloff.set_shape(FalloffShape::Square),
+        Some("radial") => parsed.falloff.set_shape(FalloffShape::Radial),
+        Some("islands") => parsed.falloff.set_shape(FalloffShape::MultiIsland { islands: islands.unwrap_or(4) }),
+        Some("archipelago") => parsed.falloff.set_shape(FalloffShape::Archipelago { islands: islands.unwrap_or(12) }),
+        Some("edge") => parsed.falloff.set_shape(FalloffShape::EdgeContinent { edge }),
+        Some("image") => {
+            let path = falloff_image.ok_or("--falloff image needs --falloff-image")?;
+            let mask = GrayscaleMask::load(&path).map_err(|error| format!("Failed to read {}: {error}", path.display()))?;
+            parsed.falloff.set_shape(FalloffShape::Image(mask));
+        }
+        Some(other) => return Err(format!("Unknown falloff: {other}")),
+    }
+    match curve.as_deref() {
+        None => {}
+        Some("linear") => parsed.falloff.set_curve(FalloffCurve::Linear),
+        Some("smoothstep") => parsed.falloff.set_curve(FalloffCurve::Smoothstep),
+        Some("power") => parsed.falloff.set_curve(FalloffCurve::Power { exponent }),
+        Some(other) => return Err(format!("Unknown falloff curve: {other}")),
+    }
+    if let Some(path) = stencil {
+        let mask = GrayscaleMask::load(&path).map_err(|error| format!("Failed to read {}: {error}", path.display()))?;
+        let mut options = StencilOptions::new(mask);
+        if let Some(strength) = stencil_strength {
+            options.set_strength(strength);
+        }
+        parsed.stencil = Some(options);
+    }
+    parsed.generator = generator.ok_or("--generator is required")?;
+    parsed.output = output.ok_or("--output is required")?;
+    Ok(parsed)
+}
+
+fn strategy(args: &Args) -> MapGeneratorStrategies {
+    match args.generator {
+        GeneratorName::NoiseMap => {
+            let mut seeder = Seeder::from(args.seed.as_str());
+            let seed = math_helpers::create_new_seed32(&mut seeder);
+            let mut options = NoiseMapOptions::new(args.width, args.height, seed);
+            if let Some(noise_function) = args.noise_function {
+                options.set_noise_function(noise_function);
+            }
+            if let Some(octaves) = args.octaves {
+                options.set_octaves(octaves);
+            }
+            if let Some(frequency) = args.frequency {
+                options.set_frequency(frequency);
+            }
+            if let Some(lacunarity) = args.lacunarity {
+                options.set_lacunarity(lacunarity);
+            }
+            if let Some(persistence) = args.persistence {
+                options.set_persistence(persistence);
+            }
+            options.set_falloff(args.falloff.clone());
+            options.set_stencil(args.stencil.clone());
+            if let Some(sea_level) = args.sea_level {
+                options.set_sea_level(sea_level);
+            }
+            MapGeneratorStrategies::NoiseMap(options)
+        }
+        GeneratorName::VoronoiContinents => {
+            let mut options = ContinentOptions::new(args.width, args.height, args.seed.clone(), args.cell_count);
+            if let Some(land_area_percentage) = args.land_area_percentage {
+                options.set_land_area_percentage(land_area_percentage);
+            }
+            if let Some(num_continents) = args.num_continents {
+                options.set_num_continents(num_continents);
+            }
+            if let Some(water_padding_percentage) = args.water_padding_percentage {
+                options.set_water_padding_percentage(water_padding_percentage);
+            }
+            if let Some(lloyd_iterations) = args.lloyd_iterations {
+                options.set_lloyd_iterations(lloyd_iterations);
+            }
+            if let Some(growth) = args.growth {
+                options.set_growth(growth);
+            }
+            if let Some(continent_weights) = &args.continent_weights {
+                options.set_continent_weights(continent_weights.clone());
+            }
+            options.set_ocean_gap(args.ocean_gap);
+            options.set_warp(args.warp.clone());
+            options.set_stencil(args.stencil.clone());
+            MapGeneratorStrategies::VoronoiContinents(options)
+        }
+        GeneratorName::VoronoiHeightmap => {
+            let mut options = HeightMapOptions::new(args.width, args.height, args.seed.clone(), args.cell_count);
+            if let Some(sea_level) = args.sea_level {
+                options.set_sea_level(sea_level);
+            }
+            MapGeneratorStrategies::VoronoiHeightmap(options)
+        }
+        GeneratorName::Planet => {
+            let mut seeder = Seeder::from(args.seed.as_str());
+            let seed = math_helpers::create_new_seed32(&mut seeder);
+            let mut options = PlanetOptions::new(args.width, args.height, seed);
+            options.set_projection(args.projection);
+            if let Some(noise_function) = args.noise_function {
+                options.set_noise_function(noise_function);
+            }
+            if let Some(octaves) = args.octaves {
+                options.set_octaves(octaves);
+            }
+            if let Some(frequency) = args.frequency {
+                options.set_frequency(frequency);
+            }
+            if let Some(lacunarity) = args.lacunarity {
+                options.set_lacunarity(lacunarity);
+            }
+            if let Some(persistence) = args.persistence {
+                options.set_persistence(persistence);
+            }
+            if let Some(sea_level) = args.sea_level {
+                options.set_sea_level(sea_level);
+            }
+            if args.num_continents.is_some() || args.land_area_percentage.is_some() {
+                let mut continents = PlanetContinentOptions::new(args.cell_count);
+                if let Some(land_area_percentage) = args.land_area_percentage {
+                    continents.set_land_area_percentage(land_area_percentage);
+                }
+                if let Some(num_continents) = args.num_continents {
+                    continents.set_num_continents(num_continents);
+                }
+                continents.set_ocean_gap(args.ocean_gap);
+                options.set_continents(Some(continents));
+            }
+            MapGeneratorStrategies::Planet(options)
+        }
+    }
+}
+
+/// Set up the same way as the window so a seed gives the same map in both.
+/// The passes run on the grid `strategy` generates on.
+fn post_process_options(args: &Args, strategy: &MapGeneratorStrategies) -> PostProcessOptions {
+    let mut options = PostProcessOptions::new();
+    options.set_erosion(args.erosion.map(|droplet_count| {
+        let mut erosion = ErosionOptions::default();
+        erosion.set_droplet_count(droplet_count);
+        erosion
+    }));
+    options.set_hydrology(args.rivers.map(HydrologyOptions::new));
+    options.set_biomes(args.biomes.then(BiomeOptions::default));
+    options.set_topology(strategy.fit_topology(args.grid));
+    options.set_projection(strategy.projection());
+    options
+}
+
+/// Prints progress to stderr whenever a phase starts and then every 10%.
+#[derive(Default)]
+struct ConsoleProgress {
+    /// The phase printed last and how many tenths of it were printed.
+    last: Mutex<(String, usize)>,
+    single_threaded: bool,
+}
+
+impl ProgressSink for ConsoleProgress {
+    fn report(&self, phase: &str, completed: usize, total: usize) {
+        let tenths = (completed * 10).checked_div(total).unwrap_or(10);
+        let mut last = self.last.lock().unwrap_or_else(|error| error.into_inner());
+        if last.0 != phase {
+            *last = (phase.to_string(), tenths);
+        } else if tenths > last.1 {
+            last.1 = tenths;
+        } else {
+            return;
+        }
+        eprintln!("{phase}: {}%", tenths * 10);
+    }
+
+    fn single_threaded(&self) -> bool {
+        self.single_threaded
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("{error}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    // Generator diagnostics go to stderr, filtered by RUST_LOG.
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")))
+        .init();
+
+    let strategy = strategy(&args);
+    let post_processing = post_process_options(&args, &strategy);
+    let progress = ConsoleProgress { single_threaded: args.single_threaded, ..Default::default() };
+    let tiles = strategy
+        .clone()
+        .generate_on_grid(post_processing.topology(), &progress)
+        .and_then(|tiles| {
+            post_processing.pipeline(args.seed.clone(), tiles.nrows(), tiles.ncols()).apply_with_progress(tiles, &progress)
+        });
+    let tiles = match tiles {
+        Ok(tiles) => tiles,
+        Err(error) => {
+            eprintln!("Failed to generate map: {error}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut layers = Vec::new();
+    if strategy.projection() == Some(PlanetProjection::CubeMap) {
+        for (name, face) in CUBE_FACE_NAMES.iter().zip(cube_map_faces(&tiles)) {
+            let mut prefix = args.output.clone().into_os_string();
+            prefix.push(format!("_{name}"));
+            layers.push((PathBuf::from(prefix), face));
+        }
+    }
+
+    if let Some(path) = &args.save {
+        let mut map_file = MapFile::new(args.seed.clone(), strategy, &tiles);
+        map_file.set_post_processing(&post_processing);
+        if let Err(error) = map_file.save(path) {
+            eprintln!("Failed to save {}: {error}", path.display());
+            return ExitCode::FAILURE;
+        }
+        println!("wrote {}", path.display());
+    }
+
+    layers.insert(0, (args.output.clone(), tiles));
+    for (prefix, tiles) in &layers {
+        match export_png_layers(tiles, prefix, args.color_ramp) {
+            Ok(written) => {
+                for path in written {
+                    println!("wrote {}", path.display());
+                }
+            }
+            Err(error) => {
+                eprintln!("Failed to export {}: {error}", prefix.display());
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_args, post_process_options, strategy, GeneratorName};
+    use bevyworld_lib::export::ElevationRamp;
+    use bevyworld_lib::map_generators::{
+        ContinentGrowth, DomainWarpOptions, FalloffCurve, FalloffShape, GridTopology, HexLayout, MapEdge,
+        MapGeneratorStrategies, NoiseFunction, PlanetProjection, WrapMode,
+    };
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let parsed = parse_args(args(&[
+            "--generator", "voronoi_continents", "--seed", "abc", "--width", "80", "--height", "60",
+            "--output", "maps/abc", "--continents", "3", "--color-ramp", "turbo",
+        ]))
+        .expect("Failed to parse args");
+
+        assert_eq!(parsed.generator, GeneratorName::VoronoiContinents);
+        assert_eq!(parsed.seed, "abc");
+        assert_eq!((parsed.width, parsed.height), (80, 60));
+        assert_eq!(parsed.num_continents, Some(3));
+        assert_eq!(parsed.land_area_percentage, None);
+        assert_eq!(parsed.color_ramp, Some(ElevationRamp::Turbo));
+        assert_eq!(parsed.grid, GridTopology::SQUARE);
+    }
+
+    #[test]
+    fn test_parse_grid_args() {
+        let parse_grid = |grid: &str| parse_args(args(&["--generator", "noise_map", "--output", "x", "--grid", grid]));
+
+        assert_eq!(parse_grid("hex").expect("Failed to parse args").grid, GridTopology::hex(HexLayout::OddRows));
+        assert_eq!(parse_grid("hex-even").expect("Failed to parse args").grid, GridTopology::hex(HexLayout::EvenRows));
+        assert_eq!(parse_grid("square").expect("Failed to parse args").grid, GridTopology::SQUARE);
+        assert!(parse_grid("triangle").is_err());
+    }
+
+    #[test]
+    fn test_parse_wrap_args() {
+        let parsed = parse_args(args(&["--generator", "noise_map", "--output", "x", "--wrap", "torus", "--grid", "hex"]))
+            .expect("Failed to parse args");
+        assert_eq!(parsed.grid, GridTopology::hex(HexLayout::OddRows).with_wrap(WrapMode::Torus));
+
+        let parse_wrap = |wrap: &str| parse_args(args(&["--generator", "noise_map", "--output", "x", "--wrap", wrap]));
+        assert_eq!(parse_wrap("horizontal").expect("Failed to parse args").grid.wrap(), WrapMode::Horizontal);
+        assert_eq!(parse_wrap("none").expect("Failed to parse args").grid, GridTopology::SQUARE);
+        assert!(parse_wrap("sphere").is_err());
+    }
+
+    #[test]
+    fn test_parse_growth_args() {
+        let parsed = parse_args(args(&[
+            "--generator", "voronoi_continents", "--output", "x", "--growth", "directional", "--growth-angle", "45",
+            "--continent-weights", "3, 1,1", "--ocean-gap", "--warp-levels", "2",
+        ]))
+        .expect("Failed to parse args");
+
+        assert_eq!(parsed.growth, Some(ContinentGrowth::Directional { angle: 45.0, elongation: 4.0 }));
+        assert_eq!(parsed.continent_weights, Some(vec![3.0, 1.0, 1.0]));
+        assert!(parsed.ocean_gap);
+        let mut warp = DomainWarpOptions::new();
+        warp.set_levels(2);
+        assert_eq!(parsed.warp, warp);
+        assert!(parse_args(args(&["--generator", "noise_map", "--output", "x", "--growth", "spiral"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_noise_args() {
+        let parsed = parse_args(args(&[
+            "--generator", "noise_map", "--output", "x", "--noise", "ridged", "--octaves", "6", "--sea-level", "0.1",
+            "--falloff", "edge", "--falloff-edge", "south", "--falloff-curve", "power", "--falloff-exponent", "3",
+        ]))
+        .expect("Failed to parse args");
+
+        let MapGeneratorStrategies::NoiseMap(options) = strategy(&parsed) else {
+            panic!("Parsed the wrong generator");
+        };
+        assert_eq!(options.noise_function(), NoiseFunction::Ridged);
+        assert_eq!(options.octaves(), 6);
+        assert_eq!(options.sea_level(), 0.1);
+        assert_eq!(options.falloff().shape(), &FalloffShape::EdgeContinent { edge: MapEdge::South });
+        assert_eq!(options.falloff().curve(), FalloffCurve::Power { exponent: 3.0 });
+        assert_eq!(options.falloff().strength(), 0.5);
+        assert!(parse_args(args(&["--generator", "noise_map", "--output", "x", "--noise", "worley"])).is_err());
+        assert!(parse_args(args(&["--generator", "noise_map", "--output", "x", "--falloff", "image"])).is_err());
+        assert!(parse_args(args(&["--generator", "noise_map", "--output", "x", "--stencil", "missing.png"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_planet_args() {
+        let parsed = parse_args(args(&[
+            "--generator", "planet", "--output", "x", "--projection", "cube-map", "--continents", "4", "--ocean-gap",
+            "--cell-count", "500",
+        ]))
+        .expect("Failed to parse args");
+
+        let planet = strategy(&parsed);
+        let MapGeneratorStrategies::Planet(options) = &planet else {
+            panic!("Parsed the wrong generator");
+        };
+        assert_eq!(options.projection(), PlanetProjection::CubeMap);
+        let continents = options.continents().expect("Continents weren't asked for");
+        assert_eq!((continents.cell_count(), continents.num_continents()), (500, 4));
+        assert!(continents.ocean_gap());
+        let post_processing = post_process_options(&parsed, &planet);
+        assert_eq!(post_processing.projection(), Some(PlanetProjection::CubeMap));
+        assert_eq!(post_processing.topology().wrap(), WrapMode::None);
+
+        let parsed = parse_args(args(&["--generator", "planet", "--output", "x", "--wrap", "torus"])).expect("Failed to parse args");
+        let planet = strategy(&parsed);
+        let MapGeneratorStrategies::Planet(options) = &planet else {
+            panic!("Parsed the wrong generator");
+        };
+        assert_eq!(options.continents(), None);
+        assert_eq!(post_process_options(&parsed, &planet).topology().wrap(), WrapMode::Horizontal);
+        assert!(parse_args(args(&["--generator", "planet", "--output", "x", "--projection", "globe"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert!(parse_args(args(&["--output", "map"])).is_err());
+        assert!(parse_args(args(&["--generator", "noise_map"])).is_err());
+        assert!(parse_args(args(&["--generator", "nope", "--output", "map"])).is_err());
+        assert!(parse_args(args(&["--generator", "noise_map", "--output", "map", "--width", "wide"])).is_err());
+        assert_eq!(
+            parse_args(args(&["--generator", "noise_map", "--output", "map", "--width", "0"])).err().as_deref(),
+            Some("--width has to be at least 1")
+        );
+        assert!(parse_args(args(&["--generator", "noise_map", "--output", "map", "--height", "0"])).is_err());
+    }
+
+    #[test]
+    fn test_generate_uses_requested_size() {
+        let parsed = parse_args(args(&["--generator", "noise_map", "--width", "8", "--height", "4", "--output", "x"]))
+            .expect("Failed to parse args");
+        let tiles = strategy(&parsed).generate().expect("Failed to generate map");
+
+        assert_eq!(tiles.shape(), (8, 4));
+    }
+}
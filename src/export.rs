@@ -0,0 +1,180 @@
+use crate::map_generators::{Tile, TileType};
+use image::{ImageBuffer, Luma, Rgb, RgbImage};
+use nalgebra::DMatrix;
+
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error("Can't export an empty map")]
+    EmptyMap,
+    #[error(transparent)]
+    ImageError(#[from] image::ImageError),
+    #[error(transparent)]
+    GradientError(#[from] colorgrad::CustomGradientError),
+}
+
+/// Color ramps for the elevation layer.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum ElevationRamp {
+    #[default]
+    Terrain,
+    Viridis,
+    Turbo,
+}
+
+impl ElevationRamp {
+    fn color_at(&self, gradient: &colorgrad::Gradient, t: f64) -> Rgb<u8> {
+        match self {
+            Self::Terrain => {
+                let [r, g, b, _] = gradient.at(t).to_rgba8();
+                Rgb([r, g, b])
+            }
+            Self::Viridis => {
+                let color = colorous::VIRIDIS.eval_continuous(t);
+                Rgb([color.r, color.g, color.b])
+            }
+            Self::Turbo => {
+                let color = colorous::TURBO.eval_continuous(t);
+                Rgb([color.r, color.g, color.b])
+            }
+        }
+    }
+}
+
+fn terrain_gradient() -> Result<colorgrad::Gradient, ExportError> {
+    Ok(colorgrad::CustomGradient::new()
+        .html_colors(&["#0b2a5b", "#2b65b0", "#e8d9a0", "#5e9940", "#3d6b2a", "#8a7556", "#ffffff"])
+        .domain(&[0.0, 0.35, 0.4, 0.45, 0.7, 0.85, 1.0])
+        .build()?)
+}
+
+pub fn terrain_color(terrain: TileType) -> Rgb<u8> {
+    match terrain {
+        TileType::Water => Rgb([38, 84, 161]),
+        TileType::Grassland => Rgb([94, 153, 64]),
+        TileType::Coast => Rgb([232, 214, 160]),
+        TileType::ThickForest => Rgb([34, 92, 44]),
+        TileType::LightForest => Rgb([72, 132, 58]),
+        TileType::Desert => Rgb([222, 184, 108]),
+        TileType::Ice => Rgb([232, 244, 250]),
+        TileType::Tundra => Rgb([150, 160, 130]),
+        TileType::Mountain => Rgb([128, 118, 108]),
+        TileType::River => Rgb([70, 130, 200]),
+        TileType::Lake => Rgb([56, 110, 180]),
+        TileType::SeaIce => Rgb([200, 222, 240]),
+    }
+}
+
+/// Elevation of every tile mapped onto `0.0..=1.0` using the lowest and
+/// highest tile in the map. A flat map comes back as all zeros.
+fn normalized_elevation(tiles: &DMatrix<Tile>) -> Result<DMatrix<f64>, ExportError> {
+    if tiles.is_empty() {
+        return Err(ExportError::EmptyMap);
+    }
+    let (min, max) = tiles.iter().fold((f64::MAX, f64::MIN), |(min, max), tile| {
+        (min.min(tile.elevation()), max.max(tile.elevation()))
+    });
+    let range = max - min;
+
+    Ok(tiles.map(|tile| if range > 0.0 { (tile.elevation() - min) / range } else { 0.0 }))
+}
+
+/// Terrain classes, one pixel per tile with x to the right and y downwards.
+pub fn terrain_image(tiles: &DMatrix<Tile>) -> Result<RgbImage, ExportError> {
+    if tiles.is_empty() {
+        return Err(ExportError::EmptyMap);
+    }
+    Ok(RgbImage::from_fn(tiles.nrows() as u32, tiles.ncols() as u32, |x, y| {
+        terrain_color(tiles[(x as usize, y as usize)].terrain())
+    }))
+}
+
+/// 16-bit grayscale heightmap normalized over the elevation range of the map.
+pub fn heightmap_image(tiles: &DMatrix<Tile>) -> Result<ImageBuffer<Luma<u16>, Vec<u16>>, ExportError> {
+    let elevation = normalized_elevation(tiles)?;
+    Ok(ImageBuffer::from_fn(tiles.nrows() as u32, tiles.ncols() as u32, |x, y| {
+        Luma([(elevation[(x as usize, y as usize)] * u16::MAX as f64).round() as u16])
+    }))
+}
+
+pub fn elevation_ramp_image(tiles: &DMatrix<Tile>, ramp: ElevationRamp) -> Result<RgbImage, ExportError> {
+    let elevation = normalized_elevation(tiles)?;
+    let gradient = terrain_gradient()?;
+    Ok(RgbImage::from_fn(tiles.nrows() as u32, tiles.ncols() as u32, |x, y| {
+        ramp.color_at(&gradient, elevation[(x as usize, y as usize)])
+    }))
+}
+
+/// Writes `<prefix>_terrain.png`, `<prefix>_height.png` and, when a ramp is
+/// given, `<prefix>_elevation.png`. Returns the paths that were written.
+pub fn export_png_layers(
+    tiles: &DMatrix<Tile>,
+    prefix: &Path,
+    ramp: Option<ElevationRamp>,
+) -> Result<Vec<PathBuf>, ExportError> {
+    let layer_path = |layer: &str| {
+        let mut file_name = prefix.file_name().unwrap_or_default().to_os_string();
+        file_name.push(format!("_{layer}.png"));
+        prefix.with_file_name(file_name)
+    };
+
+    let mut written = Vec::new();
+
+    let terrain_path = layer_path("terrain");
+    terrain_image(tiles)?.save(&terrain_path)?;
+    written.push(terrain_path);
+
+    let height_path = layer_path("height");
+    heightmap_image(tiles)?.save(&height_path)?;
+    written.push(height_path);
+
+    if let Some(ramp) = ramp {
+        let elevation_path = layer_path("elevation");
+        elevation_ramp_image(tiles, ramp)?.save(&elevation_path)?;
+        written.push(elevation_path);
+    }
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{elevation_ramp_image, heightmap_image, terrain_color, terrain_image, ElevationRamp, ExportError};
+    use crate::map_generators::{Tile, TileType};
+    use nalgebra::DMatrix;
+
+    fn sample_tiles() -> DMatrix<Tile> {
+        DMatrix::from_fn(4, 2, |x, y| {
+            let terrain = if x < 2 { TileType::Water } else { TileType::Grassland };
+            Tile::new(terrain, (x + y * 4) as f64 - 2.0)
+        })
+    }
+
+    #[test]
+    fn test_terrain_image() {
+        let image = terrain_image(&sample_tiles()).expect("Failed to build terrain image");
+        assert_eq!(image.dimensions(), (4, 2));
+        assert_eq!(*image.get_pixel(0, 1), terrain_color(TileType::Water));
+        assert_eq!(*image.get_pixel(3, 0), terrain_color(TileType::Grassland));
+    }
+
+    #[test]
+    fn test_heightmap_image_is_normalized() {
+        let image = heightmap_image(&sample_tiles()).expect("Failed to build heightmap");
+        assert_eq!(image.get_pixel(0, 0).0[0], 0);
+        assert_eq!(image.get_pixel(3, 1).0[0], u16::MAX);
+    }
+
+    #[test]
+    fn test_flat_and_empty_maps() {
+        let flat = DMatrix::from_element(3, 3, Tile::new(TileType::Water, 0.5));
+        let image = heightmap_image(&flat).expect("Failed to build heightmap");
+        assert!(image.pixels().all(|pixel| pixel.0[0] == 0));
+
+        let empty = DMatrix::from_element(0, 0, Tile::default());
+        assert!(matches!(elevation_ramp_image(&empty, ElevationRamp::Terrain), Err(ExportError::EmptyMap)));
+    }
+}
@@ -1,2 +1,4 @@
+pub mod export;
+pub mod map_file;
 pub mod map_generators;
 pub mod math_helpers;
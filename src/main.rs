@@ -1,11 +1,24 @@
-use noise::math;
 use rand_seeder::Seeder;
 
-use bevyworld_lib::{map_generators::{self, NoiseMapOptions, TileType}, math_helpers};
+use bevyworld_lib::{
+    map_file::MapFile,
+    map_generators::{
+        BiomeOptions, ContinentGrowth, ContinentOptions, DomainWarpOptions, ErosionOptions, Falloff, FalloffCurve,
+        FalloffOptions, FalloffShape, GrayscaleMask, GridTopology, HeightMapOptions, HexLayout, HydrologyOptions, MapEdge, MapGeneratorError,
+        MapGeneratorStrategies, NoiseFunction, NoiseMapOptions, voronoi_continent_map, PlanetContinentOptions, PlanetOptions,
+        PlanetProjection, PostProcessOptions, ProgressSink, StencilOptions, Tile, TileShape, TileType, WrapMode,
+    },
+    math_helpers,
+};
+use nalgebra::DMatrix;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 use bevy::{
     core_pipeline::core_2d::Camera2dBundle, diagnostic::LogDiagnosticsPlugin,
-    ecs::{query::QuerySingleError, world}, math::uvec2, prelude::*, window::PrimaryWindow,
+    asset::LoadState, ecs::{query::QuerySingleError, system::SystemParam}, math::{mat3, uvec2, vec2, vec3}, prelude::*, tasks::{block_on, poll_once, AsyncComputeTaskPool, Task},
+    window::PrimaryWindow,
 };
 
 use bevy_inspector_egui::bevy_egui::{
@@ -16,7 +29,7 @@ use bevy_inspector_egui::bevy_egui::{
 mod debug_plugin;
 use debug_plugin::DebugPlugin;
 
-use bevy_fast_tilemap::{FastTileMapPlugin, Map, MapBundleManaged, MapIndexer};
+use bevy_fast_tilemap::{FastTileMapPlugin, Map, MapBundleManaged, MapIndexer, TileProjection, IDENTITY};
 
 fn main() {
     App::new()
@@ -36,13 +49,21 @@ fn main() {
         ))
         .insert_resource::<UiState>(UiState::new())
         .init_resource::<WorldCoords>()
+        .init_resource::<CurrentMap>()
+        .init_resource::<ContinentIds>()
+        .init_resource::<MapGeneration>()
+        .init_resource::<StencilImage>()
         .add_plugins(EguiPlugin)
         .add_plugins(DebugPlugin)
-        .add_plugins(FastTileMapPlugin::default())
+        .add_plugins(FastTileMapPlugin {
+            pre_sample_code: Some(CONTINENT_TINT_PRE_SAMPLE.to_string()),
+            post_sample_code: Some(CONTINENT_TINT_POST_SAMPLE.to_string()),
+            ..default()
+        })
         .add_systems(Startup, setup)
         .add_systems(Startup, setup_world_coords)
-        .add_systems(Update, ui_system)
-        .add_systems(Update, cursor_system)
+        .add_systems(Update, (ui_system, finish_generation_system, load_stencil_system))
+        .add_systems(Update, (cursor_system, camera_system))
         .run();
 }
 
@@ -56,6 +77,8 @@ struct WorldCoordsText;
 #[derive(Component)]
 struct WindowCoordsText;
 #[derive(Component)]
+struct TileCoordsText;
+#[derive(Component)]
 struct MainCamera;
 
 
@@ -70,7 +93,7 @@ fn setup_world_coords(
             TextSection::new(
                 "world mouse position: ",
                 TextStyle {
-                    font_size: font_size,
+                    font_size,
                     ..default()
                 }
             ),
@@ -96,7 +119,7 @@ fn setup_world_coords(
             TextSection::new(
                 "window mouse position: ",
                 TextStyle {
-                    font_size: font_size,
+                    font_size,
                     ..default()
                 }
             ),
@@ -117,16 +140,47 @@ fn setup_world_coords(
         WindowCoordsText
     ));
 
+    commands.spawn((
+        TextBundle::from_sections([
+            TextSection::new(
+                "tile under mouse: ",
+                TextStyle {
+                    font_size,
+                    ..default()
+                }
+            ),
+            TextSection::from_style(
+                TextStyle {
+                    font_size: 30.0,
+                    ..default()
+                  }
+            ),
+        ])
+        .with_text_justify(JustifyText::Center)
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(145.0),
+            right: Val::Px(5.0),
+            ..default()
+        }),
+        TileCoordsText
+    ));
 }
 
+/// The text of one of the mouse readouts, kept apart from the other two.
+type CoordsText<'w, 's, Shown, Other, Another> =
+    Query<'w, 's, &'static mut Text, (With<Shown>, Without<Other>, Without<Another>)>;
+
 fn cursor_system(
     mut mycoords: ResMut<WorldCoords>,
     // query to get the window (so we can read the current cursor position)
-    mut q_world_text: Query<&mut Text, (With<WorldCoordsText>, Without<WindowCoordsText>)>,
-    mut q_window_text: Query<&mut Text, (With<WindowCoordsText>, Without<WorldCoordsText>)>,
+    mut q_world_text: CoordsText<WorldCoordsText, WindowCoordsText, TileCoordsText>,
+    mut q_window_text: CoordsText<WindowCoordsText, WorldCoordsText, TileCoordsText>,
+    mut q_tile_text: CoordsText<TileCoordsText, WorldCoordsText, WindowCoordsText>,
     q_window: Query<&Window, With<PrimaryWindow>>,
     // query to get camera transform
     q_camera: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
+    shown_map: ShownMap,
 ) {
     // get the camera info and transform
     // assuming there is exactly one main camera entity, so Query::single() is OK
@@ -135,6 +189,7 @@ fn cursor_system(
     // There is only one primary window, so we can similarly get it from the query:
     let window = q_window.single();
 
+    let mut tile_text = "N/A".to_string();
     let (world_text, window_text) = match window.cursor_position() {
         Some(cursor_position) =>
         {
@@ -143,6 +198,9 @@ fn cursor_system(
             match world_position {
                 Some(world_position) => {
                     mycoords.0 = world_position;
+                    if let Some((x, y)) = shown_map.tile_at(world_position) {
+                        tile_text = format!("({x}, {y})");
+                    }
                     (format!("({}, {})", world_position.x, world_position.y), 
                     format!("({}, {})", cursor_position.x, cursor_position.y))
                 },
@@ -166,106 +224,1060 @@ fn cursor_system(
     for mut text in &mut q_window_text {
         text.sections[1].value = window_text.clone();
     }
+    for mut text in &mut q_tile_text {
+        text.sections[1].value = tile_text.clone();
+    }
+}
+
+/// Screen widths per second the camera pans at.
+const CAMERA_SPEED: f32 = 0.5;
+
+/// Pans the camera with the arrow keys or WASD. On a map that wraps the
+/// camera jumps back by a whole map once it crosses a seam, which the copies
+/// of the map hide, so it can scroll on forever.
+fn camera_system(
+    keys: Res<ButtonInput<KeyCode>>,
+    time: Res<Time>,
+    mut contexts: EguiContexts,
+    layouts: Query<&MapLayout>,
+    mut cameras: Query<(&mut Transform, &OrthographicProjection), With<MainCamera>>,
+) {
+    let Ok((mut transform, projection)) = cameras.get_single_mut() else {
+        return;
+    };
+    if !contexts.ctx_mut().wants_keyboard_input() {
+        let pressed = |keys_for_direction: [KeyCode; 2]| keys.any_pressed(keys_for_direction) as i32 as f32;
+        let direction = vec2(
+            pressed([KeyCode::ArrowRight, KeyCode::KeyD]) - pressed([KeyCode::ArrowLeft, KeyCode::KeyA]),
+            pressed([KeyCode::ArrowUp, KeyCode::KeyW]) - pressed([KeyCode::ArrowDown, KeyCode::KeyS]),
+        );
+        let speed = CAMERA_SPEED * projection.area.width() * time.delta_seconds();
+        transform.translation += (direction * speed).extend(0.0);
+    }
+
+    let Ok(layout) = layouts.get_single() else {
+        return;
+    };
+    let period = layout.world_period();
+    let around = |position: f32, period: f32| (position + period / 2.0).rem_euclid(period) - period / 2.0;
+    if layout.topology.wrap().wraps_x() {
+        transform.translation.x = around(transform.translation.x, period.x);
+    }
+    if layout.topology.wrap().wraps_y() {
+        transform.translation.y = around(transform.translation.y, period.y);
+    }
+}
+
+/// The map currently shown, kept around so it can be saved.
+#[derive(Resource, Default)]
+struct CurrentMap(Option<MapFile>);
+
+/// Continent of every tile of the current map, when it was generated with
+/// voronoi continents. Not saved with the map.
+#[derive(Resource, Default)]
+struct ContinentIds(Option<DMatrix<Option<usize>>>);
+
+/// Stencil image requested from the asset server that hasn't finished
+/// loading yet.
+#[derive(Resource, Default)]
+struct StencilImage(Option<Handle<Image>>);
+
+/// Size of the square tiles in `tiles/multitiles.png`.
+const SQUARE_TILE_SIZE: Vec2 = Vec2::splat(128.0);
+
+/// Size of the hexes in `tiles/hextiles.png`. They're squashed to three
+/// quarters of a regular hex's height, which gives the map a tilted look.
+const HEX_TILE_SIZE: Vec2 = Vec2::new(128.0, 96.0);
+
+/// Draws the axial coordinates of a hex map: a step along `q` moves a whole
+/// hex to the right, a step along `r` half a hex to the right and three
+/// quarters of a hex down. Hexes further down and right are drawn over the
+/// ones before them, so the pointy tops overlap the rows above.
+const HEX_PROJECTION: TileProjection = TileProjection {
+    projection: mat3(vec3(1.0, 0.0, -1.0), vec3(0.5, -0.75, -1.0), vec3(0.0, 0.0, 1.0)),
+    tile_anchor_point: vec2(0.5, 0.5),
+};
+
+/// How the tiles of a map are laid out on the tilemap drawing them.
+///
+/// Hex maps are drawn in axial coordinates, where the rows of the map lean
+/// to the left. Every row is shifted right far enough for its first hex to
+/// land on the tilemap, which leaves the corners of the tilemap empty.
+#[derive(Component, Debug, PartialEq, Clone, Copy)]
+struct MapLayout {
+    topology: GridTopology,
+    width: usize,
+    height: usize,
+    shift: i64,
+    render_width: u32,
+}
+
+impl MapLayout {
+    fn new(topology: GridTopology, width: usize, height: usize) -> MapLayout {
+        let rows = 0..height as i64;
+        let shift = rows.clone().map(|y| -topology.to_axial(0, y).0).max().unwrap_or(0);
+        let last = width as i64 - 1;
+        let render_width = rows.map(|y| topology.to_axial(last, y).0 + shift + 1).max().unwrap_or(0);
+        MapLayout { topology, width, height, shift, render_width: render_width as u32 }
+    }
+
+    /// Where a map tile is drawn on the tilemap.
+    fn render_position(&self, x: usize, y: usize) -> (u32, u32) {
+        let (q, r) = self.topology.to_axial(x as i64, y as i64);
+        ((q + self.shift) as u32, r as u32)
+    }
+
+    /// The hex atlas keeps its first slot transparent for the empty cells of
+    /// the tilemap, so the terrain starts one slot later.
+    fn first_atlas_index(&self) -> u32 {
+        match self.topology.shape() {
+            TileShape::Square => 0,
+            TileShape::Hex(_) => 1,
+        }
+    }
+
+    /// The map tile drawn at a position given in tilemap coordinates.
+    /// Positions on the copies across the seams of a map that wraps are
+    /// tiles of the map too.
+    fn tile_at(&self, map_position: Vec2) -> Option<(usize, usize)> {
+        let (x, y) = (map_position.x as f64, map_position.y as f64);
+        let (x, y) = match self.topology.shape() {
+            // Square tiles are anchored at their top left corner.
+            TileShape::Square => self.topology.tile_at(x - 0.5, y - 0.5),
+            // Hexes are anchored at their center, and the tilemap is in
+            // axial coordinates.
+            TileShape::Hex(_) => self.topology.tile_at(x - self.shift as f64 + y / 2.0, y),
+        };
+        self.topology.wrap_tile(x, y, self.width, self.height)
+    }
+
+    /// How far apart the copies of a map that wraps are drawn, in world
+    /// units. Hex rows overlap by a quarter of a hex.
+    fn world_period(&self) -> Vec2 {
+        match self.topology.shape() {
+            TileShape::Square => vec2(self.width as f32, self.height as f32) * SQUARE_TILE_SIZE,
+            TileShape::Hex(_) => vec2(self.width as f32, self.height as f32 * 0.75) * HEX_TILE_SIZE,
+        }
+    }
+
+    /// Offsets of the copies drawn around the map across the edges that
+    /// wrap, so there's map on both sides of a seam.
+    fn copy_offsets(&self) -> Vec<Vec2> {
+        let period = self.world_period();
+        let wrap = self.topology.wrap();
+        let columns: &[f32] = if wrap.wraps_x() { &[-1.0, 0.0, 1.0] } else { &[0.0] };
+        let rows: &[f32] = if wrap.wraps_y() { &[-1.0, 0.0, 1.0] } else { &[0.0] };
+        rows.iter()
+            .flat_map(|row| columns.iter().map(move |column| vec2(column * period.x, row * period.y)))
+            .filter(|offset| *offset != Vec2::ZERO)
+            .collect()
+    }
+
+    fn build(&self, assets: &AssetServer, initializer: impl FnOnce(&mut MapIndexer)) -> Map {
+        let size = uvec2(self.render_width, self.height as u32);
+        let builder = match self.topology.shape() {
+            TileShape::Square => {
+                Map::builder(size, assets.load("tiles/multitiles.png"), SQUARE_TILE_SIZE).with_projection(IDENTITY)
+            }
+            TileShape::Hex(_) => {
+                Map::builder(size, assets.load("tiles/hextiles.png"), HEX_TILE_SIZE).with_projection(HEX_PROJECTION)
+            }
+        };
+        builder.build_and_initialize(initializer)
+    }
+
+    /// Spawns the tilemap along with its copies, which share its tiles.
+    fn spawn(self, commands: &mut Commands, map: Handle<Map>) {
+        let copies = self.copy_offsets();
+        commands
+            .spawn((MapBundleManaged { material: map.clone(), ..default() }, self))
+            .with_children(|parent| {
+                for offset in copies {
+                    parent.spawn(MapBundleManaged {
+                        material: map.clone(),
+                        transform: Transform::from_translation(offset.extend(0.0)),
+                        ..default()
+                    });
+                }
+            });
+    }
+}
+
+/// The tilemap the map is drawn on.
+#[derive(SystemParam)]
+struct TileMap<'w, 's> {
+    commands: Commands<'w, 's>,
+    assets: Res<'w, AssetServer>,
+    materials: ResMut<'w, Assets<Map>>,
+    maps: Query<'w, 's, (Entity, &'static Handle<Map>, &'static MapLayout)>,
+}
+
+impl TileMap<'_, '_> {
+    /// Draws the tiles, tinting every tile by its continent when
+    /// `continent_ids` is given. The tilemap is replaced when the tiles
+    /// don't fit its layout.
+    fn show(&mut self, tiles: &DMatrix<Tile>, continent_ids: Option<&DMatrix<Option<usize>>>, topology: GridTopology) {
+        let layout = MapLayout::new(topology, tiles.nrows(), tiles.ncols());
+        match self.maps.get_single() {
+            Ok((_, handle, shown)) if *shown == layout => match self.materials.get_mut(handle) {
+                Some(map) => apply_tiles(tiles, continent_ids, &layout, &mut map.indexer_mut()),
+                None => error!("Failed to get a map from map handle"),
+            },
+            result => {
+                if let Err(QuerySingleError::MultipleEntities(_)) = result {
+                    error!("Why are there multiple maps");
+                }
+                for (entity, _, _) in &self.maps {
+                    self.commands.entity(entity).despawn_recursive();
+                }
+                let map = layout.build(&self.assets, |indexer| apply_tiles(tiles, continent_ids, &layout, indexer));
+                layout.spawn(&mut self.commands, self.materials.add(map));
+            }
+        }
+    }
+}
+
+/// Looks up what the tilemap shows, for the cursor.
+#[derive(SystemParam)]
+struct ShownMap<'w, 's> {
+    materials: Res<'w, Assets<Map>>,
+    maps: Query<'w, 's, (&'static Handle<Map>, &'static MapLayout)>,
+}
+
+impl ShownMap<'_, '_> {
+    /// The map tile drawn at a world position.
+    fn tile_at(&self, world_position: Vec2) -> Option<(usize, usize)> {
+        let (handle, layout) = self.maps.get_single().ok()?;
+        let map = self.materials.get(handle)?;
+        layout.tile_at(map.world_to_map(world_position))
+    }
+}
+
+/// Starts loading stencil images for [`load_stencil_system`] to pick up.
+#[derive(SystemParam)]
+struct StencilLoader<'w> {
+    asset_server: Res<'w, AssetServer>,
+    image: ResMut<'w, StencilImage>,
+}
+
+impl StencilLoader<'_> {
+    /// `path` is relative to the assets folder.
+    fn load(&mut self, path: String) {
+        self.image.0 = Some(self.asset_server.load(path));
+    }
+
+    fn is_loading(&self) -> bool {
+        self.image.0.is_some()
+    }
+}
+
+/// Tile indices carry the continent plus one above the atlas index bits, the
+/// shader strips it before sampling and tints the tile by it afterwards.
+const CONTINENT_TINT_SHIFT: u32 = 8;
+
+const CONTINENT_TINT_PRE_SAMPLE: &str = r#"
+    var continent_tint = tile_index >> 8u;
+    tile_index = tile_index & 0xFFu;
+"#;
+
+const CONTINENT_TINT_POST_SAMPLE: &str = r#"
+    if continent_tint != 0u {
+        let hue = fract(f32(continent_tint) * 0.618034);
+        let tint = clamp(abs(fract(vec3<f32>(hue, hue + 0.666667, hue + 0.333333)) * 6.0 - 3.0) - 1.0, vec3<f32>(0.0), vec3<f32>(1.0));
+        color = vec4<f32>(mix(color.rgb, color.rgb * tint, 0.6), color.a);
+    }
+"#;
+
+/// What a generation task hands back to the UI.
+struct GeneratedMap {
+    map_file: MapFile,
+    continent_ids: Option<DMatrix<Option<usize>>>,
+}
+
+/// Shared between the UI and the task generating a map.
+#[derive(Default)]
+struct GenerationProgress {
+    /// Name of the running phase and how far along it is.
+    status: Mutex<(String, f32)>,
+    cancelled: AtomicBool,
+    single_threaded: bool,
+}
+
+impl GenerationProgress {
+    fn status(&self) -> (String, f32) {
+        self.status.lock().unwrap_or_else(|error| error.into_inner()).clone()
+    }
+}
+
+impl ProgressSink for GenerationProgress {
+    fn report(&self, phase: &str, completed: usize, total: usize) {
+        let fraction = if total == 0 { 1.0 } else { completed as f32 / total as f32 };
+        let mut status = self.status.lock().unwrap_or_else(|error| error.into_inner());
+        if status.0 != phase {
+            status.0 = phase.to_string();
+        }
+        status.1 = fraction;
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn single_threaded(&self) -> bool {
+        self.single_threaded
+    }
+}
+
+/// Comes back as `None` when the generation was cancelled.
+type GenerationResult = Option<Result<GeneratedMap, MapGeneratorError>>;
+
+struct GenerationJob {
+    task: Task<GenerationResult>,
+    progress: Arc<GenerationProgress>,
+}
+
+/// The map being generated in the background, if any.
+#[derive(Resource, Default)]
+struct MapGeneration(Option<GenerationJob>);
+
+impl MapGeneration {
+    /// Starts generating on the async compute pool, cancelling whatever was
+    /// running before. `single_threaded` keeps the generator from fanning
+    /// out over the compute pool.
+    fn start(
+        &mut self,
+        seed: String,
+        strategy: MapGeneratorStrategies,
+        post_processing: PostProcessOptions,
+        single_threaded: bool,
+    ) {
+        self.cancel();
+        let progress = Arc::new(GenerationProgress { single_threaded, ..Default::default() });
+        let task_progress = progress.clone();
+        let task = AsyncComputeTaskPool::get()
+            .spawn(async move { gen_noise_map(seed, strategy, &post_processing, &task_progress) });
+        self.0 = Some(GenerationJob { task, progress });
+    }
+
+    /// The task notices the flag at its next progress check and stops.
+    fn cancel(&mut self) {
+        if let Some(job) = self.0.take() {
+            job.progress.cancelled.store(true, Ordering::Relaxed);
+            job.task.detach();
+        }
+    }
 }
 
-enum MapGeneratorStrategies {
-    NoiseMap(NoiseMapOptions),
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+enum MapGeneratorKind {
+    #[default]
+    NoiseMap,
+    VoronoiHeightmap,
+    VoronoiContinents,
+    Planet,
 }
 
-impl Default for MapGeneratorStrategies {
-    fn default() -> Self {
-        MapGeneratorStrategies::NoiseMap(NoiseMapOptions::default())
+impl From<&MapGeneratorStrategies> for MapGeneratorKind {
+    fn from(strategy: &MapGeneratorStrategies) -> Self {
+        match strategy {
+            MapGeneratorStrategies::NoiseMap(_) => MapGeneratorKind::NoiseMap,
+            MapGeneratorStrategies::VoronoiHeightmap(_) => MapGeneratorKind::VoronoiHeightmap,
+            MapGeneratorStrategies::VoronoiContinents(_) => MapGeneratorKind::VoronoiContinents,
+            MapGeneratorStrategies::Planet(_) => MapGeneratorKind::Planet,
+        }
     }
 }
 
+/// The options a loaded map was generated with. The controls only cover
+/// part of them, so regenerating goes back to these until a control is
+/// changed.
+struct LoadedMap {
+    seed: String,
+    strategy: MapGeneratorStrategies,
+    post_processing: PostProcessOptions,
+    /// What the controls turned into right after loading.
+    shown_strategy: MapGeneratorStrategies,
+    shown_post_processing: PostProcessOptions,
+}
+
 #[derive(Default, Resource)]
 struct UiState {
     seed: String,
     voronoi_cell_count: usize,
-    map_generator: MapGeneratorStrategies,
+    noise_function: NoiseFunction,
+    noise_octaves: usize,
+    noise_frequency: f64,
+    noise_lacunarity: f64,
+    noise_persistence: f64,
+    falloff: FalloffOptions,
+    falloff_image_path: String,
+    noise_sea_level: f64,
+    sea_level: f64,
+    land_area_percentage: f64,
+    num_continents: usize,
+    water_padding_percentage: f64,
+    lloyd_iterations: usize,
+    continent_growth: ContinentGrowth,
+    ocean_gap: bool,
+    coastline_warp: DomainWarpOptions,
+    tint_continents: bool,
+    planet_projection: PlanetProjection,
+    planet_continents: bool,
+    stencil_path: String,
+    stencil: Option<GrayscaleMask>,
+    stencil_strength: f64,
+    erosion: bool,
+    droplet_count: usize,
+    biomes: bool,
+    single_threaded: bool,
+    rivers: bool,
+    river_threshold: f64,
+    map_generator: MapGeneratorKind,
+    tile_shape: TileShape,
+    wrap: WrapMode,
+    map_file_path: String,
+    loaded_map: Option<LoadedMap>,
+    error_message: Option<String>,
 }
 
 impl UiState {
     fn new() -> UiState {
+        let noise_defaults = NoiseMapOptions::default();
         UiState {
             seed: "Initial Seed".to_string(),
             voronoi_cell_count: 120,
-            map_generator: MapGeneratorStrategies::default(),
+            noise_function: noise_defaults.noise_function(),
+            noise_octaves: noise_defaults.octaves(),
+            noise_frequency: noise_defaults.frequency(),
+            noise_lacunarity: noise_defaults.lacunarity(),
+            noise_persistence: noise_defaults.persistence(),
+            falloff: noise_defaults.falloff().clone(),
+            falloff_image_path: "mask.png".to_string(),
+            noise_sea_level: noise_defaults.sea_level(),
+            sea_level: HeightMapOptions::default().sea_level(),
+            land_area_percentage: 29.0,
+            num_continents: 8,
+            water_padding_percentage: 7.0,
+            lloyd_iterations: 30,
+            continent_growth: ContinentGrowth::default(),
+            ocean_gap: false,
+            coastline_warp: DomainWarpOptions::new(),
+            tint_continents: false,
+            planet_projection: PlanetProjection::default(),
+            planet_continents: false,
+            stencil_path: "stencils/continents.png".to_string(),
+            stencil: None,
+            stencil_strength: 0.75,
+            erosion: false,
+            droplet_count: 200_000,
+            biomes: false,
+            single_threaded: false,
+            rivers: false,
+            river_threshold: 500.0,
+            map_generator: MapGeneratorKind::default(),
+            tile_shape: TileShape::default(),
+            wrap: WrapMode::default(),
+            map_file_path: "map.ron".to_string(),
+            loaded_map: None,
+            error_message: None,
+        }
+    }
+
+    fn map_generator_strategy(&self, width: usize, height: usize) -> MapGeneratorStrategies {
+        match self.map_generator {
+            MapGeneratorKind::NoiseMap => {
+                let mut seeder = Seeder::from(self.seed.clone());
+                let seed = math_helpers::create_new_seed32(&mut seeder);
+                let mut options = NoiseMapOptions::new(width, height, seed);
+                options.set_noise_function(self.noise_function);
+                options.set_octaves(self.noise_octaves);
+                options.set_frequency(self.noise_frequency);
+                options.set_lacunarity(self.noise_lacunarity);
+                options.set_persistence(self.noise_persistence);
+                options.set_falloff(self.falloff.clone());
+                options.set_stencil(self.stencil_options());
+                options.set_sea_level(self.noise_sea_level);
+                MapGeneratorStrategies::NoiseMap(options)
+            }
+            MapGeneratorKind::VoronoiHeightmap => {
+                let mut options = HeightMapOptions::new(width, height, self.seed.clone(), self.voronoi_cell_count);
+                options.set_sea_level(self.sea_level);
+                MapGeneratorStrategies::VoronoiHeightmap(options)
+            }
+            MapGeneratorKind::VoronoiContinents => {
+                let mut options = ContinentOptions::new(width, height, self.seed.clone(), self.voronoi_cell_count);
+                options.set_land_area_percentage(self.land_area_percentage);
+                options.set_num_continents(self.num_continents);
+                options.set_water_padding_percentage(self.water_padding_percentage);
+                options.set_lloyd_iterations(self.lloyd_iterations);
+                options.set_growth(self.continent_growth);
+                options.set_ocean_gap(self.ocean_gap);
+                options.set_warp(self.coastline_warp.clone());
+                options.set_stencil(self.stencil_options());
+                MapGeneratorStrategies::VoronoiContinents(options)
+            }
+            MapGeneratorKind::Planet => {
+                let mut seeder = Seeder::from(self.seed.clone());
+                let seed = math_helpers::create_new_seed32(&mut seeder);
+                let mut options = PlanetOptions::new(width, height, seed);
+                options.set_projection(self.planet_projection);
+                options.set_noise_function(self.noise_function);
+                options.set_octaves(self.noise_octaves);
+                options.set_frequency(self.noise_frequency);
+                options.set_lacunarity(self.noise_lacunarity);
+                options.set_persistence(self.noise_persistence);
+                options.set_sea_level(self.noise_sea_level);
+                options.set_continents(self.planet_continents.then(|| {
+                    let mut continents = PlanetContinentOptions::new(self.voronoi_cell_count);
+                    continents.set_land_area_percentage(self.land_area_percentage);
+                    continents.set_num_continents(self.num_continents);
+                    continents.set_ocean_gap(self.ocean_gap);
+                    continents
+                }));
+                MapGeneratorStrategies::Planet(options)
+            }
+        }
+    }
+
+    fn topology(&self) -> GridTopology {
+        GridTopology::new(self.tile_shape, self.wrap)
+    }
+
+    fn stencil_options(&self) -> Option<StencilOptions> {
+        self.stencil.clone().map(|mask| {
+            let mut options = StencilOptions::new(mask);
+            options.set_strength(self.stencil_strength);
+            options
+        })
+    }
+
+    fn biome_options(&self) -> Option<BiomeOptions> {
+        self.biomes.then(BiomeOptions::default)
+    }
+
+    fn erosion_options(&self) -> Option<ErosionOptions> {
+        self.erosion.then(|| {
+            let mut options = ErosionOptions::default();
+            options.set_droplet_count(self.droplet_count);
+            options
+        })
+    }
+
+    fn hydrology_options(&self) -> Option<HydrologyOptions> {
+        self.rivers.then(|| HydrologyOptions::new(self.river_threshold))
+    }
+
+    /// The passes to run on a map generated by `strategy`, on the grid it
+    /// gets generated on.
+    fn post_process_options(&self, strategy: &MapGeneratorStrategies) -> PostProcessOptions {
+        let mut options = PostProcessOptions::new();
+        options.set_erosion(self.erosion_options());
+        options.set_hydrology(self.hydrology_options());
+        options.set_biomes(self.biome_options());
+        options.set_topology(strategy.fit_topology(self.topology()));
+        options.set_projection(strategy.projection());
+        options
+    }
+
+    /// What regenerating should run: the loaded map's own options while
+    /// the controls still show them, otherwise whatever the controls say.
+    fn generation_options(&self) -> (MapGeneratorStrategies, PostProcessOptions) {
+        let strategy = self.map_generator_strategy(MAP_WIDTH, MAP_HEIGHT);
+        let post_processing = self.post_process_options(&strategy);
+        match &self.loaded_map {
+            Some(loaded) if loaded.seed == self.seed
+                && loaded.shown_strategy == strategy
+                && loaded.shown_post_processing == post_processing =>
+            {
+                (loaded.strategy.clone(), loaded.post_processing.clone())
+            }
+            _ => (strategy, post_processing),
         }
     }
+
+    /// Sets every control to what `map_file` was generated with, and keeps
+    /// its options around for the settings the controls don't have.
+    fn restore(&mut self, map_file: &MapFile) {
+        self.seed = map_file.seed().to_string();
+        self.map_generator = MapGeneratorKind::from(map_file.options());
+        let stencil = match map_file.options() {
+            MapGeneratorStrategies::NoiseMap(options) => options.stencil(),
+            MapGeneratorStrategies::VoronoiContinents(options) => options.stencil(),
+            MapGeneratorStrategies::VoronoiHeightmap(_) | MapGeneratorStrategies::Planet(_) => None,
+        };
+        self.stencil = stencil.map(|stencil| stencil.mask().clone());
+        if let Some(stencil) = stencil {
+            self.stencil_strength = stencil.strength();
+        }
+        match map_file.options() {
+            MapGeneratorStrategies::NoiseMap(options) => {
+                self.noise_function = options.noise_function();
+                self.noise_octaves = options.octaves();
+                self.noise_frequency = options.frequency();
+                self.noise_lacunarity = options.lacunarity();
+                self.noise_persistence = options.persistence();
+                self.falloff = options.falloff().clone();
+                self.noise_sea_level = options.sea_level();
+            }
+            MapGeneratorStrategies::VoronoiHeightmap(options) => {
+                self.voronoi_cell_count = options.cell_count();
+                self.sea_level = options.sea_level();
+            }
+            MapGeneratorStrategies::VoronoiContinents(options) => {
+                self.voronoi_cell_count = options.cell_count();
+                self.land_area_percentage = options.land_area_percentage();
+                self.num_continents = options.num_continents();
+                self.water_padding_percentage = options.water_padding_percentage();
+                self.lloyd_iterations = options.lloyd_iterations();
+                self.continent_growth = options.growth();
+                self.ocean_gap = options.ocean_gap();
+                self.coastline_warp = options.warp().clone();
+            }
+            MapGeneratorStrategies::Planet(options) => {
+                self.planet_projection = options.projection();
+                self.noise_function = options.noise_function();
+                self.noise_octaves = options.octaves();
+                self.noise_frequency = options.frequency();
+                self.noise_lacunarity = options.lacunarity();
+                self.noise_persistence = options.persistence();
+                self.noise_sea_level = options.sea_level();
+                self.planet_continents = options.continents().is_some();
+                if let Some(continents) = options.continents() {
+                    self.voronoi_cell_count = continents.cell_count();
+                    self.land_area_percentage = continents.land_area_percentage();
+                    self.num_continents = continents.num_continents();
+                    self.ocean_gap = continents.ocean_gap();
+                }
+            }
+        }
+        self.erosion = map_file.erosion().is_some();
+        if let Some(erosion) = map_file.erosion() {
+            self.droplet_count = erosion.droplet_count();
+        }
+        self.rivers = map_file.hydrology().is_some();
+        if let Some(hydrology) = map_file.hydrology() {
+            self.river_threshold = hydrology.river_threshold();
+        }
+        self.biomes = map_file.biomes().is_some();
+        self.tile_shape = map_file.topology().shape();
+        self.wrap = map_file.topology().wrap();
+
+        self.loaded_map = None;
+        let (shown_strategy, shown_post_processing) = self.generation_options();
+        self.loaded_map = Some(LoadedMap {
+            seed: self.seed.clone(),
+            strategy: map_file.options().clone(),
+            post_processing: map_file.post_processing(),
+            shown_strategy,
+            shown_post_processing,
+        });
+    }
 }
 
 fn ui_system(
     mut ui_state: ResMut<UiState>,
+    mut current_map: ResMut<CurrentMap>,
+    mut continent_ids: ResMut<ContinentIds>,
+    mut generation: ResMut<MapGeneration>,
     mut contexts: EguiContexts,
-    mut materials: ResMut<Assets<Map>>,
-    maps: Query<&Handle<Map>>,
+    mut tile_map: TileMap,
+    mut stencil_loader: StencilLoader,
 ) {
     //let window = egui::Window::new("Hello").show(contexts.ctx_mut(), |ui| {
     let _ = egui::Window::new("Map Generation").show(contexts.ctx_mut(), |ui| {
         ui.add(egui::TextEdit::singleline(&mut ui_state.seed));
+        egui::ComboBox::from_label("Generator")
+            .selected_text(format!("{:?}", ui_state.map_generator))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut ui_state.map_generator, MapGeneratorKind::NoiseMap, "NoiseMap");
+                ui.selectable_value(&mut ui_state.map_generator, MapGeneratorKind::VoronoiHeightmap, "VoronoiHeightmap");
+                ui.selectable_value(&mut ui_state.map_generator, MapGeneratorKind::VoronoiContinents, "VoronoiContinents");
+                ui.selectable_value(&mut ui_state.map_generator, MapGeneratorKind::Planet, "Planet");
+            });
+        egui::ComboBox::from_label("Grid")
+            .selected_text(match ui_state.tile_shape {
+                TileShape::Square => "Square",
+                TileShape::Hex(HexLayout::OddRows) => "Hex odd rows",
+                TileShape::Hex(HexLayout::EvenRows) => "Hex even rows",
+            })
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut ui_state.tile_shape, TileShape::Square, "Square");
+                ui.selectable_value(&mut ui_state.tile_shape, TileShape::Hex(HexLayout::OddRows), "Hex odd rows");
+                ui.selectable_value(&mut ui_state.tile_shape, TileShape::Hex(HexLayout::EvenRows), "Hex even rows");
+            });
+        egui::ComboBox::from_label("Wrap")
+            .selected_text(format!("{:?}", ui_state.wrap))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut ui_state.wrap, WrapMode::None, "None");
+                ui.selectable_value(&mut ui_state.wrap, WrapMode::Horizontal, "Horizontal");
+                ui.selectable_value(&mut ui_state.wrap, WrapMode::Torus, "Torus");
+            });
         ui.add(egui::DragValue::new(&mut ui_state.voronoi_cell_count).speed(1));
-        //println!("response {:?}", response);
-        if ui.add(egui::Button::new("Regenerate Map")).clicked() {
-            match maps.get_single() {
-                Ok(map_handle) => {
-                    let width = 1920;
-                    let height = 1080;
-
-                    println!("seed: {}", ui_state.seed);
-                    let mut seeder = Seeder::from(ui_state.seed.clone());
-                    let seed = math_helpers::create_new_seed32(& mut seeder);
-
-
-                    match materials.get_mut(map_handle) {
-                        Some(map) => {
-                            let options = NoiseMapOptions::new(width, height, seed);
-                            let mut map_indexer = map.indexer_mut();
-                            gen_noise_map(MapGeneratorStrategies::NoiseMap(options), &mut map_indexer);
-                        }
-                        None => {
-                            println!("Failed to get a map from map handle");
+        match ui_state.map_generator {
+            MapGeneratorKind::NoiseMap => {
+                noise_controls(ui, &mut ui_state);
+                if falloff_controls(ui, &mut ui_state) {
+                    if let MapGeneratorStrategies::NoiseMap(options) = ui_state.map_generator_strategy(MAP_WIDTH, MAP_HEIGHT) {
+                        let falloff = Falloff::new(options.falloff(), options.map_width(), options.map_height(), options.seed());
+                        tile_map.show(&mask_preview_tiles(&falloff.mask_values()), None, ui_state.topology());
+                    }
+                }
+            }
+            MapGeneratorKind::VoronoiHeightmap => {
+                ui.add(egui::Slider::new(&mut ui_state.sea_level, 0.0..=1.0).text("Sea level"));
+            }
+            MapGeneratorKind::VoronoiContinents => {
+                ui.add(egui::Slider::new(&mut ui_state.land_area_percentage, 0.0..=100.0).text("Land %"));
+                ui.add(egui::DragValue::new(&mut ui_state.num_continents).speed(1).prefix("Continents: "));
+                ui.add(egui::Slider::new(&mut ui_state.water_padding_percentage, 0.0..=50.0).text("Water padding %"));
+                ui.add(egui::DragValue::new(&mut ui_state.lloyd_iterations).speed(1).prefix("Lloyd iterations: "));
+                egui::ComboBox::from_label("Growth")
+                    .selected_text(match ui_state.continent_growth {
+                        ContinentGrowth::Random => "Random",
+                        ContinentGrowth::Frontier => "Frontier",
+                        ContinentGrowth::Directional { .. } => "Directional",
+                    })
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut ui_state.continent_growth, ContinentGrowth::Random, "Random");
+                        ui.selectable_value(&mut ui_state.continent_growth, ContinentGrowth::Frontier, "Frontier");
+                        let directional = matches!(ui_state.continent_growth, ContinentGrowth::Directional { .. });
+                        if ui.selectable_label(directional, "Directional").clicked() && !directional {
+                            ui_state.continent_growth = ContinentGrowth::Directional { angle: 0.0, elongation: 4.0 };
                         }
+                    });
+                if let ContinentGrowth::Directional { angle, elongation } = &mut ui_state.continent_growth {
+                    ui.add(egui::Slider::new(angle, 0.0..=180.0).text("Growth angle"));
+                    ui.add(egui::Slider::new(elongation, 0.0..=20.0).text("Elongation"));
+                }
+                ui.checkbox(&mut ui_state.ocean_gap, "Ocean gap between continents");
+                let warp = &mut ui_state.coastline_warp;
+                let mut amplitude = warp.amplitude();
+                let mut frequency = warp.frequency();
+                let mut octaves = warp.octaves();
+                let mut levels = warp.levels();
+                ui.add(egui::Slider::new(&mut amplitude, 0.0..=200.0).text("Coast warp"));
+                ui.add(egui::Slider::new(&mut frequency, 0.0005..=0.05).logarithmic(true).text("Warp frequency"));
+                ui.add(egui::Slider::new(&mut octaves, 1..=8).text("Warp octaves"));
+                ui.add(egui::Slider::new(&mut levels, 0..=4).text("Warp levels"));
+                warp.set_amplitude(amplitude);
+                warp.set_frequency(frequency);
+                warp.set_octaves(octaves);
+                warp.set_levels(levels);
+                if ui.checkbox(&mut ui_state.tint_continents, "Tint continents").changed() {
+                    if let Some(map_file) = &current_map.0 {
+                        let tint = continent_ids.0.as_ref().filter(|_| ui_state.tint_continents);
+                        tile_map.show(&map_file.tiles(), tint, map_file.topology());
                     }
                 }
-                Err(QuerySingleError::NoEntities(_)) => {
-                    println!("No maps for some reason");
+            }
+            MapGeneratorKind::Planet => {
+                egui::ComboBox::from_label("Projection")
+                    .selected_text(format!("{:?}", ui_state.planet_projection))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut ui_state.planet_projection, PlanetProjection::Equirectangular, "Equirectangular");
+                        ui.selectable_value(&mut ui_state.planet_projection, PlanetProjection::Mercator, "Mercator");
+                        ui.selectable_value(&mut ui_state.planet_projection, PlanetProjection::CubeMap, "CubeMap");
+                    });
+                noise_controls(ui, &mut ui_state);
+                ui.checkbox(&mut ui_state.planet_continents, "Continents");
+                if ui_state.planet_continents {
+                    ui.add(egui::Slider::new(&mut ui_state.land_area_percentage, 0.0..=100.0).text("Land %"));
+                    ui.add(egui::DragValue::new(&mut ui_state.num_continents).speed(1).prefix("Continents: "));
+                    ui.checkbox(&mut ui_state.ocean_gap, "Ocean gap between continents");
                 }
-                Err(QuerySingleError::MultipleEntities(_)) => {
-                    println!("Why are there multiple maps");
+            }
+        }
+        if matches!(ui_state.map_generator, MapGeneratorKind::NoiseMap | MapGeneratorKind::VoronoiContinents) {
+            ui.horizontal(|ui| {
+                ui.add(egui::TextEdit::singleline(&mut ui_state.stencil_path));
+                if ui.add(egui::Button::new("Load Stencil")).clicked() {
+                    stencil_loader.load(ui_state.stencil_path.clone());
                 }
-            };
+                if ui_state.stencil.is_some() && ui.add(egui::Button::new("Clear")).clicked() {
+                    ui_state.stencil = None;
+                }
+            });
+            if ui_state.stencil.is_some() {
+                ui.add(egui::Slider::new(&mut ui_state.stencil_strength, 0.0..=1.0).text("Stencil strength"));
+            } else if stencil_loader.is_loading() {
+                ui.label("Loading stencil...");
+            }
+        }
+        ui.checkbox(&mut ui_state.erosion, "Erosion");
+        if ui_state.erosion {
+            ui.add(egui::DragValue::new(&mut ui_state.droplet_count).speed(1000).prefix("Droplets: "));
+        }
+        ui.checkbox(&mut ui_state.rivers, "Rivers and lakes");
+        if ui_state.rivers {
+            ui.add(
+                egui::Slider::new(&mut ui_state.river_threshold, 10.0..=10000.0)
+                    .logarithmic(true)
+                    .text("River threshold"),
+            );
+        }
+        ui.checkbox(&mut ui_state.biomes, "Biomes");
+        ui.checkbox(&mut ui_state.single_threaded, "Single threaded");
+        //println!("response {:?}", response);
+        if ui.add(egui::Button::new("Regenerate Map")).clicked() {
+            info!(seed = ui_state.seed.as_str(), "Regenerating map");
+            let (strategy, post_processing) = ui_state.generation_options();
+            generation.start(ui_state.seed.clone(), strategy, post_processing, ui_state.single_threaded);
+            ui_state.error_message = None;
+        }
+        if let Some(job) = &generation.0 {
+            let (phase, fraction) = job.progress.status();
+            let mut cancel = false;
+            ui.horizontal(|ui| {
+                let text = format!("{} {:.0}%", phase.replace('_', " "), fraction * 100.0);
+                ui.add(egui::ProgressBar::new(fraction).text(text).desired_width(200.0));
+                cancel = ui.add(egui::Button::new("Cancel")).clicked();
+            });
+            if cancel {
+                generation.cancel();
+            }
+            ui.ctx().request_repaint();
+        }
+        ui.horizontal(|ui| {
+            ui.add(egui::TextEdit::singleline(&mut ui_state.map_file_path));
+            if ui.add(egui::Button::new("Save Map")).clicked() {
+                ui_state.error_message = match &current_map.0 {
+                    Some(map_file) => map_file.save(Path::new(&ui_state.map_file_path)).err().map(|error| error.to_string()),
+                    None => Some("No map to save".to_string()),
+                };
+            }
+            if ui.add(egui::Button::new("Load Map")).clicked() {
+                match MapFile::load(Path::new(&ui_state.map_file_path)) {
+                    Ok(map_file) => {
+                        generation.cancel();
+                        tile_map.show(&map_file.tiles(), None, map_file.topology());
+                        ui_state.restore(&map_file);
+                        ui_state.error_message = None;
+                        current_map.0 = Some(map_file);
+                        continent_ids.0 = None;
+                    }
+                    Err(error) => ui_state.error_message = Some(error.to_string()),
+                }
+            }
+        });
+        if let Some(error) = &ui_state.error_message {
+            ui.colored_label(egui::Color32::RED, error);
         }
     });
 }
 
 
-fn gen_noise_map(map_generator: MapGeneratorStrategies, map: &mut MapIndexer) {
-    let tile_matrix = match map_generator {
-        MapGeneratorStrategies::NoiseMap(options) => {
-            map_generators::noise_map(options)
-        }
+/// Runs the generator and the post processing passes, reporting to
+/// `progress` and stopping as soon as it's cancelled.
+fn gen_noise_map(
+    seed: String,
+    map_generator: MapGeneratorStrategies,
+    post_processing: &PostProcessOptions,
+    progress: &GenerationProgress,
+) -> GenerationResult {
+    let _span = info_span!("generate_map", generator = map_generator.name(), seed = seed.as_str()).entered();
+    let topology = post_processing.topology();
+    let generated = match &map_generator {
+        MapGeneratorStrategies::VoronoiContinents(options) => voronoi_continent_map(options, topology, progress)
+            .map(|continent_map| (continent_map.tiles, Some(continent_map.continent_ids)))
+            .map_err(MapGeneratorError::from),
+        _ => map_generator.clone().generate_on_grid(topology, progress).map(|tile_matrix| (tile_matrix, None)),
     };
-    /* 
-    let tile_matrix = match map_generators::voronoi_continents(
-        params.map_size.x as usize,
-        params.map_size.y as usize,
-        &mut params.seeder,
-        params.voronoi_cell_count,
-    ) {
-        Ok(matrix) => matrix,
-        Err(error) => {
-            println!("error: {error:?}");
-            return
+    let tile_matrix = generated.and_then(|(tile_matrix, continent_ids)| {
+        let pipeline = post_processing.pipeline(seed.clone(), tile_matrix.nrows(), tile_matrix.ncols());
+        Ok((pipeline.apply_with_progress(tile_matrix, progress)?, continent_ids))
+    });
+    let (tile_matrix, continent_ids) = match tile_matrix {
+        Ok(generated) => generated,
+        Err(error) if error.is_cancelled() => return None,
+        Err(error) => return Some(Err(error)),
+    };
+    info!(width = tile_matrix.nrows(), height = tile_matrix.ncols(), "Map generation finished");
+
+    let mut map_file = MapFile::new(seed, map_generator, &tile_matrix);
+    map_file.set_post_processing(post_processing);
+    Some(Ok(GeneratedMap { map_file, continent_ids }))
+}
+
+/// Turns the stencil image into a mask once the asset server has loaded it.
+fn load_stencil_system(
+    mut ui_state: ResMut<UiState>,
+    mut stencil_image: ResMut<StencilImage>,
+    asset_server: Res<AssetServer>,
+    images: Res<Assets<Image>>,
+) {
+    let Some(handle) = &stencil_image.0 else {
+        return;
+    };
+    if let Some(image) = images.get(handle) {
+        match image.clone().try_into_dynamic() {
+            Ok(image) => {
+                ui_state.stencil = Some(GrayscaleMask::from_image(&image.to_luma8()));
+                ui_state.error_message = None;
+            }
+            Err(error) => ui_state.error_message = Some(format!("Unsupported stencil: {error}")),
         }
+    } else if matches!(asset_server.get_load_state(handle), Some(LoadState::Failed)) {
+        ui_state.error_message = Some(format!("Failed to load stencil {}", ui_state.stencil_path));
+    } else {
+        return;
+    }
+    stencil_image.0 = None;
+}
+
+/// Applies a finished generation to the map.
+fn finish_generation_system(
+    mut ui_state: ResMut<UiState>,
+    mut current_map: ResMut<CurrentMap>,
+    mut continent_ids: ResMut<ContinentIds>,
+    mut generation: ResMut<MapGeneration>,
+    mut tile_map: TileMap,
+) {
+    let Some(job) = &mut generation.0 else {
+        return;
+    };
+    let Some(result) = block_on(poll_once(&mut job.task)) else {
+        return;
     };
-*/
-    println!(
-        "tile_matrix.shape: ({}, {})",
-        tile_matrix.shape().0,
-        tile_matrix.shape().1
-    );
+    generation.0 = None;
 
+    match result {
+        Some(Ok(GeneratedMap { map_file, continent_ids: ids })) => {
+            let tint = ids.as_ref().filter(|_| ui_state.tint_continents);
+            tile_map.show(&map_file.tiles(), tint, map_file.topology());
+            current_map.0 = Some(map_file);
+            continent_ids.0 = ids;
+        }
+        Some(Err(error)) => {
+            error!("Map generation failed: {error}");
+            ui_state.error_message = Some(error.to_string());
+        }
+        None => info!("Map generation cancelled"),
+    }
+}
+
+/// Shows the fractal noise controls shared by noise maps and planets.
+fn noise_controls(ui: &mut egui::Ui, ui_state: &mut UiState) {
+    egui::ComboBox::from_label("Noise")
+        .selected_text(format!("{:?}", ui_state.noise_function))
+        .show_ui(ui, |ui| {
+            ui.selectable_value(&mut ui_state.noise_function, NoiseFunction::Fbm, "Fbm");
+            ui.selectable_value(&mut ui_state.noise_function, NoiseFunction::Ridged, "Ridged");
+            ui.selectable_value(&mut ui_state.noise_function, NoiseFunction::Billow, "Billow");
+            ui.selectable_value(&mut ui_state.noise_function, NoiseFunction::Hybrid, "Hybrid");
+        });
+    ui.add(egui::Slider::new(&mut ui_state.noise_octaves, 1..=32).text("Octaves"));
+    ui.add(egui::Slider::new(&mut ui_state.noise_frequency, 0.0001..=0.05).logarithmic(true).text("Frequency"));
+    ui.add(egui::Slider::new(&mut ui_state.noise_lacunarity, 1.0..=4.0).text("Lacunarity"));
+    ui.add(egui::Slider::new(&mut ui_state.noise_persistence, 0.0..=1.0).text("Persistence"));
+    ui.add(egui::Slider::new(&mut ui_state.noise_sea_level, -1.0..=1.0).text("Sea level"));
+}
+
+/// Shows the falloff controls and tells whether the mask preview was asked
+/// for.
+fn falloff_controls(ui: &mut egui::Ui, ui_state: &mut UiState) -> bool {
+    let mut shape = ui_state.falloff.shape().clone();
+    egui::ComboBox::from_label("Falloff")
+        .selected_text(match shape {
+            FalloffShape::None => "None",
+            FalloffShape::Square => "Square",
+            FalloffShape::Radial => "Radial",
+            FalloffShape::MultiIsland { .. } => "Islands",
+            FalloffShape::Archipelago { .. } => "Archipelago",
+            FalloffShape::EdgeContinent { .. } => "Edge continent",
+            FalloffShape::Image(_) => "Image",
+        })
+        .show_ui(ui, |ui| {
+            ui.selectable_value(&mut shape, FalloffShape::None, "None");
+            ui.selectable_value(&mut shape, FalloffShape::Square, "Square");
+            ui.selectable_value(&mut shape, FalloffShape::Radial, "Radial");
+            let islands = matches!(shape, FalloffShape::MultiIsland { .. });
+            if ui.selectable_label(islands, "Islands").clicked() && !islands {
+                shape = FalloffShape::MultiIsland { islands: 4 };
+            }
+            let archipelago = matches!(shape, FalloffShape::Archipelago { .. });
+            if ui.selectable_label(archipelago, "Archipelago").clicked() && !archipelago {
+                shape = FalloffShape::Archipelago { islands: 12 };
+            }
+            let edge = matches!(shape, FalloffShape::EdgeContinent { .. });
+            if ui.selectable_label(edge, "Edge continent").clicked() && !edge {
+                shape = FalloffShape::EdgeContinent { edge: MapEdge::default() };
+            }
+        });
+    match &mut shape {
+        FalloffShape::MultiIsland { islands } | FalloffShape::Archipelago { islands } => {
+            ui.add(egui::Slider::new(islands, 1..=40).text("Islands"));
+        }
+        FalloffShape::EdgeContinent { edge } => {
+            egui::ComboBox::from_label("Edge")
+                .selected_text(format!("{edge:?}"))
+                .show_ui(ui, |ui| {
+                    for choice in [MapEdge::North, MapEdge::East, MapEdge::South, MapEdge::West] {
+                        ui.selectable_value(edge, choice, format!("{choice:?}"));
+                    }
+                });
+        }
+        _ => {}
+    }
+    ui.horizontal(|ui| {
+        ui.add(egui::TextEdit::singleline(&mut ui_state.falloff_image_path));
+        if ui.add(egui::Button::new("Load Mask")).clicked() {
+            match GrayscaleMask::load(Path::new(&ui_state.falloff_image_path)) {
+                Ok(mask) => shape = FalloffShape::Image(mask),
+                Err(error) => ui_state.error_message = Some(error.to_string()),
+            }
+        }
+    });
+    ui_state.falloff.set_shape(shape);
+
+    let mut curve = ui_state.falloff.curve();
+    egui::ComboBox::from_label("Falloff curve")
+        .selected_text(match curve {
+            FalloffCurve::Linear => "Linear",
+            FalloffCurve::Smoothstep => "Smoothstep",
+            FalloffCurve::Power { .. } => "Power",
+        })
+        .show_ui(ui, |ui| {
+            ui.selectable_value(&mut curve, FalloffCurve::Linear, "Linear");
+            ui.selectable_value(&mut curve, FalloffCurve::Smoothstep, "Smoothstep");
+            let power = matches!(curve, FalloffCurve::Power { .. });
+            if ui.selectable_label(power, "Power").clicked() && !power {
+                curve = FalloffCurve::Power { exponent: 2.0 };
+            }
+        });
+    if let FalloffCurve::Power { exponent } = &mut curve {
+        ui.add(egui::Slider::new(exponent, 0.1..=8.0).logarithmic(true).text("Exponent"));
+    }
+    ui_state.falloff.set_curve(curve);
+
+    let mut strength = ui_state.falloff.strength();
+    ui.add(egui::Slider::new(&mut strength, 0.0..=1.0).text("Falloff strength"));
+    ui_state.falloff.set_strength(strength);
+
+    ui.add(egui::Button::new("Preview Mask")).clicked()
+}
+
+/// Bands a falloff mask into terrain so it can be looked at on the tilemap,
+/// from water where the mask is empty to mountains where it is full.
+fn mask_preview_tiles(mask: &DMatrix<f64>) -> DMatrix<Tile> {
+    mask.map(|value| {
+        let terrain = match value {
+            value if value < 0.2 => TileType::Water,
+            value if value < 0.4 => TileType::Coast,
+            value if value < 0.6 => TileType::Grassland,
+            value if value < 0.8 => TileType::LightForest,
+            _ => TileType::Mountain,
+        };
+        Tile::new(terrain, value)
+    })
+}
+
+/// Tints every tile by its continent when `continent_ids` is given.
+fn apply_tiles(
+    tile_matrix: &DMatrix<Tile>,
+    continent_ids: Option<&DMatrix<Option<usize>>>,
+    layout: &MapLayout,
+    map: &mut MapIndexer,
+) {
     for x in 0..tile_matrix.nrows() {
         for y in 0..tile_matrix.ncols() {
             let tile_type = tile_matrix.get((x, y));
@@ -274,39 +1286,40 @@ fn gen_noise_map(map_generator: MapGeneratorStrategies, map: &mut MapIndexer) {
                 //Some(tile_type) => tile_type.terrain().to_atlas_index(),
                 Some(tile_type) => tile_type.terrain().to_atlas_index(),
                 None => {
-                    println!("No tile at ({}, {})", x, y);
+                    warn!(x, y, "No tile to apply");
                     TileType::Water.to_atlas_index()
                 }
             };
 
-            map.set(x as u32, y as u32, tile_index);
+            let tint = continent_ids
+                .and_then(|continent_ids| continent_ids[(x, y)])
+                .map_or(0, |continent| continent as u32 + 1);
+            let (render_x, render_y) = layout.render_position(x, y);
+            map.set(render_x, render_y, (tile_index + layout.first_atlas_index()) | tint << CONTINENT_TINT_SHIFT);
         }
     }
-    println!("map generation finished");
 }
 
+const MAP_WIDTH: usize = 1920;
+const MAP_HEIGHT: usize = 1080;
+
 fn setup(
     mut commands: Commands,
     assets: Res<AssetServer>,
     ui_state: Res<UiState>,
+    mut generation: ResMut<MapGeneration>,
     mut materials: ResMut<Assets<Map>>,
 ) {
-    let map_width = 1920;
-    let map_height = 1080;
+    let map_width = MAP_WIDTH;
+    let map_height = MAP_HEIGHT;
     //let map_size = Vec2::new(map_height, map_width);
-    let tile_size = Vec2::splat(128.0);
-
-    let texture = assets.load("tiles/multitiles.png");
-    //let multitile_handle: Handle<Image> = assets.load("tiles/multitiles.png");
-
-    let mut seeder = Seeder::from(ui_state.seed.clone());
-    let seed = math_helpers::create_new_seed32(&mut seeder);
 
-    let mut map = Map::builder(uvec2(map_width as u32, map_height as u32), texture, tile_size).build();
+    let layout = MapLayout::new(ui_state.topology(), map_width, map_height);
+    let map = layout.build(&assets, |_| {});
 
-    let mut indexer = map.indexer_mut();
-    let options = NoiseMapOptions::new(map_width, map_height, seed);
-    gen_noise_map(MapGeneratorStrategies::NoiseMap(options), &mut indexer);
+    let strategy = ui_state.map_generator_strategy(map_width, map_height);
+    let post_processing = ui_state.post_process_options(&strategy);
+    generation.start(ui_state.seed.clone(), strategy, post_processing, ui_state.single_threaded);
 
     let mut camera = Camera2dBundle::default();
     camera.projection.scale = 115.0;
@@ -315,6 +1328,6 @@ fn setup(
     //camera.projection.scale *= 1932.0;
     //camera.projection.scale *= 250.0;
     commands.spawn((camera, MainCamera));
-    commands.spawn(MapBundleManaged::new(map, materials.as_mut()));
+    layout.spawn(&mut commands, materials.add(map));
     //commands.spawn(Camera2dBundle::default());
 }
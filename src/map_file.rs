@@ -0,0 +1,368 @@
+use crate::map_generators::{
+    BiomeOptions, ErosionOptions, GridTopology, HydrologyOptions, MapGeneratorStrategies, PostProcessOptions, Tile, TileShape, WrapMode,
+};
+use nalgebra::DMatrix;
+use serde::{Deserialize, Serialize};
+
+use std::path::Path;
+
+use thiserror::Error;
+
+/// Bumped whenever the layout of [`MapFile`] changes.
+pub const MAP_FILE_VERSION: u32 = 1;
+
+const BINARY_MAGIC: &[u8; 4] = b"BWMP";
+
+#[derive(Error, Debug)]
+pub enum MapFileError {
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    RonError(#[from] ron::Error),
+    #[error(transparent)]
+    RonParseError(#[from] ron::error::SpannedError),
+    #[error(transparent)]
+    BincodeError(#[from] bincode::Error),
+    #[error("Not a map file")]
+    NotAMapFile,
+    #[error("Map file version {found} isn't the supported version {supported}")]
+    UnsupportedVersion { found: u32, supported: u32 },
+    #[error("Map file has {found} tiles but a {map_width}x{map_height} map needs {expected}")]
+    TileCountMismatch { found: usize, expected: usize, map_width: usize, map_height: usize },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MapFileFormat {
+    Ron,
+    Binary,
+}
+
+impl MapFileFormat {
+    /// `.ron` files are text, everything else uses the binary format.
+    pub fn from_path(path: &Path) -> MapFileFormat {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("ron") => MapFileFormat::Ron,
+            _ => MapFileFormat::Binary,
+        }
+    }
+}
+
+/// A generated map together with everything needed to regenerate it.
+///
+/// Tiles are stored column major, the same order `DMatrix` uses.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct MapFile {
+    version: u32,
+    generator: String,
+    seed: String,
+    options: MapGeneratorStrategies,
+    erosion: Option<ErosionOptions>,
+    biomes: Option<BiomeOptions>,
+    hydrology: Option<HydrologyOptions>,
+    shape: TileShape,
+    wrap: WrapMode,
+    map_width: usize,
+    map_height: usize,
+    tiles: Vec<Tile>,
+}
+
+#[derive(Deserialize)]
+struct VersionProbe {
+    version: u32,
+}
+
+fn check_version(version: u32) -> Result<(), MapFileError> {
+    if version != MAP_FILE_VERSION {
+        return Err(MapFileError::UnsupportedVersion { found: version, supported: MAP_FILE_VERSION });
+    }
+    Ok(())
+}
+
+impl MapFile {
+    pub fn new(seed: String, options: MapGeneratorStrategies, tiles: &DMatrix<Tile>) -> MapFile {
+        MapFile {
+            version: MAP_FILE_VERSION,
+            generator: options.name().to_string(),
+            seed,
+            options,
+            erosion: None,
+            biomes: None,
+            hydrology: None,
+            shape: TileShape::Square,
+            wrap: WrapMode::None,
+            map_width: tiles.nrows(),
+            map_height: tiles.ncols(),
+            tiles: tiles.iter().copied().collect(),
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn generator(&self) -> &str {
+        &self.generator
+    }
+
+    pub fn seed(&self) -> &str {
+        &self.seed
+    }
+
+    pub fn options(&self) -> &MapGeneratorStrategies {
+        &self.options
+    }
+
+    /// Erosion options the elevation was eroded with, if any.
+    pub fn erosion(&self) -> Option<&ErosionOptions> {
+        self.erosion.as_ref()
+    }
+
+    pub fn set_erosion(&mut self, erosion: Option<ErosionOptions>) {
+        self.erosion = erosion;
+    }
+
+    /// Biome options the tiles were classified with, if any.
+    pub fn biomes(&self) -> Option<&BiomeOptions> {
+        self.biomes.as_ref()
+    }
+
+    pub fn set_biomes(&mut self, biomes: Option<BiomeOptions>) {
+        self.biomes = biomes;
+    }
+
+    /// Hydrology options the rivers and lakes were generated with, if any.
+    pub fn hydrology(&self) -> Option<&HydrologyOptions> {
+        self.hydrology.as_ref()
+    }
+
+    pub fn set_hydrology(&mut self, hydrology: Option<HydrologyOptions>) {
+        self.hydrology = hydrology;
+    }
+
+    /// The grid the tiles are laid out on, and which of its edges wrap.
+    pub fn topology(&self) -> GridTopology {
+        GridTopology::new(self.shape, self.wrap)
+    }
+
+    pub fn set_topology(&mut self, topology: GridTopology) {
+        self.shape = topology.shape();
+        self.wrap = topology.wrap();
+    }
+
+    /// All the optional passes the tiles went through, on the grid of the
+    /// map.
+    pub fn post_processing(&self) -> PostProcessOptions {
+        let mut post_processing = PostProcessOptions::new();
+        post_processing.set_erosion(self.erosion.clone());
+        post_processing.set_hydrology(self.hydrology.clone());
+        post_processing.set_biomes(self.biomes.clone());
+        post_processing.set_topology(self.topology());
+        post_processing.set_projection(self.options.projection());
+        post_processing
+    }
+
+    pub fn set_post_processing(&mut self, post_processing: &PostProcessOptions) {
+        self.erosion = post_processing.erosion().cloned();
+        self.hydrology = post_processing.hydrology().cloned();
+        self.biomes = post_processing.biomes().cloned();
+        self.set_topology(post_processing.topology());
+    }
+
+    pub fn tiles(&self) -> DMatrix<Tile> {
+        DMatrix::from_column_slice(self.map_width, self.map_height, &self.tiles)
+    }
+
+    /// Checks that a freshly loaded file has as many tiles as its size needs.
+    fn validate(self) -> Result<MapFile, MapFileError> {
+        check_version(self.version)?;
+        let expected = self.map_width.checked_mul(self.map_height).ok_or(MapFileError::NotAMapFile)?;
+        if self.tiles.len() != expected {
+            return Err(MapFileError::TileCountMismatch {
+                found: self.tiles.len(),
+                expected,
+                map_width: self.map_width,
+                map_height: self.map_height,
+            });
+        }
+        Ok(self)
+    }
+
+    pub fn to_ron(&self) -> Result<String, MapFileError> {
+        Ok(ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?)
+    }
+
+    pub fn from_ron(text: &str) -> Result<MapFile, MapFileError> {
+        let probe: VersionProbe = ron::from_str(text)?;
+        check_version(probe.version)?;
+        let map_file: MapFile = ron::from_str(text)?;
+        map_file.validate()
+    }
+
+    /// Magic bytes, the version as little endian `u32`, then the bincode body.
+    pub fn to_binary(&self) -> Result<Vec<u8>, MapFileError> {
+        let mut bytes = Vec::with_capacity(BINARY_MAGIC.len() + 4);
+        bytes.extend_from_slice(BINARY_MAGIC);
+        bytes.extend_from_slice(&MAP_FILE_VERSION.to_le_bytes());
+        bincode::serialize_into(&mut bytes, self)?;
+        Ok(bytes)
+    }
+
+    pub fn from_binary(bytes: &[u8]) -> Result<MapFile, MapFileError> {
+        let body = bytes.strip_prefix(BINARY_MAGIC).ok_or(MapFileError::NotAMapFile)?;
+        let (version, body) = body.split_first_chunk::<4>().ok_or(MapFileError::NotAMapFile)?;
+        let version = u32::from_le_bytes(*version);
+        check_version(version)?;
+        let map_file: MapFile = bincode::deserialize(body)?;
+        map_file.validate()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), MapFileError> {
+        match MapFileFormat::from_path(path) {
+            MapFileFormat::Ron => std::fs::write(path, self.to_ron()?)?,
+            MapFileFormat::Binary => std::fs::write(path, self.to_binary()?)?,
+        }
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<MapFile, MapFileError> {
+        match MapFileFormat::from_path(path) {
+            MapFileFormat::Ron => MapFile::from_ron(&std::fs::read_to_string(path)?),
+            MapFileFormat::Binary => MapFile::from_binary(&std::fs::read(path)?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MapFile, MapFileError, MAP_FILE_VERSION};
+    use crate::map_generators::{
+        BiomeOptions, ContinentOptions, ErosionOptions, GridTopology, HexLayout, HydrologyOptions, MapGeneratorStrategies, NoProgress,
+        PlanetContinentOptions, PlanetOptions, PlanetProjection, WrapMode,
+    };
+
+    fn sample_map_file() -> MapFile {
+        let options = MapGeneratorStrategies::VoronoiContinents(ContinentOptions::new(30, 20, "saved".to_string(), 30));
+        let tiles = options.clone().generate().expect("Failed to generate map");
+        MapFile::new("saved".to_string(), options, &tiles)
+    }
+
+    #[test]
+    fn test_ron_round_trip() {
+        let map_file = sample_map_file();
+        let text = map_file.to_ron().expect("Failed to write ron");
+        let loaded = MapFile::from_ron(&text).expect("Failed to read ron");
+
+        assert_eq!(loaded, map_file);
+        assert_eq!(loaded.generator(), "voronoi_continents");
+        assert_eq!(loaded.tiles().shape(), (30, 20));
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let map_file = sample_map_file();
+        let bytes = map_file.to_binary().expect("Failed to write binary");
+        let loaded = MapFile::from_binary(&bytes).expect("Failed to read binary");
+
+        assert_eq!(loaded, map_file);
+        assert_eq!(loaded.tiles(), map_file.tiles());
+    }
+
+    #[test]
+    fn test_biomes_round_trip() {
+        let mut map_file = sample_map_file();
+        map_file.set_biomes(Some(BiomeOptions::new(3)));
+        map_file.set_hydrology(Some(HydrologyOptions::new(40.0)));
+        map_file.set_erosion(Some(ErosionOptions::new(5)));
+        let bytes = map_file.to_binary().expect("Failed to write binary");
+        let loaded = MapFile::from_binary(&bytes).expect("Failed to read binary");
+
+        assert_eq!(loaded.erosion(), Some(&ErosionOptions::new(5)));
+        assert_eq!(loaded.biomes(), Some(&BiomeOptions::new(3)));
+        assert_eq!(loaded.hydrology(), Some(&HydrologyOptions::new(40.0)));
+    }
+
+    #[test]
+    fn test_topology_round_trip() {
+        let mut map_file = sample_map_file();
+        map_file.set_topology(GridTopology::hex(HexLayout::EvenRows));
+
+        let bytes = map_file.to_binary().expect("Failed to write binary");
+        assert_eq!(MapFile::from_binary(&bytes).expect("Failed to read binary"), map_file);
+        let text = map_file.to_ron().expect("Failed to write ron");
+        let loaded = MapFile::from_ron(&text).expect("Failed to read ron");
+        assert_eq!(loaded.topology(), GridTopology::hex(HexLayout::EvenRows));
+        assert_eq!(loaded.post_processing().topology(), GridTopology::hex(HexLayout::EvenRows));
+    }
+
+    #[test]
+    fn test_wrap_round_trip() {
+        let mut map_file = sample_map_file();
+        let topology = GridTopology::hex(HexLayout::OddRows).with_wrap(WrapMode::Torus);
+        map_file.set_topology(topology);
+
+        let bytes = map_file.to_binary().expect("Failed to write binary");
+        assert_eq!(MapFile::from_binary(&bytes).expect("Failed to read binary").topology(), topology);
+        let text = map_file.to_ron().expect("Failed to write ron");
+        let loaded = MapFile::from_ron(&text).expect("Failed to read ron");
+        assert_eq!(loaded.topology(), topology);
+        assert_eq!(loaded.post_processing().topology(), topology);
+    }
+
+    #[test]
+    fn test_planet_round_trip() {
+        let mut options = PlanetOptions::new(30, 20, 6);
+        options.set_projection(PlanetProjection::Mercator);
+        options.set_continents(Some(PlanetContinentOptions::new(100)));
+        let strategy = MapGeneratorStrategies::Planet(options);
+        let topology = strategy.fit_topology(GridTopology::SQUARE);
+        let tiles = strategy.clone().generate_on_grid(topology, &NoProgress).expect("Failed to generate map");
+        let mut map_file = MapFile::new("planet".to_string(), strategy.clone(), &tiles);
+        map_file.set_topology(topology);
+
+        let bytes = map_file.to_binary().expect("Failed to write binary");
+        assert_eq!(MapFile::from_binary(&bytes).expect("Failed to read binary"), map_file);
+        let loaded = MapFile::from_ron(&map_file.to_ron().expect("Failed to write ron")).expect("Failed to read ron");
+        assert_eq!(loaded.options(), &strategy);
+        assert_eq!(loaded.generator(), "planet");
+        assert_eq!(loaded.topology().wrap(), WrapMode::Horizontal);
+        assert_eq!(loaded.post_processing().projection(), Some(PlanetProjection::Mercator));
+    }
+
+    #[test]
+    fn test_ron_files_resave_as_binary() {
+        let map_file = sample_map_file();
+        let text = map_file.to_ron().expect("Failed to write ron");
+        let loaded = MapFile::from_ron(&text).expect("Failed to read ron");
+
+        let bytes = loaded.to_binary().expect("Failed to write binary");
+        assert_eq!(bytes[4..8], MAP_FILE_VERSION.to_le_bytes());
+        let reloaded = MapFile::from_binary(&bytes).expect("Failed to read binary");
+
+        assert_eq!(reloaded, map_file);
+        assert_eq!(reloaded.to_ron().expect("Failed to write ron"), text);
+    }
+
+    #[test]
+    fn test_rejects_other_versions_and_garbage() {
+        let mut bytes = sample_map_file().to_binary().expect("Failed to write binary");
+        for version in [MAP_FILE_VERSION - 1, MAP_FILE_VERSION + 1] {
+            bytes[4..8].copy_from_slice(&version.to_le_bytes());
+            assert!(matches!(MapFile::from_binary(&bytes), Err(MapFileError::UnsupportedVersion { .. })));
+        }
+
+        assert!(matches!(MapFile::from_binary(b"nope"), Err(MapFileError::NotAMapFile)));
+
+        let text = sample_map_file().to_ron().expect("Failed to write ron");
+        let at = text.rfind("map_width: 30").expect("Map width not found");
+        let text = format!("{}map_width: {}{}", &text[..at], usize::MAX, &text[at + "map_width: 30".len()..]);
+        assert!(matches!(MapFile::from_ron(&text), Err(MapFileError::NotAMapFile)));
+
+        let text = sample_map_file().to_ron().expect("Failed to write ron");
+        let text = text.replacen(&format!("version: {MAP_FILE_VERSION}"), &format!("version: {}", MAP_FILE_VERSION + 1), 1);
+        assert!(matches!(MapFile::from_ron(&text), Err(MapFileError::UnsupportedVersion { .. })));
+
+        let text = sample_map_file().to_ron().expect("Failed to write ron");
+        let truncated: String = text.lines().filter(|line| !line.trim_start().starts_with("wrap:")).collect::<Vec<_>>().join("\n");
+        assert!(matches!(MapFile::from_ron(&truncated), Err(MapFileError::RonParseError(_))));
+    }
+}
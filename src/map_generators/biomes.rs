@@ -0,0 +1,330 @@
+use crate::map_generators::grid::GridTopology;
+use crate::map_generators::map_generator::MapGeneratorError;
+use crate::map_generators::pipeline::MapPass;
+use crate::map_generators::planet::PlanetProjection;
+use crate::map_generators::progress::ProgressSink;
+use crate::map_generators::tile::Tile;
+use crate::map_generators::tile_types::TileType;
+use crate::math_helpers::create_new_seed32;
+use nalgebra::DMatrix;
+use noise::{Fbm, MultiFractal, OpenSimplex};
+use rand_seeder::Seeder;
+use serde::{Deserialize, Serialize};
+
+use std::collections::VecDeque;
+use std::f64::consts::FRAC_PI_2;
+
+/// Controls the temperature and moisture fields the biomes are picked from.
+///
+/// Temperature runs from `0.0` at the poles to `1.0` on the equator and
+/// drops with altitude. On a flat map the top and bottom rows are the poles
+/// and the middle row is the equator. Moisture mixes noise with how close a
+/// tile is to water.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct BiomeOptions {
+    seed: u32,
+    lapse_rate: f64,
+    moisture_frequency: f64,
+    moisture_noise_weight: f64,
+    water_moisture_distance: f64,
+}
+
+impl BiomeOptions {
+    pub fn new(seed: u32) -> BiomeOptions {
+        BiomeOptions {
+            seed,
+            lapse_rate: 0.5,
+            moisture_frequency: 0.01,
+            moisture_noise_weight: 0.5,
+            water_moisture_distance: 60.0,
+        }
+    }
+
+    /// How much colder the highest land is than sea level.
+    pub fn set_lapse_rate(&mut self, lapse_rate: f64) {
+        self.lapse_rate = lapse_rate;
+    }
+
+    pub fn set_moisture_frequency(&mut self, moisture_frequency: f64) {
+        self.moisture_frequency = moisture_frequency;
+    }
+
+    pub fn set_moisture_noise_weight(&mut self, moisture_noise_weight: f64) {
+        self.moisture_noise_weight = moisture_noise_weight;
+    }
+
+    /// Distance in tiles at which the moisture from water falls to `1/e`.
+    pub fn set_water_moisture_distance(&mut self, water_moisture_distance: f64) {
+        self.water_moisture_distance = water_moisture_distance;
+    }
+}
+
+impl Default for BiomeOptions {
+    fn default() -> Self {
+        let mut seeder = Seeder::from("Initial Seed");
+        BiomeOptions::new(create_new_seed32(&mut seeder))
+    }
+}
+
+/// Picks a biome from the relative altitude of a land tile (`0.0` at the
+/// lowest land, `1.0` at the highest), its temperature and its moisture.
+pub fn classify_biome(altitude: f64, temperature: f64, moisture: f64, is_coastal: bool) -> TileType {
+    if temperature < 0.15 {
+        return TileType::Ice;
+    }
+    if altitude > 0.85 {
+        return TileType::Mountain;
+    }
+    if temperature < 0.3 {
+        return TileType::Tundra;
+    }
+    if is_coastal {
+        return TileType::Coast;
+    }
+
+    match moisture {
+        moisture if moisture < 0.2 && temperature > 0.5 => TileType::Desert,
+        moisture if moisture < 0.45 => TileType::Grassland,
+        moisture if moisture < 0.7 => TileType::LightForest,
+        _ => TileType::ThickForest,
+    }
+}
+
+/// Distance in steps between edge neighbors from every tile to the closest
+/// water tile. Without any water every tile is `f64::INFINITY` away.
+pub fn distance_to_water(tiles: &DMatrix<Tile>, topology: GridTopology) -> DMatrix<f64> {
+    let (width, height) = tiles.shape();
+    let mut distances = DMatrix::from_element(width, height, f64::INFINITY);
+    let mut queue = VecDeque::new();
+
+    for x in 0..width {
+        for y in 0..height {
+            if tiles[(x, y)].terrain().is_water() {
+                distances[(x, y)] = 0.0;
+                queue.push_back((x, y));
+            }
+        }
+    }
+
+    while let Some((x, y)) = queue.pop_front() {
+        let next_distance = distances[(x, y)] + 1.0;
+        for (nx, ny) in topology.edge_neighbors(x, y, width, height) {
+            if distances[(nx, ny)] > next_distance {
+                distances[(nx, ny)] = next_distance;
+                queue.push_back((nx, ny));
+            }
+        }
+    }
+
+    distances
+}
+
+/// Whether a tile touches the sea or a lake. River banks don't count as coast.
+fn borders_open_water(tiles: &DMatrix<Tile>, x: usize, y: usize, topology: GridTopology) -> bool {
+    let (width, height) = tiles.shape();
+    topology
+        .edge_neighbors(x, y, width, height)
+        .any(|index| matches!(tiles[index].terrain(), TileType::Water | TileType::Lake))
+}
+
+/// Fills in temperature and moisture for every tile and replaces the terrain
+/// of land tiles with a biome. Water, rivers and lakes keep their terrain
+/// unless they're cold enough to freeze.
+pub fn classify_biomes(tiles: DMatrix<Tile>, options: &BiomeOptions, topology: GridTopology) -> DMatrix<Tile> {
+    let height = tiles.ncols();
+    classify_biomes_with_latitude(tiles, options, topology, |_, y| {
+        if height > 1 {
+            (2.0 * y as f64 / (height - 1) as f64 - 1.0).abs()
+        } else {
+            0.0
+        }
+    })
+}
+
+/// Like [`classify_biomes`] but with the latitude of every tile given by
+/// `latitude`, from `0.0` on the equator to `1.0` at either pole.
+pub fn classify_biomes_with_latitude(
+    tiles: DMatrix<Tile>,
+    options: &BiomeOptions,
+    topology: GridTopology,
+    latitude: impl Fn(usize, usize) -> f64,
+) -> DMatrix<Tile> {
+    let (width, height) = tiles.shape();
+    let (min_land, max_land) = tiles
+        .iter()
+        .filter(|tile| !tile.terrain().is_water())
+        .fold((f64::MAX, f64::MIN), |(min, max), tile| {
+            (min.min(tile.elevation()), max.max(tile.elevation()))
+        });
+    let land_range = max_land - min_land;
+
+    let water_distance = distance_to_water(&tiles, topology);
+    let noise: Fbm<OpenSimplex> = Fbm::new(options.seed);
+    let noise = noise.set_octaves(4).set_frequency(options.moisture_frequency);
+
+    DMatrix::from_fn(width, height, |x, y| {
+        let tile = tiles[(x, y)];
+        let is_water = tile.terrain().is_water();
+        let altitude = if is_water || land_range <= 0.0 {
+            0.0
+        } else {
+            (tile.elevation() - min_land) / land_range
+        };
+
+        let temperature = ((1.0 - latitude(x, y)) - options.lapse_rate * altitude).clamp(0.0, 1.0);
+
+        let noise_moisture = (topology.sample_noise(&noise, x, y, width, height) + 1.0) / 2.0;
+        let water_moisture = (-water_distance[(x, y)] / options.water_moisture_distance).exp();
+        let moisture = (options.moisture_noise_weight * noise_moisture
+            + (1.0 - options.moisture_noise_weight) * water_moisture)
+            .clamp(0.0, 1.0);
+
+        let terrain = if is_water {
+            if temperature < 0.1 {
+                TileType::SeaIce
+            } else {
+                tile.terrain()
+            }
+        } else {
+            classify_biome(altitude, temperature, moisture, borders_open_water(&tiles, x, y, topology))
+        };
+
+        Tile::new(terrain, tile.elevation()).with_climate(temperature, moisture)
+    })
+}
+
+/// Biome classification as a pipeline pass. The moisture noise is seeded by
+/// the pipeline.
+pub struct BiomePass {
+    options: BiomeOptions,
+    projection: Option<PlanetProjection>,
+}
+
+impl BiomePass {
+    pub fn new(options: BiomeOptions) -> BiomePass {
+        BiomePass { options, projection: None }
+    }
+
+    /// The projection of a planet, which gives every tile its latitude.
+    /// Flat maps leave it out and run from pole to pole.
+    pub fn set_projection(&mut self, projection: Option<PlanetProjection>) {
+        self.projection = projection;
+    }
+}
+
+impl MapPass for BiomePass {
+    fn name(&self) -> &str {
+        "biomes"
+    }
+
+    fn apply(
+        &self,
+        tiles: DMatrix<Tile>,
+        topology: GridTopology,
+        seeder: &mut Seeder,
+        progress: &dyn ProgressSink,
+    ) -> Result<DMatrix<Tile>, MapGeneratorError> {
+        let mut options = self.options.clone();
+        options.seed = create_new_seed32(seeder);
+        progress.report("biomes", 0, 1);
+        let tiles = match self.projection {
+            Some(projection) => {
+                let (width, height) = tiles.shape();
+                classify_biomes_with_latitude(tiles, &options, topology, |x, y| {
+                    projection
+                        .tile_latitude(topology, x, y, width, height)
+                        .map_or(0.0, |latitude| latitude.abs() / FRAC_PI_2)
+                })
+            }
+            None => classify_biomes(tiles, &options, topology),
+        };
+        progress.report("biomes", 1, 1);
+        Ok(tiles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify_biome, classify_biomes, classify_biomes_with_latitude, distance_to_water, BiomeOptions};
+    use crate::map_generators::{GridTopology, HexLayout, Tile, TileType};
+    use nalgebra::DMatrix;
+
+    #[test]
+    fn test_classify_biome() {
+        assert_eq!(classify_biome(0.5, 0.05, 0.5, false), TileType::Ice);
+        assert_eq!(classify_biome(0.9, 0.6, 0.5, false), TileType::Mountain);
+        assert_eq!(classify_biome(0.2, 0.2, 0.5, false), TileType::Tundra);
+        assert_eq!(classify_biome(0.2, 0.6, 0.5, true), TileType::Coast);
+        assert_eq!(classify_biome(0.2, 0.9, 0.1, false), TileType::Desert);
+        assert_eq!(classify_biome(0.2, 0.4, 0.1, false), TileType::Grassland);
+        assert_eq!(classify_biome(0.2, 0.6, 0.3, false), TileType::Grassland);
+        assert_eq!(classify_biome(0.2, 0.6, 0.6, false), TileType::LightForest);
+        assert_eq!(classify_biome(0.2, 0.6, 0.9, false), TileType::ThickForest);
+    }
+
+    #[test]
+    fn test_distance_to_water() {
+        let tiles = DMatrix::from_fn(5, 1, |x, _| {
+            let terrain = if x == 0 { TileType::Water } else { TileType::Grassland };
+            Tile::new(terrain, 0.0)
+        });
+        let distances = distance_to_water(&tiles, GridTopology::SQUARE);
+        assert_eq!(distances.as_slice(), &[0.0, 1.0, 2.0, 3.0, 4.0]);
+
+        let dry = DMatrix::from_element(2, 2, Tile::new(TileType::Grassland, 0.0));
+        assert!(distance_to_water(&dry, GridTopology::SQUARE).iter().all(|distance| distance.is_infinite()));
+
+        let hex = GridTopology::hex(HexLayout::OddRows);
+        let lake = DMatrix::from_fn(7, 7, |x, y| {
+            let terrain = if (x, y) == (3, 3) { TileType::Water } else { TileType::Grassland };
+            Tile::new(terrain, 0.0)
+        });
+        let distances = distance_to_water(&lake, hex);
+        for y in 0..7 {
+            for x in 0..7 {
+                assert_eq!(distances[(x, y)], hex.distance((x, y), (3, 3)) as f64);
+            }
+        }
+    }
+
+    #[test]
+    fn test_classify_biomes_temperature_follows_latitude_and_altitude() {
+        let tiles = DMatrix::from_fn(3, 21, |x, _| Tile::new(TileType::Grassland, x as f64));
+        let classified = classify_biomes(tiles, &BiomeOptions::new(1), GridTopology::SQUARE);
+
+        let equator = classified[(0, 10)].temperature();
+        assert!(equator > classified[(0, 0)].temperature());
+        assert!(equator > classified[(0, 20)].temperature());
+        assert!(equator > classified[(2, 10)].temperature());
+        assert_eq!(classified[(0, 0)].terrain(), TileType::Ice);
+        assert!(classified.iter().all(|tile| (0.0..=1.0).contains(&tile.moisture())));
+    }
+
+    #[test]
+    fn test_classify_biomes_takes_the_latitude_it_is_given() {
+        let tiles = DMatrix::from_fn(3, 21, |_, _| Tile::new(TileType::Grassland, 0.0));
+        let classified =
+            classify_biomes_with_latitude(tiles, &BiomeOptions::new(1), GridTopology::SQUARE, |x, _| x as f64 / 2.0);
+
+        for y in 0..21 {
+            assert_eq!(classified[(0, y)].temperature(), 1.0);
+            assert_eq!(classified[(2, y)].temperature(), 0.0);
+            assert_eq!(classified[(2, y)].terrain(), TileType::Ice);
+        }
+    }
+
+    #[test]
+    fn test_classify_biomes_keeps_water() {
+        let tiles = DMatrix::from_fn(4, 9, |x, _| {
+            let terrain = if x < 2 { TileType::Water } else { TileType::Grassland };
+            Tile::new(terrain, 0.0)
+        });
+        let classified = classify_biomes(tiles, &BiomeOptions::new(1), GridTopology::SQUARE);
+
+        assert_eq!(classified[(0, 4)].terrain(), TileType::Water);
+        assert_eq!(classified[(2, 4)].terrain(), TileType::Coast);
+        assert_eq!(classified[(0, 0)].terrain(), TileType::SeaIce);
+        assert!(classified[(0, 0)].terrain().is_water());
+        assert!(classified[(1, 4)].moisture() > 0.0);
+    }
+}
@@ -0,0 +1,176 @@
+use crate::map_generators::tiled_diagram::TiledDiagram;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use voronoice::BoundingBox;
+
+use std::collections::BTreeSet;
+
+/// How a continent picks the next cell to grow into.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum ContinentGrowth {
+    /// A free neighbor of a random cell of the continent. Cells deep inland
+    /// are picked as often as coastal ones, so continents grow long arms
+    /// where they happen to get lucky.
+    #[default]
+    Random,
+    /// Any free cell along the coast, all equally likely. Gives rounder
+    /// continents.
+    Frontier,
+    /// Like [`ContinentGrowth::Frontier`] but prefers cells on a line through
+    /// the first cell of the continent. `angle` is in degrees from the x axis
+    /// and an `elongation` of `0.0` is the same as `Frontier`.
+    Directional { angle: f64, elongation: f64 },
+}
+
+/// Which continent owns which cell while the continents grow.
+pub(crate) struct GrowthMap<'a> {
+    diagram: &'a TiledDiagram,
+    land_box: &'a BoundingBox,
+    ocean_gap: bool,
+    owners: Vec<Option<usize>>,
+    land_probability: Option<Vec<f64>>,
+}
+
+impl<'a> GrowthMap<'a> {
+    pub(crate) fn new(diagram: &'a TiledDiagram, land_box: &'a BoundingBox, ocean_gap: bool) -> GrowthMap<'a> {
+        GrowthMap {
+            diagram,
+            land_box,
+            ocean_gap,
+            owners: vec![None; diagram.site_count()],
+            land_probability: None,
+        }
+    }
+
+    /// Chance of every cell to be accepted when a continent tries to grow
+    /// into it, which lets a stencil steer the continents.
+    pub(crate) fn set_land_probability(&mut self, land_probability: Vec<f64>) {
+        self.land_probability = Some(land_probability);
+    }
+
+    pub(crate) fn claim(&mut self, cell_index: usize, continent: usize) {
+        self.owners[cell_index] = Some(continent);
+    }
+
+    /// Whether `continent` can grow into the cell. With an ocean gap a cell
+    /// touching another continent stays water.
+    pub(crate) fn is_free(&self, cell_index: usize, continent: usize) -> bool {
+        let cell = self.diagram.cell(cell_index);
+        self.owners[cell_index].is_none()
+            && self.land_box.is_inside(cell.site_position())
+            && !(self.ocean_gap
+                && self
+                    .diagram
+                    .neighbors(cell_index)
+                    .any(|neighbor| self.owners[neighbor].is_some_and(|owner| owner != continent)))
+    }
+
+    /// Free cells next to the continent.
+    fn frontier(&self, cells: &BTreeSet<usize>, continent: usize) -> BTreeSet<usize> {
+        cells
+            .iter()
+            .flat_map(|cell_index| self.diagram.neighbors(*cell_index).collect::<Vec<usize>>())
+            .filter(|neighbor| self.is_free(*neighbor, continent))
+            .collect()
+    }
+
+    pub(crate) fn can_grow(&self, cells: &BTreeSet<usize>, continent: usize) -> bool {
+        cells.iter().any(|cell_index| {
+            self.diagram
+                .neighbors(*cell_index)
+                .any(|neighbor| self.is_free(neighbor, continent))
+        })
+    }
+
+    /// Picks the cell `continent` grows into next, starting from `origin`.
+    /// `None` only means this attempt found nothing, [`GrowthMap::can_grow`]
+    /// tells whether the continent is stuck for good.
+    pub(crate) fn next_cell<R: Rng>(
+        &self,
+        growth: ContinentGrowth,
+        cells: &BTreeSet<usize>,
+        continent: usize,
+        origin: usize,
+        rng: &mut R,
+    ) -> Option<usize> {
+        let cell_index = match growth {
+            ContinentGrowth::Random => {
+                let cell_index = *cells.iter().choose(rng)?;
+                let mut neighbors: Vec<usize> = self.diagram.neighbors(cell_index).collect();
+                neighbors.shuffle(rng);
+                neighbors.into_iter().find(|neighbor| self.is_free(*neighbor, continent))
+            }
+            ContinentGrowth::Frontier => self.frontier(cells, continent).into_iter().choose(rng),
+            ContinentGrowth::Directional { angle, elongation } => {
+                let frontier: Vec<usize> = self.frontier(cells, continent).into_iter().collect();
+                let origin = &self.diagram.sites()[origin];
+                let (direction_y, direction_x) = angle.to_radians().sin_cos();
+                frontier
+                    .choose_weighted(rng, |cell_index| {
+                        let site = &self.diagram.sites()[*cell_index];
+                        let (x, y) = self.diagram.offset(origin, site);
+                        let length = (x * x + y * y).sqrt();
+                        if length == 0.0 {
+                            return 1.0;
+                        }
+                        let alignment = (x * direction_x + y * direction_y) / length;
+                        1.0 + elongation.max(0.0) * alignment * alignment
+                    })
+                    .ok()
+                    .copied()
+            }
+        }?;
+        match &self.land_probability {
+            Some(land_probability) if !rng.gen_bool(land_probability[cell_index].clamp(0.0, 1.0)) => None,
+            _ => Some(cell_index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ContinentGrowth, GrowthMap};
+    use crate::map_generators::TiledDiagram;
+    use rand_pcg::Pcg64;
+    use rand_seeder::Seeder;
+    use voronoice::{BoundingBox, Point, VoronoiBuilder};
+
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_ocean_gap_keeps_continents_apart() {
+        let sites: Vec<Point> = (0..100)
+            .map(|index| Point {
+                x: (index % 10) as f64 * 10.0 - 45.0,
+                y: (index / 10) as f64 * 10.0 - 45.0,
+            })
+            .collect();
+        let diagram: TiledDiagram = VoronoiBuilder::default()
+            .set_sites(sites)
+            .set_bounding_box(BoundingBox::new_centered(100.0, 100.0))
+            .build()
+            .expect("Failed to create a voronoi diagram")
+            .into();
+        let land_box = BoundingBox::new_centered(100.0, 100.0);
+        let mut rng: Pcg64 = Seeder::from("gap").make_rng();
+
+        let mut growth = GrowthMap::new(&diagram, &land_box, true);
+        let mut continents = [BTreeSet::from([0]), BTreeSet::from([99])];
+        growth.claim(0, 0);
+        growth.claim(99, 1);
+        while let Some(continent) = (0..2).find(|continent| growth.can_grow(&continents[*continent], *continent)) {
+            let origin = *continents[continent].first().unwrap();
+            if let Some(cell_index) =
+                growth.next_cell(ContinentGrowth::Frontier, &continents[continent], continent, origin, &mut rng)
+            {
+                growth.claim(cell_index, continent);
+                continents[continent].insert(cell_index);
+            }
+        }
+
+        for cell_index in &continents[0] {
+            assert!(diagram.neighbors(*cell_index).all(|neighbor| !continents[1].contains(&neighbor)));
+        }
+        assert!(continents[0].len() + continents[1].len() < 100);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::map_generators::grid::{roll, WrapMode};
+use fastnoise_lite::{FastNoiseLite, FractalType, NoiseType};
+use serde::{Deserialize, Serialize};
+
+/// Noise that drives a [`DomainWarp`].
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum WarpNoiseType {
+    #[default]
+    OpenSimplex2,
+    OpenSimplex2S,
+    Perlin,
+    ValueCubic,
+    Value,
+}
+
+impl From<WarpNoiseType> for NoiseType {
+    fn from(noise_type: WarpNoiseType) -> Self {
+        match noise_type {
+            WarpNoiseType::OpenSimplex2 => NoiseType::OpenSimplex2,
+            WarpNoiseType::OpenSimplex2S => NoiseType::OpenSimplex2S,
+            WarpNoiseType::Perlin => NoiseType::Perlin,
+            WarpNoiseType::ValueCubic => NoiseType::ValueCubic,
+            WarpNoiseType::Value => NoiseType::Value,
+        }
+    }
+}
+
+/// Moves sample points around with fractal noise so straight edges in
+/// whatever gets sampled come out ragged.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct DomainWarpOptions {
+    amplitude: f64,
+    frequency: f64,
+    octaves: u32,
+    noise_type: WarpNoiseType,
+    levels: usize,
+}
+
+impl DomainWarpOptions {
+    pub fn new() -> DomainWarpOptions {
+        DomainWarpOptions {
+            amplitude: 40.0,
+            frequency: 0.005,
+            octaves: 4,
+            noise_type: WarpNoiseType::OpenSimplex2,
+            levels: 1,
+        }
+    }
+
+    /// Furthest a point moves per level, in tiles. `0.0` turns warping off.
+    pub fn set_amplitude(&mut self, amplitude: f64) {
+        self.amplitude = amplitude;
+    }
+
+    /// Frequency of the noise in cycles per tile.
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.frequency = frequency;
+    }
+
+    pub fn set_octaves(&mut self, octaves: u32) {
+        self.octaves = octaves;
+    }
+
+    pub fn set_noise_type(&mut self, noise_type: WarpNoiseType) {
+        self.noise_type = noise_type;
+    }
+
+    /// How many times the warp is fed its own output. Every extra level
+    /// folds the coastlines further.
+    pub fn set_levels(&mut self, levels: usize) {
+        self.levels = levels;
+    }
+
+    pub fn amplitude(&self) -> f64 {
+        self.amplitude
+    }
+
+    pub fn frequency(&self) -> f64 {
+        self.frequency
+    }
+
+    pub fn octaves(&self) -> u32 {
+        self.octaves
+    }
+
+    pub fn noise_type(&self) -> WarpNoiseType {
+        self.noise_type
+    }
+
+    pub fn levels(&self) -> usize {
+        self.levels
+    }
+}
+
+impl Default for DomainWarpOptions {
+    fn default() -> Self {
+        DomainWarpOptions::new()
+    }
+}
+
+/// Domain warp ready to sample, one noise per axis.
+pub struct DomainWarp {
+    noise_x: FastNoiseLite,
+    noise_y: FastNoiseLite,
+    amplitude: f64,
+    levels: usize,
+    wrap: WrapMode,
+    width: f64,
+    height: f64,
+}
+
+impl DomainWarp {
+    pub fn new(options: &DomainWarpOptions, seed_x: u32, seed_y: u32) -> DomainWarp {
+        let noise = |seed: u32| {
+            let mut noise = FastNoiseLite::with_seed(seed as i32);
+            noise.set_noise_type(Some(options.noise_type.into()));
+            noise.set_frequency(Some(options.frequency as f32));
+            noise.set_fractal_type(Some(FractalType::FBm));
+            noise.set_fractal_octaves(Some(options.octaves as i32));
+            noise
+        };
+        DomainWarp {
+            noise_x: noise(seed_x),
+            noise_y: noise(seed_y),
+            amplitude: options.amplitude,
+            levels: options.levels,
+            wrap: WrapMode::None,
+            width: 0.0,
+            height: 0.0,
+        }
+    }
+
+    /// Makes the warp repeat every `width` along x, and every `height` along
+    /// y on a torus, so points on both sides of a seam move the same way.
+    pub fn set_wrap(&mut self, wrap: WrapMode, width: f64, height: f64) {
+        self.wrap = wrap;
+        self.width = width;
+        self.height = height;
+    }
+
+    /// Where the point `(x, y)` gets moved to.
+    pub fn warp(&self, x: f64, y: f64) -> (f64, f64) {
+        let (mut warped_x, mut warped_y) = (x, y);
+        for _ in 0..self.levels {
+            let offset_x = self.offset(&self.noise_x, warped_x, warped_y);
+            let offset_y = self.offset(&self.noise_y, warped_x, warped_y);
+            warped_x = x + self.amplitude * offset_x;
+            warped_y = y + self.amplitude * offset_y;
+        }
+        (warped_x, warped_y)
+    }
+
+    /// FastNoise only goes up to 3D, so on a torus the noise of the cylinder
+    /// is blended with itself one map height further down.
+    fn offset(&self, noise: &FastNoiseLite, x: f64, y: f64) -> f64 {
+        let cylinder = |y: f64| {
+            let [around_x, around_y] = roll(x, self.width);
+            noise.get_noise_3d(around_x as f32, around_y as f32, y as f32) as f64
+        };
+        match self.wrap {
+            WrapMode::None => noise.get_noise_2d(x as f32, y as f32) as f64,
+            WrapMode::Horizontal => cylinder(y),
+            WrapMode::Torus => {
+                let y = y.rem_euclid(self.height);
+                let blend = y / self.height;
+                (1.0 - blend) * cylinder(y) + blend * cylinder(y - self.height)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DomainWarp, DomainWarpOptions};
+    use crate::map_generators::WrapMode;
+
+    #[test]
+    fn test_warp_stays_within_amplitude() {
+        for levels in [0, 1, 3] {
+            let mut options = DomainWarpOptions::new();
+            options.set_amplitude(25.0);
+            options.set_levels(levels);
+            let warp = DomainWarp::new(&options, 1, 2);
+
+            let mut moved = false;
+            for index in 0..500 {
+                let (x, y) = ((index * 37 % 400) as f64, (index * 11 % 300) as f64);
+                let (warped_x, warped_y) = warp.warp(x, y);
+                assert!((warped_x - x).abs() <= 25.0 && (warped_y - y).abs() <= 25.0);
+                moved |= (warped_x, warped_y) != (x, y);
+            }
+            assert_eq!(moved, levels > 0);
+        }
+    }
+
+    #[test]
+    fn test_wrapped_warp_repeats_across_the_seams() {
+        for wrap in [WrapMode::Horizontal, WrapMode::Torus] {
+            let mut warp = DomainWarp::new(&DomainWarpOptions::new(), 1, 2);
+            warp.set_wrap(wrap, 400.0, 300.0);
+
+            for index in 0..100 {
+                let (x, y) = ((index * 37 % 400) as f64, (index * 11 % 300) as f64);
+                let (warped_x, warped_y) = warp.warp(x, y);
+                let (across_x, across_y) = warp.warp(x + 400.0, y);
+                assert!((across_x - 400.0 - warped_x).abs() < 1e-3 && (across_y - warped_y).abs() < 1e-3);
+                if wrap == WrapMode::Torus {
+                    let (across_x, across_y) = warp.warp(x, y - 300.0);
+                    assert!((across_x - warped_x).abs() < 1e-3 && (across_y + 300.0 - warped_y).abs() < 1e-3);
+                }
+            }
+        }
+    }
+}
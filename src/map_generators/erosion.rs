@@ -0,0 +1,445 @@
+use crate::map_generators::grid::GridTopology;
+use crate::map_generators::map_generator::MapGeneratorError;
+use crate::map_generators::pipeline::MapPass;
+use crate::map_generators::progress::{NoProgress, ProgressSink};
+use crate::map_generators::tile::Tile;
+use crate::math_helpers::create_new_seed32;
+use nalgebra::DMatrix;
+use rand::Rng;
+use rand_pcg::Pcg64Mcg;
+use rand_seeder::Seeder;
+use serde::{Deserialize, Serialize};
+
+/// Controls the droplet based hydraulic erosion and the thermal erosion that
+/// runs after it.
+///
+/// Rates and thresholds are in elevation units, so they depend on the
+/// generator. The defaults suit the `-1.0..1.0` elevation of `noise_map`
+/// at 1920x1080.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ErosionOptions {
+    seed: u32,
+    droplet_count: usize,
+    max_droplet_lifetime: usize,
+    inertia: f64,
+    sediment_capacity: f64,
+    min_sediment_capacity: f64,
+    erosion_rate: f64,
+    deposition_rate: f64,
+    evaporation_rate: f64,
+    gravity: f64,
+    erosion_radius: usize,
+    thermal_iterations: usize,
+    talus: f64,
+    thermal_rate: f64,
+}
+
+impl ErosionOptions {
+    pub fn new(seed: u32) -> ErosionOptions {
+        ErosionOptions {
+            seed,
+            droplet_count: 200_000,
+            max_droplet_lifetime: 30,
+            inertia: 0.05,
+            sediment_capacity: 4.0,
+            min_sediment_capacity: 0.0001,
+            erosion_rate: 0.3,
+            deposition_rate: 0.3,
+            evaporation_rate: 0.01,
+            gravity: 4.0,
+            erosion_radius: 2,
+            thermal_iterations: 10,
+            talus: 0.005,
+            thermal_rate: 0.5,
+        }
+    }
+
+    pub fn droplet_count(&self) -> usize {
+        self.droplet_count
+    }
+
+    pub fn set_droplet_count(&mut self, droplet_count: usize) {
+        self.droplet_count = droplet_count;
+    }
+
+    pub fn set_max_droplet_lifetime(&mut self, max_droplet_lifetime: usize) {
+        self.max_droplet_lifetime = max_droplet_lifetime;
+    }
+
+    /// How much of its old direction a droplet keeps each step, from `0.0`
+    /// (always straight downhill) to `1.0` (never turns).
+    pub fn set_inertia(&mut self, inertia: f64) {
+        self.inertia = inertia;
+    }
+
+    pub fn set_sediment_capacity(&mut self, sediment_capacity: f64) {
+        self.sediment_capacity = sediment_capacity;
+    }
+
+    pub fn set_min_sediment_capacity(&mut self, min_sediment_capacity: f64) {
+        self.min_sediment_capacity = min_sediment_capacity;
+    }
+
+    pub fn set_erosion_rate(&mut self, erosion_rate: f64) {
+        self.erosion_rate = erosion_rate;
+    }
+
+    pub fn set_deposition_rate(&mut self, deposition_rate: f64) {
+        self.deposition_rate = deposition_rate;
+    }
+
+    pub fn set_evaporation_rate(&mut self, evaporation_rate: f64) {
+        self.evaporation_rate = evaporation_rate;
+    }
+
+    pub fn set_gravity(&mut self, gravity: f64) {
+        self.gravity = gravity;
+    }
+
+    /// Radius in tiles a droplet erodes from.
+    pub fn set_erosion_radius(&mut self, erosion_radius: usize) {
+        self.erosion_radius = erosion_radius;
+    }
+
+    pub fn set_thermal_iterations(&mut self, thermal_iterations: usize) {
+        self.thermal_iterations = thermal_iterations;
+    }
+
+    /// Steepest height difference between neighboring tiles that thermal
+    /// erosion leaves alone.
+    pub fn set_talus(&mut self, talus: f64) {
+        self.talus = talus;
+    }
+
+    /// Share of the height difference above the talus that slides down per
+    /// iteration.
+    pub fn set_thermal_rate(&mut self, thermal_rate: f64) {
+        self.thermal_rate = thermal_rate;
+    }
+}
+
+impl Default for ErosionOptions {
+    fn default() -> Self {
+        let mut seeder = Seeder::from("Initial Seed");
+        ErosionOptions::new(create_new_seed32(&mut seeder))
+    }
+}
+
+/// Interpolated height and gradient at a position inside the map.
+fn height_and_gradient(heights: &DMatrix<f64>, x: f64, y: f64) -> (f64, f64, f64) {
+    let (cx, cy) = (x as usize, y as usize);
+    let (u, v) = (x - cx as f64, y - cy as f64);
+    let (next_x, next_y) = next_corner(heights, cx, cy);
+    let h00 = heights[(cx, cy)];
+    let h10 = heights[(next_x, cy)];
+    let h01 = heights[(cx, next_y)];
+    let h11 = heights[(next_x, next_y)];
+
+    let gradient_x = (h10 - h00) * (1.0 - v) + (h11 - h01) * v;
+    let gradient_y = (h01 - h00) * (1.0 - u) + (h11 - h10) * u;
+    let height = h00 * (1.0 - u) * (1.0 - v) + h10 * u * (1.0 - v) + h01 * (1.0 - u) * v + h11 * u * v;
+    (height, gradient_x, gradient_y)
+}
+
+/// Column and row after `(cx, cy)`. Only droplets on maps that wrap get
+/// past the last column or row, and they go on at the other side.
+fn next_corner(heights: &DMatrix<f64>, cx: usize, cy: usize) -> (usize, usize) {
+    ((cx + 1) % heights.nrows(), (cy + 1) % heights.ncols())
+}
+
+/// Brings a droplet that ran over a seam back onto the map. The second
+/// remainder catches tiny negative values that round up to `period`.
+fn wrap_around(value: f64, period: usize) -> f64 {
+    value.rem_euclid(period as f64) % period as f64
+}
+
+/// Tile offsets around a droplet with weights falling off linearly to the
+/// erosion radius.
+fn erosion_brush(radius: usize) -> Vec<(isize, isize, f64)> {
+    let radius = radius as isize;
+    let mut brush = Vec::new();
+    for dy in -radius..=radius {
+        for dx in -radius..=radius {
+            let distance = ((dx * dx + dy * dy) as f64).sqrt();
+            let weight = radius as f64 + 1.0 - distance;
+            if distance <= radius as f64 {
+                brush.push((dx, dy, weight));
+            }
+        }
+    }
+    brush
+}
+
+/// Simulates `droplet_count` rain droplets, one after another, that run
+/// downhill picking up sediment where they speed up and dropping it where
+/// they slow down. Droplets stop on water tiles and carry their sediment
+/// out to sea. Across edges that wrap they carry on at the other side of the
+/// map.
+pub fn hydraulic_erosion(
+    tiles: &DMatrix<Tile>,
+    heights: &mut DMatrix<f64>,
+    options: &ErosionOptions,
+    topology: GridTopology,
+    progress: &dyn ProgressSink,
+) -> Result<(), MapGeneratorError> {
+    let wrap = topology.wrap();
+    let (width, height) = heights.shape();
+    if width < 2 || height < 2 {
+        return Ok(());
+    }
+    let mut rng: Pcg64Mcg = Seeder::from(options.seed).make_rng();
+    let brush = erosion_brush(options.erosion_radius);
+
+    for droplet in 0..options.droplet_count {
+        if droplet % 1024 == 0 {
+            if progress.is_cancelled() {
+                return Err(MapGeneratorError::Cancelled);
+            }
+            progress.report("hydraulic_erosion", droplet, options.droplet_count);
+        }
+        let mut x = rng.gen_range(0.0..if wrap.wraps_x() { width } else { width - 1 } as f64);
+        let mut y = rng.gen_range(0.0..if wrap.wraps_y() { height } else { height - 1 } as f64);
+        let (mut direction_x, mut direction_y) = (0.0, 0.0);
+        let mut speed = 1.0;
+        let mut water = 1.0;
+        let mut sediment = 0.0;
+
+        for _ in 0..options.max_droplet_lifetime {
+            let (cx, cy) = (x as usize, y as usize);
+            if tiles[(cx, cy)].terrain().is_water() {
+                break;
+            }
+            let (u, v) = (x - cx as f64, y - cy as f64);
+            let (current_height, gradient_x, gradient_y) = height_and_gradient(heights, x, y);
+
+            direction_x = direction_x * options.inertia - gradient_x * (1.0 - options.inertia);
+            direction_y = direction_y * options.inertia - gradient_y * (1.0 - options.inertia);
+            let length = (direction_x * direction_x + direction_y * direction_y).sqrt();
+            if length == 0.0 {
+                break;
+            }
+            direction_x /= length;
+            direction_y /= length;
+            x += direction_x;
+            y += direction_y;
+            if wrap.wraps_x() {
+                x = wrap_around(x, width);
+            }
+            if wrap.wraps_y() {
+                y = wrap_around(y, height);
+            }
+            if x < 0.0
+                || y < 0.0
+                || (!wrap.wraps_x() && x >= (width - 1) as f64)
+                || (!wrap.wraps_y() && y >= (height - 1) as f64)
+            {
+                break;
+            }
+
+            let delta_height = height_and_gradient(heights, x, y).0 - current_height;
+            let capacity = (-delta_height * speed * water * options.sediment_capacity).max(options.min_sediment_capacity);
+
+            if sediment > capacity || delta_height > 0.0 {
+                // Uphill the droplet fills the pit behind it, otherwise it drops
+                // what it can't carry. Either way the four corners share it.
+                let deposit = if delta_height > 0.0 {
+                    delta_height.min(sediment)
+                } else {
+                    (sediment - capacity) * options.deposition_rate
+                };
+                sediment -= deposit;
+                let (next_x, next_y) = next_corner(heights, cx, cy);
+                heights[(cx, cy)] += deposit * (1.0 - u) * (1.0 - v);
+                heights[(next_x, cy)] += deposit * u * (1.0 - v);
+                heights[(cx, next_y)] += deposit * (1.0 - u) * v;
+                heights[(next_x, next_y)] += deposit * u * v;
+            } else {
+                let erode = ((capacity - sediment) * options.erosion_rate).min(-delta_height);
+                let cells = || {
+                    brush
+                        .iter()
+                        .filter_map(|&(dx, dy, weight)| {
+                            let (bx, by) = topology.wrap_tile(cx as i64 + dx as i64, cy as i64 + dy as i64, width, height)?;
+                            Some((bx, by, weight))
+                        })
+                };
+                let total_weight: f64 = cells().map(|(_, _, weight)| weight).sum();
+                for (bx, by, weight) in cells() {
+                    heights[(bx, by)] -= erode * weight / total_weight;
+                }
+                sediment += erode;
+            }
+
+            speed = (speed * speed - delta_height * options.gravity).max(0.0).sqrt();
+            water *= 1.0 - options.evaporation_rate;
+        }
+    }
+    progress.report("hydraulic_erosion", options.droplet_count, options.droplet_count);
+    Ok(())
+}
+
+/// Moves material from every tile to its lower edge neighbors wherever the
+/// difference is steeper than the talus. All tiles move at once, so the
+/// result doesn't depend on the order they're visited in.
+pub fn thermal_erosion(
+    heights: &mut DMatrix<f64>,
+    options: &ErosionOptions,
+    topology: GridTopology,
+    progress: &dyn ProgressSink,
+) -> Result<(), MapGeneratorError> {
+    let (width, height) = heights.shape();
+
+    for iteration in 0..options.thermal_iterations {
+        if progress.is_cancelled() {
+            return Err(MapGeneratorError::Cancelled);
+        }
+        progress.report("thermal_erosion", iteration, options.thermal_iterations);
+        let mut delta = DMatrix::zeros(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let excess = topology
+                    .edge_neighbors(x, y, width, height)
+                    .map(|neighbor| (neighbor, (heights[(x, y)] - heights[neighbor] - options.talus).max(0.0)));
+                let total_excess: f64 = excess.clone().map(|(_, excess)| excess).sum();
+                let max_excess = excess.clone().map(|(_, excess)| excess).fold(0.0, f64::max);
+                if total_excess <= 0.0 {
+                    continue;
+                }
+
+                let moved = options.thermal_rate * max_excess / 2.0;
+                delta[(x, y)] -= moved;
+                for (neighbor, excess) in excess {
+                    if excess > 0.0 {
+                        delta[neighbor] += moved * excess / total_excess;
+                    }
+                }
+            }
+        }
+        *heights += delta;
+    }
+    progress.report("thermal_erosion", options.thermal_iterations, options.thermal_iterations);
+    Ok(())
+}
+
+/// Hydraulic erosion followed by thermal erosion. Only the elevation
+/// changes, so run it before anything that reads the elevation such as the
+/// hydrology and biome passes.
+pub fn erode(tiles: DMatrix<Tile>, options: &ErosionOptions) -> DMatrix<Tile> {
+    erode_with_progress(tiles, options, GridTopology::SQUARE, &NoProgress).expect("Nothing cancels without a sink")
+}
+
+pub fn erode_with_progress(
+    tiles: DMatrix<Tile>,
+    options: &ErosionOptions,
+    topology: GridTopology,
+    progress: &dyn ProgressSink,
+) -> Result<DMatrix<Tile>, MapGeneratorError> {
+    let mut heights = tiles.map(|tile| tile.elevation());
+    hydraulic_erosion(&tiles, &mut heights, options, topology, progress)?;
+    thermal_erosion(&mut heights, options, topology, progress)?;
+
+    Ok(tiles.zip_map(&heights, |tile, elevation| {
+        Tile::new(tile.terrain(), elevation).with_climate(tile.temperature(), tile.moisture())
+    }))
+}
+
+/// Erosion as a pipeline pass. The droplets are seeded by the pipeline.
+pub struct ErosionPass {
+    options: ErosionOptions,
+}
+
+impl ErosionPass {
+    pub fn new(options: ErosionOptions) -> ErosionPass {
+        ErosionPass { options }
+    }
+}
+
+impl MapPass for ErosionPass {
+    fn name(&self) -> &str {
+        "erosion"
+    }
+
+    fn apply(
+        &self,
+        tiles: DMatrix<Tile>,
+        topology: GridTopology,
+        seeder: &mut Seeder,
+        progress: &dyn ProgressSink,
+    ) -> Result<DMatrix<Tile>, MapGeneratorError> {
+        let mut options = self.options.clone();
+        options.seed = create_new_seed32(seeder);
+        erode_with_progress(tiles, &options, topology, progress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{erode, erode_with_progress, thermal_erosion, ErosionOptions};
+    use crate::map_generators::NoProgress;
+    use crate::map_generators::{GridTopology, HexLayout, Tile, TileType, WrapMode};
+    use nalgebra::DMatrix;
+
+    fn hill() -> DMatrix<Tile> {
+        DMatrix::from_fn(64, 64, |x, y| {
+            let distance = ((x as f64 - 32.0).powi(2) + (y as f64 - 32.0).powi(2)).sqrt();
+            let elevation = 1.0 - distance / 32.0 + 0.02 * ((x * 7 + y * 13) % 5) as f64;
+            let terrain = if elevation < 0.0 { TileType::Water } else { TileType::Grassland };
+            Tile::new(terrain, elevation)
+        })
+    }
+
+    fn options(seed: u32) -> ErosionOptions {
+        let mut options = ErosionOptions::new(seed);
+        options.set_droplet_count(2000);
+        options.set_talus(0.05);
+        options
+    }
+
+    #[test]
+    fn test_erosion_is_reproducible() {
+        let eroded = erode(hill(), &options(1));
+
+        assert_eq!(eroded, erode(hill(), &options(1)));
+        assert_ne!(eroded, erode(hill(), &options(2)));
+        assert_ne!(eroded, hill());
+        assert!(eroded.iter().all(|tile| tile.elevation().is_finite()));
+        assert!(eroded.iter().zip(hill().iter()).all(|(eroded, tile)| eroded.terrain() == tile.terrain()));
+    }
+
+    #[test]
+    fn test_erosion_doesnt_add_material() {
+        let before: f64 = hill().iter().map(|tile| tile.elevation()).sum();
+        let after: f64 = erode(hill(), &options(1)).iter().map(|tile| tile.elevation()).sum();
+        assert!(after <= before + 1e-9);
+    }
+
+    #[test]
+    fn test_droplets_run_across_the_seams() {
+        // The hill sits on the corner of the map, so on a torus it's whole.
+        let corner_hill = DMatrix::from_fn(64, 64, |x, y| hill()[((x + 32) % 64, (y + 32) % 64)]);
+        let topology = GridTopology::SQUARE.with_wrap(WrapMode::Torus);
+        let eroded =
+            erode_with_progress(corner_hill.clone(), &options(1), topology, &NoProgress).expect("Erosion was cancelled");
+
+        let before: f64 = corner_hill.iter().map(|tile| tile.elevation()).sum();
+        let after: f64 = eroded.iter().map(|tile| tile.elevation()).sum();
+        assert!(after <= before + 1e-9);
+        for corner in [(0, 0), (63, 0), (0, 63), (63, 63)] {
+            assert_ne!(eroded[corner], corner_hill[corner], "{corner:?}");
+        }
+    }
+
+    #[test]
+    fn test_thermal_erosion_flattens_steep_slopes() {
+        let mut options = ErosionOptions::new(0);
+        options.set_talus(0.1);
+        options.set_thermal_iterations(50);
+        for topology in [GridTopology::SQUARE, GridTopology::hex(HexLayout::OddRows)] {
+            let mut heights = DMatrix::from_fn(9, 9, |x, y| if (x, y) == (4, 4) { 1.0 } else { 0.0 });
+            thermal_erosion(&mut heights, &options, topology, &NoProgress).expect("Thermal erosion was cancelled");
+
+            assert!((heights.sum() - 1.0).abs() < 1e-9);
+            assert!(heights[(4, 4)] - heights[(4, 3)] < 0.2, "{topology:?}");
+        }
+    }
+}
@@ -0,0 +1,322 @@
+use crate::map_generators::noise_map::distance_from_center;
+use image::GrayImage;
+use lerp::Lerp;
+use nalgebra::DMatrix;
+use rand::prelude::*;
+use rand_pcg::Pcg64Mcg;
+use rand_seeder::Seeder;
+use serde::{Deserialize, Serialize};
+
+use std::path::Path;
+
+/// A side of the map. North is the `y = 0` edge and west the `x = 0` one.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum MapEdge {
+    North,
+    East,
+    South,
+    #[default]
+    West,
+}
+
+/// A grayscale image used as a falloff mask, white being land.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct GrayscaleMask {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl GrayscaleMask {
+    pub fn from_image(image: &GrayImage) -> GrayscaleMask {
+        GrayscaleMask {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            pixels: image.as_raw().clone(),
+        }
+    }
+
+    /// Loads any image the `image` crate can read, converting it to
+    /// grayscale.
+    pub fn load(path: &Path) -> Result<GrayscaleMask, image::ImageError> {
+        Ok(GrayscaleMask::from_image(&image::open(path)?.to_luma8()))
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Bilinearly filtered brightness between `0.0` and `1.0`, `u` and `v`
+    /// going from `0.0` to `1.0` across the image.
+    pub fn sample(&self, u: f64, v: f64) -> f64 {
+        if self.width == 0 || self.height == 0 {
+            return 0.0;
+        }
+        let x = u.clamp(0.0, 1.0) * (self.width - 1) as f64;
+        let y = v.clamp(0.0, 1.0) * (self.height - 1) as f64;
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let pixel = |x: usize, y: usize| self.pixels[y * self.width + x] as f64 / 255.0;
+        let top = pixel(x0, y0).lerp(pixel(x1, y0), x.fract());
+        let bottom = pixel(x0, y1).lerp(pixel(x1, y1), x.fract());
+        top.lerp(bottom, y.fract())
+    }
+}
+
+/// Where the falloff keeps land, from `1.0` for land to `0.0` for open sea.
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub enum FalloffShape {
+    /// Leaves the noise alone.
+    None,
+    /// One rounded rectangle filling the map, the shape every noise map had
+    /// before masks were configurable.
+    #[default]
+    Square,
+    /// One round island touching the middle of each edge.
+    Radial,
+    /// A few large islands scattered around the middle of the map.
+    MultiIsland { islands: usize },
+    /// Many small islands strung along a chain across the map.
+    Archipelago { islands: usize },
+    /// Land along one edge sloping down into the sea at the other.
+    EdgeContinent { edge: MapEdge },
+    /// A grayscale image stretched over the map.
+    Image(GrayscaleMask),
+}
+
+/// Reshapes the mask before it is blended in.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum FalloffCurve {
+    #[default]
+    Linear,
+    /// Flattens the mask at both ends so coasts get a wide shelf and
+    /// interiors a plateau.
+    Smoothstep,
+    /// Exponents above `1.0` shrink the land, below `1.0` grow it.
+    Power { exponent: f64 },
+}
+
+impl FalloffCurve {
+    pub fn apply(&self, value: f64) -> f64 {
+        match self {
+            FalloffCurve::Linear => value,
+            FalloffCurve::Smoothstep => value * value * (3.0 - 2.0 * value),
+            FalloffCurve::Power { exponent } => value.powf(*exponent),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct FalloffOptions {
+    shape: FalloffShape,
+    curve: FalloffCurve,
+    strength: f64,
+}
+
+impl FalloffOptions {
+    pub fn new() -> FalloffOptions {
+        FalloffOptions {
+            shape: FalloffShape::Square,
+            curve: FalloffCurve::Linear,
+            strength: 0.5,
+        }
+    }
+
+    pub fn set_shape(&mut self, shape: FalloffShape) {
+        self.shape = shape;
+    }
+
+    pub fn set_curve(&mut self, curve: FalloffCurve) {
+        self.curve = curve;
+    }
+
+    /// How far the noise is pulled towards the mask, `0.0` is pure noise and
+    /// `1.0` the mask alone.
+    pub fn set_strength(&mut self, strength: f64) {
+        self.strength = strength;
+    }
+
+    pub fn shape(&self) -> &FalloffShape {
+        &self.shape
+    }
+
+    pub fn curve(&self) -> FalloffCurve {
+        self.curve
+    }
+
+    pub fn strength(&self) -> f64 {
+        self.strength
+    }
+}
+
+impl Default for FalloffOptions {
+    fn default() -> Self {
+        FalloffOptions::new()
+    }
+}
+
+/// A falloff mask ready to sample on a map of a given size.
+pub struct Falloff<'a> {
+    options: &'a FalloffOptions,
+    map_width: usize,
+    map_height: usize,
+    /// Island centers in `-1.0..=1.0` map coordinates, and their radius.
+    islands: Vec<(f64, f64)>,
+    island_radius: f64,
+}
+
+impl<'a> Falloff<'a> {
+    /// `seed` places the islands of the shapes that have them.
+    pub fn new(options: &'a FalloffOptions, map_width: usize, map_height: usize, seed: u32) -> Falloff<'a> {
+        let mut rng: Pcg64Mcg = Seeder::from(("falloff", seed)).make_rng();
+        let (islands, island_radius) = match options.shape {
+            FalloffShape::MultiIsland { islands } => {
+                let centers = (0..islands).map(|_| (rng.gen_range(-0.6..=0.6), rng.gen_range(-0.6..=0.6))).collect();
+                (centers, (1.2 / (islands.max(1) as f64).sqrt()).min(1.0))
+            }
+            FalloffShape::Archipelago { islands } => {
+                let (direction_y, direction_x) = rng.gen_range(0.0..std::f64::consts::PI).sin_cos();
+                let bend = rng.gen_range(-0.4..=0.4);
+                let centers = (0..islands)
+                    .map(|_| {
+                        let along = rng.gen_range(-0.75..=0.75);
+                        let across = bend * (1.0 - along * along) + rng.gen_range(-0.15..=0.15);
+                        (along * direction_x - across * direction_y, along * direction_y + across * direction_x)
+                    })
+                    .collect();
+                (centers, (0.5 / (islands.max(1) as f64).sqrt()).min(0.5))
+            }
+            _ => (Vec::new(), 0.0),
+        };
+        Falloff {
+            options,
+            map_width,
+            map_height,
+            islands,
+            island_radius,
+        }
+    }
+
+    /// The mask at a tile after the curve, between `0.0` and `1.0`.
+    pub fn mask(&self, x: usize, y: usize) -> f64 {
+        let (width, height) = (self.map_width as f64, self.map_height as f64);
+        let (x, y) = (x as f64, y as f64);
+        let (nx, ny) = (2.0 * (x / width) - 1.0, 2.0 * (y / height) - 1.0);
+        let value = match &self.options.shape {
+            FalloffShape::None => 1.0,
+            FalloffShape::Square => 1.0 - distance_from_center(x, y, width, height),
+            FalloffShape::Radial => 1.0 - (nx * nx + ny * ny).sqrt().min(1.0),
+            FalloffShape::MultiIsland { .. } | FalloffShape::Archipelago { .. } => self
+                .islands
+                .iter()
+                .map(|(center_x, center_y)| {
+                    let distance = ((nx - center_x).powi(2) + (ny - center_y).powi(2)).sqrt();
+                    1.0 - (distance / self.island_radius).min(1.0)
+                })
+                .fold(0.0, f64::max),
+            FalloffShape::EdgeContinent { edge } => match edge {
+                MapEdge::North => 1.0 - y / height,
+                MapEdge::East => x / width,
+                MapEdge::South => y / height,
+                MapEdge::West => 1.0 - x / width,
+            },
+            FalloffShape::Image(image) => {
+                image.sample(x / (width - 1.0).max(1.0), y / (height - 1.0).max(1.0))
+            }
+        };
+        self.options.curve.apply(value.clamp(0.0, 1.0))
+    }
+
+    /// Blends a noise value at a tile towards the mask.
+    pub fn apply(&self, noise: f64, x: usize, y: usize) -> f64 {
+        if self.options.shape == FalloffShape::None {
+            return noise;
+        }
+        noise.lerp(self.mask(x, y), self.options.strength)
+    }
+
+    /// The whole mask, for previewing it.
+    pub fn mask_values(&self) -> DMatrix<f64> {
+        DMatrix::from_fn(self.map_width, self.map_height, |x, y| self.mask(x, y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Falloff, FalloffCurve, FalloffOptions, FalloffShape, GrayscaleMask, MapEdge};
+    use crate::map_generators::noise_map::distance_from_center;
+    use image::GrayImage;
+    use lerp::Lerp;
+
+    #[test]
+    fn test_square_matches_the_old_falloff() {
+        let options = FalloffOptions::new();
+        let falloff = Falloff::new(&options, 40, 30, 1);
+
+        for (x, y) in [(0, 0), (20, 15), (7, 29), (39, 3)] {
+            let old = 0.3.lerp(1.0 - distance_from_center(x as f64, y as f64, 40.0, 30.0), 0.5);
+            assert_eq!(falloff.apply(0.3, x, y), old);
+        }
+    }
+
+    #[test]
+    fn test_masks_stay_in_range() {
+        let shapes = [
+            FalloffShape::None,
+            FalloffShape::Square,
+            FalloffShape::Radial,
+            FalloffShape::MultiIsland { islands: 4 },
+            FalloffShape::Archipelago { islands: 12 },
+            FalloffShape::EdgeContinent { edge: MapEdge::North },
+            FalloffShape::Image(GrayscaleMask::from_image(&GrayImage::from_fn(4, 4, |x, y| [(x * 60 + y) as u8].into()))),
+        ];
+        for shape in shapes {
+            for curve in [FalloffCurve::Linear, FalloffCurve::Smoothstep, FalloffCurve::Power { exponent: 2.0 }] {
+                let mut options = FalloffOptions::new();
+                options.set_shape(shape.clone());
+                options.set_curve(curve);
+                let mask = Falloff::new(&options, 50, 40, 3).mask_values();
+
+                assert_eq!(mask.shape(), (50, 40));
+                assert!(mask.iter().all(|value| (0.0..=1.0).contains(value)), "{shape:?} {curve:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_shapes_put_land_where_expected() {
+        let mask_of = |shape: FalloffShape| {
+            let mut options = FalloffOptions::new();
+            options.set_shape(shape);
+            Falloff::new(&options, 64, 48, 9).mask_values()
+        };
+
+        let radial = mask_of(FalloffShape::Radial);
+        assert!(radial[(32, 24)] > 0.9 && radial[(0, 0)] == 0.0);
+
+        let edge = mask_of(FalloffShape::EdgeContinent { edge: MapEdge::East });
+        assert!(edge[(63, 10)] > edge[(0, 10)]);
+
+        let islands = mask_of(FalloffShape::MultiIsland { islands: 3 });
+        assert!(islands.iter().any(|value| *value > 0.5));
+        assert!(islands.iter().any(|value| *value == 0.0));
+
+        let mut options = FalloffOptions::new();
+        options.set_shape(FalloffShape::None);
+        assert_eq!(Falloff::new(&options, 8, 8, 0).apply(-0.7, 3, 3), -0.7);
+    }
+
+    #[test]
+    fn test_image_mask_is_stretched_over_the_map() {
+        let image = GrayImage::from_fn(2, 1, |x, _| [if x == 0 { 0 } else { 255 }].into());
+        let mask = GrayscaleMask::from_image(&image);
+
+        assert_eq!(mask.sample(0.0, 0.0), 0.0);
+        assert_eq!(mask.sample(1.0, 0.0), 1.0);
+        assert!((mask.sample(0.5, 0.5) - 0.5).abs() < 1e-9);
+    }
+}
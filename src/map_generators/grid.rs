@@ -0,0 +1,355 @@
+use noise::NoiseFn;
+use serde::{Deserialize, Serialize};
+
+/// Axial directions to the six neighbors of a hex, east first and then
+/// counterclockwise.
+const HEX_DIRECTIONS: [(i64, i64); 6] = [(1, 0), (1, -1), (0, -1), (-1, 0), (-1, 1), (0, 1)];
+
+/// Directions to the four neighbors sharing an edge with a square tile.
+const SQUARE_EDGE_DIRECTIONS: [(i64, i64); 4] = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+
+/// Directions to the eight neighbors touching a square tile, corners
+/// included.
+const SQUARE_DIRECTIONS: [(i64, i64); 8] = [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)];
+
+/// Which rows of a hex map are shoved half a tile to the right. Hexes are
+/// pointy topped, so the tiles of a row sit side by side and every other
+/// row nests into the gaps of the rows around it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum HexLayout {
+    #[default]
+    OddRows,
+    EvenRows,
+}
+
+/// The shape of the tiles of a map.
+///
+/// Tiles are always stored as `(x, y)` in a rectangular `DMatrix`. On a hex
+/// grid those are offset coordinates: `y` is the row and `x` the position
+/// within it. Axial coordinates `(q, r)`, where every neighbor is the same
+/// step away no matter the row, are what the hex math is done in.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum TileShape {
+    #[default]
+    Square,
+    Hex(HexLayout),
+}
+
+/// Which edges of a map carry on at the opposite edge.
+///
+/// Falloff masks and stencils still cover the map as a rectangle, so they
+/// need to be turned down for land to reach over the seams.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum WrapMode {
+    #[default]
+    None,
+    /// The left and right edges meet, like the map of a globe, which makes
+    /// the map a cylinder.
+    Horizontal,
+    /// The top and bottom edges meet as well, which makes the map a torus.
+    /// Hex rows only line up across the top and bottom on maps with an even
+    /// height.
+    Torus,
+}
+
+impl WrapMode {
+    pub fn wraps_x(&self) -> bool {
+        matches!(self, WrapMode::Horizontal | WrapMode::Torus)
+    }
+
+    pub fn wraps_y(&self) -> bool {
+        matches!(self, WrapMode::Torus)
+    }
+}
+
+/// Noise that can be sampled on a plane, rolled up into a cylinder or
+/// rolled up both ways into a torus.
+pub trait SeamlessNoise: NoiseFn<f64, 2> + NoiseFn<f64, 3> + NoiseFn<f64, 4> {}
+
+impl<T: NoiseFn<f64, 2> + NoiseFn<f64, 3> + NoiseFn<f64, 4> + ?Sized> SeamlessNoise for T {}
+
+/// How the tiles of a map fit together: their shape, and which edges of the
+/// map wrap around.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct GridTopology {
+    shape: TileShape,
+    wrap: WrapMode,
+}
+
+impl GridTopology {
+    /// Square tiles on a map that doesn't wrap, which is what every map was
+    /// before there was a choice.
+    pub const SQUARE: GridTopology = GridTopology::new(TileShape::Square, WrapMode::None);
+
+    pub const fn new(shape: TileShape, wrap: WrapMode) -> GridTopology {
+        GridTopology { shape, wrap }
+    }
+
+    /// Hexes on a map that doesn't wrap.
+    pub const fn hex(layout: HexLayout) -> GridTopology {
+        GridTopology::new(TileShape::Hex(layout), WrapMode::None)
+    }
+
+    pub const fn with_wrap(self, wrap: WrapMode) -> GridTopology {
+        GridTopology::new(self.shape, wrap)
+    }
+
+    pub fn shape(&self) -> TileShape {
+        self.shape
+    }
+
+    pub fn wrap(&self) -> WrapMode {
+        self.wrap
+    }
+
+    /// Axial coordinates of a tile. On a square grid they're the tile
+    /// coordinates unchanged.
+    pub fn to_axial(&self, x: i64, y: i64) -> (i64, i64) {
+        match self.shape {
+            TileShape::Square => (x, y),
+            TileShape::Hex(HexLayout::OddRows) => (x - (y - (y & 1)) / 2, y),
+            TileShape::Hex(HexLayout::EvenRows) => (x - (y + (y & 1)) / 2, y),
+        }
+    }
+
+    /// Tile coordinates of an axial position, which can be off the map.
+    pub fn from_axial(&self, q: i64, r: i64) -> (i64, i64) {
+        match self.shape {
+            TileShape::Square => (q, r),
+            TileShape::Hex(HexLayout::OddRows) => (q + (r - (r & 1)) / 2, r),
+            TileShape::Hex(HexLayout::EvenRows) => (q + (r + (r & 1)) / 2, r),
+        }
+    }
+
+    /// The tile at `(x, y)` once the edges that wrap have brought it back
+    /// onto the map, `None` when it's off the map for good.
+    pub fn wrap_tile(&self, x: i64, y: i64, width: usize, height: usize) -> Option<(usize, usize)> {
+        let (width, height) = (width as i64, height as i64);
+        let x = if self.wrap.wraps_x() && width > 0 { x.rem_euclid(width) } else { x };
+        let y = if self.wrap.wraps_y() && height > 0 { y.rem_euclid(height) } else { y };
+        ((0..width).contains(&x) && (0..height).contains(&y)).then_some((x as usize, y as usize))
+    }
+
+    /// Neighbors sharing an edge with the tile, four on a square grid and
+    /// six on a hex grid. Neighbors off the map are left out, neighbors
+    /// across an edge that wraps are on the other side of the map.
+    pub fn edge_neighbors(&self, x: usize, y: usize, width: usize, height: usize) -> impl Iterator<Item = (usize, usize)> + Clone {
+        let directions: &'static [(i64, i64)] = match self.shape {
+            TileShape::Square => &SQUARE_EDGE_DIRECTIONS,
+            TileShape::Hex(_) => &HEX_DIRECTIONS,
+        };
+        self.neighbors_along(x, y, width, height, directions)
+    }
+
+    /// Every neighbor touching the tile, eight on a square grid where the
+    /// corners count too and six on a hex grid where they all share an edge.
+    pub fn neighbors(&self, x: usize, y: usize, width: usize, height: usize) -> impl Iterator<Item = (usize, usize)> + Clone {
+        let directions: &'static [(i64, i64)] = match self.shape {
+            TileShape::Square => &SQUARE_DIRECTIONS,
+            TileShape::Hex(_) => &HEX_DIRECTIONS,
+        };
+        self.neighbors_along(x, y, width, height, directions)
+    }
+
+    fn neighbors_along(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        directions: &'static [(i64, i64)],
+    ) -> impl Iterator<Item = (usize, usize)> + Clone {
+        let topology = *self;
+        let (q, r) = topology.to_axial(x as i64, y as i64);
+        directions
+            .iter()
+            .map(move |&(dq, dr)| topology.from_axial(q + dq, r + dr))
+            .filter_map(move |(nx, ny)| topology.wrap_tile(nx, ny, width, height))
+            // A map narrow enough to wrap onto itself would make the tile
+            // its own neighbor.
+            .filter(move |&neighbor| neighbor != (x, y))
+    }
+
+    /// Steps between two tiles going from neighbor to neighbor, counting
+    /// diagonal steps on a square grid. Goes straight across the map, never
+    /// around it.
+    pub fn distance(&self, from: (usize, usize), to: (usize, usize)) -> usize {
+        let (from_q, from_r) = self.to_axial(from.0 as i64, from.1 as i64);
+        let (to_q, to_r) = self.to_axial(to.0 as i64, to.1 as i64);
+        let (dq, dr) = (to_q - from_q, to_r - from_r);
+        match self.shape {
+            TileShape::Square => dq.abs().max(dr.abs()) as usize,
+            TileShape::Hex(_) => (dq.abs() + dr.abs() + (dq + dr).abs()) as usize / 2,
+        }
+    }
+
+    /// Where the center of a tile lies, in tiles. Hex rows nest half a tile
+    /// into each other but stay one tile apart, so a hex map covers the same
+    /// area as a square map of the same size.
+    pub fn tile_center(&self, x: usize, y: usize) -> (f64, f64) {
+        let (q, r) = self.to_axial(x as i64, y as i64);
+        match self.shape {
+            TileShape::Square => (x as f64, y as f64),
+            TileShape::Hex(_) => (q as f64 + r as f64 / 2.0, r as f64),
+        }
+    }
+
+    /// Samples `noise` at the center of a tile so it carries on across the
+    /// edges that wrap. Those axes are rolled up into circles as long as the
+    /// map, so the noise keeps the scale it has on a flat map.
+    pub fn sample_noise<N: SeamlessNoise + ?Sized>(&self, noise: &N, x: usize, y: usize, width: usize, height: usize) -> f64 {
+        let (center_x, center_y) = self.tile_center(x, y);
+        match self.wrap {
+            WrapMode::None => NoiseFn::<f64, 2>::get(noise, [center_x, center_y]),
+            WrapMode::Horizontal => {
+                let [around_x, across_x] = roll(center_x, width as f64);
+                NoiseFn::<f64, 3>::get(noise, [around_x, across_x, center_y])
+            }
+            WrapMode::Torus => {
+                let [around_x, across_x] = roll(center_x, width as f64);
+                let [around_y, across_y] = roll(center_y, height as f64);
+                NoiseFn::<f64, 4>::get(noise, [around_x, across_x, around_y, across_y])
+            }
+        }
+    }
+
+    /// The tile whose area holds a point, the inverse of
+    /// [`GridTopology::tile_center`]. The tile can be off the map.
+    pub fn tile_at(&self, x: f64, y: f64) -> (i64, i64) {
+        match self.shape {
+            TileShape::Square => (x.round() as i64, y.round() as i64),
+            TileShape::Hex(_) => {
+                let (q, r) = round_axial(x - y / 2.0, y);
+                self.from_axial(q, r)
+            }
+        }
+    }
+}
+
+/// Rolls a coordinate up into a circle of circumference `period`, giving the
+/// point on the circle. Steps along the coordinate are steps of the same
+/// length around the circle.
+pub(crate) fn roll(value: f64, period: f64) -> [f64; 2] {
+    let radius = period / std::f64::consts::TAU;
+    let (sin, cos) = (value / radius).sin_cos();
+    [radius * cos, radius * sin]
+}
+
+/// Rounds fractional axial coordinates to the hex they fall in, by rounding
+/// the cube coordinates and fixing up the one that moved the most.
+pub fn round_axial(q: f64, r: f64) -> (i64, i64) {
+    let s = -q - r;
+    let (mut rounded_q, mut rounded_r, rounded_s) = (q.round(), r.round(), s.round());
+    let (q_diff, r_diff, s_diff) = ((rounded_q - q).abs(), (rounded_r - r).abs(), (rounded_s - s).abs());
+    if q_diff > r_diff && q_diff > s_diff {
+        rounded_q = -rounded_r - rounded_s;
+    } else if r_diff > s_diff {
+        rounded_r = -rounded_q - rounded_s;
+    }
+    (rounded_q as i64, rounded_r as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GridTopology, HexLayout, WrapMode};
+    use noise::{NoiseFn, OpenSimplex};
+
+    const TOPOLOGIES: [GridTopology; 3] =
+        [GridTopology::SQUARE, GridTopology::hex(HexLayout::OddRows), GridTopology::hex(HexLayout::EvenRows)];
+
+    #[test]
+    fn test_axial_round_trip() {
+        for topology in TOPOLOGIES {
+            for (x, y) in [(0, 0), (3, 1), (5, 4), (0, 7), (-2, -3)] {
+                let (q, r) = topology.to_axial(x, y);
+                assert_eq!(topology.from_axial(q, r), (x, y), "{topology:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_neighbor_counts() {
+        let count = |topology: GridTopology, x, y| {
+            (topology.edge_neighbors(x, y, 10, 10).count(), topology.neighbors(x, y, 10, 10).count())
+        };
+
+        assert_eq!(count(GridTopology::SQUARE, 4, 4), (4, 8));
+        assert_eq!(count(GridTopology::SQUARE, 0, 0), (2, 3));
+        for layout in [HexLayout::OddRows, HexLayout::EvenRows] {
+            assert_eq!(count(GridTopology::hex(layout), 4, 4), (6, 6));
+            assert_eq!(count(GridTopology::hex(layout), 4, 5), (6, 6));
+        }
+        assert_eq!(count(GridTopology::hex(HexLayout::OddRows), 0, 0), (2, 2));
+        assert_eq!(count(GridTopology::hex(HexLayout::EvenRows), 0, 0), (3, 3));
+    }
+
+    #[test]
+    fn test_hex_neighbors_are_one_step_away() {
+        for topology in TOPOLOGIES {
+            for (x, y) in [(4, 4), (4, 5), (0, 3), (9, 9)] {
+                for neighbor in topology.edge_neighbors(x, y, 10, 10) {
+                    assert_eq!(topology.distance((x, y), neighbor), 1, "{topology:?}");
+                    assert!(topology.neighbors(neighbor.0, neighbor.1, 10, 10).any(|back| back == (x, y)));
+                }
+            }
+        }
+        let hex = GridTopology::hex(HexLayout::OddRows);
+        let mut neighbors: Vec<(usize, usize)> = hex.neighbors(4, 5, 10, 10).collect();
+        neighbors.sort();
+        assert_eq!(neighbors, [(3, 5), (4, 4), (4, 6), (5, 4), (5, 5), (5, 6)]);
+    }
+
+    #[test]
+    fn test_tile_at_finds_the_tile_around_a_point() {
+        for topology in TOPOLOGIES {
+            for (x, y) in [(0, 0), (3, 1), (5, 4), (8, 7)] {
+                let (center_x, center_y) = topology.tile_center(x, y);
+                for (dx, dy) in [(0.0, 0.0), (0.3, 0.0), (-0.3, 0.1), (0.1, -0.3), (-0.2, 0.3)] {
+                    let expected = (x as i64, y as i64);
+                    assert_eq!(topology.tile_at(center_x + dx, center_y + dy), expected, "{topology:?} {dx} {dy}");
+                }
+            }
+        }
+        let hex = GridTopology::hex(HexLayout::OddRows);
+        assert_eq!(hex.tile_center(2, 1), (2.5, 1.0));
+        assert_eq!(hex.tile_at(2.5, 0.6), (2, 1));
+        assert_eq!(hex.tile_at(2.1, 0.4), (2, 0));
+    }
+
+    #[test]
+    fn test_wrapped_neighbors_cross_the_seams() {
+        for topology in TOPOLOGIES {
+            let cylinder = topology.with_wrap(WrapMode::Horizontal);
+            let torus = topology.with_wrap(WrapMode::Torus);
+            assert_eq!(cylinder.edge_neighbors(0, 4, 10, 10).count(), topology.edge_neighbors(4, 4, 10, 10).count());
+            assert!(cylinder.neighbors(0, 4, 10, 10).any(|(x, _)| x == 9), "{topology:?}");
+            assert!(cylinder.neighbors(5, 0, 10, 10).all(|(_, y)| y <= 1), "{topology:?}");
+            assert!(torus.neighbors(5, 0, 10, 10).any(|(_, y)| y == 9), "{topology:?}");
+            for (x, y) in [(0, 0), (9, 9), (0, 9), (4, 5)] {
+                for neighbor in torus.neighbors(x, y, 10, 10) {
+                    assert!(torus.neighbors(neighbor.0, neighbor.1, 10, 10).any(|back| back == (x, y)));
+                }
+            }
+        }
+        let torus = GridTopology::SQUARE.with_wrap(WrapMode::Torus);
+        assert_eq!(torus.wrap_tile(-1, 10, 10, 10), Some((9, 0)));
+        assert_eq!(GridTopology::SQUARE.wrap_tile(-1, 3, 10, 10), None);
+    }
+
+    #[test]
+    fn test_wrapped_noise_repeats() {
+        let noise = OpenSimplex::new(7);
+        for wrap in [WrapMode::Horizontal, WrapMode::Torus] {
+            let topology = GridTopology::hex(HexLayout::OddRows).with_wrap(wrap);
+            for (x, y) in [(0, 0), (3, 1), (7, 4), (11, 5)] {
+                let value = topology.sample_noise(&noise, x, y, 12, 6);
+                assert!((topology.sample_noise(&noise, x + 12, y, 12, 6) - value).abs() < 1e-9, "{wrap:?}");
+                if wrap == WrapMode::Torus {
+                    assert!((topology.sample_noise(&noise, x, y + 6, 12, 6) - value).abs() < 1e-9);
+                }
+            }
+        }
+        let plane = GridTopology::SQUARE;
+        assert_eq!(plane.sample_noise(&noise, 3, 1, 12, 6), noise.get([3.0, 1.0]));
+    }
+}
@@ -0,0 +1,313 @@
+use crate::map_generators::grid::GridTopology;
+use crate::map_generators::map_generator::MapGeneratorError;
+use crate::map_generators::pipeline::MapPass;
+use crate::map_generators::progress::ProgressSink;
+use crate::map_generators::tile::Tile;
+use crate::map_generators::tile_types::TileType;
+use nalgebra::DMatrix;
+use rand_seeder::Seeder;
+use serde::{Deserialize, Serialize};
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+
+/// Controls which tiles turn into rivers and lakes.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct HydrologyOptions {
+    river_threshold: f64,
+    min_lake_size: usize,
+}
+
+impl HydrologyOptions {
+    pub fn new(river_threshold: f64) -> HydrologyOptions {
+        HydrologyOptions {
+            river_threshold,
+            min_lake_size: 4,
+        }
+    }
+
+    /// Number of upstream tiles that have to drain through a tile before it
+    /// becomes a river.
+    pub fn set_river_threshold(&mut self, river_threshold: f64) {
+        self.river_threshold = river_threshold;
+    }
+
+    pub fn river_threshold(&self) -> f64 {
+        self.river_threshold
+    }
+
+    /// Depressions with fewer tiles than this are filled in and stay land.
+    pub fn set_min_lake_size(&mut self, min_lake_size: usize) {
+        self.min_lake_size = min_lake_size;
+    }
+}
+
+impl Default for HydrologyOptions {
+    fn default() -> Self {
+        HydrologyOptions::new(500.0)
+    }
+}
+
+/// A tile waiting in the priority flood, lowest elevation first and the
+/// earliest pushed first among equal elevations.
+#[derive(PartialEq)]
+struct FloodCell {
+    elevation: f64,
+    order: usize,
+    index: (usize, usize),
+}
+
+impl Eq for FloodCell {}
+
+impl Ord for FloodCell {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .elevation
+            .total_cmp(&self.elevation)
+            .then_with(|| other.order.cmp(&self.order))
+    }
+}
+
+impl PartialOrd for FloodCell {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Result of flooding the map from its water tiles.
+struct FlowField {
+    /// Elevation with every depression filled up to its spill point. Along a
+    /// flow path it strictly decreases.
+    filled: DMatrix<f64>,
+    /// The tile each tile drains into. Outlets drain nowhere.
+    downstream: DMatrix<Option<(usize, usize)>>,
+    /// Tiles that sat below their spill point before filling.
+    depressed: DMatrix<bool>,
+    /// Every tile, ordered from the outlets upstream.
+    order: Vec<(usize, usize)>,
+}
+
+/// Priority flood from every water tile. Land that can only be reached by
+/// climbing is raised to the level it spills over at, so every land tile
+/// ends up with a strictly downhill path to water. A map without any water
+/// drains into its lowest tile.
+fn flood(tiles: &DMatrix<Tile>, topology: GridTopology) -> FlowField {
+    let (width, height) = tiles.shape();
+    let mut filled = tiles.map(|tile| tile.elevation());
+    // The fill level without the nudges that keep flats draining, so raised
+    // flats don't count as depressions.
+    let mut level = filled.clone();
+    let mut downstream = DMatrix::from_element(width, height, None);
+    let mut depressed = DMatrix::from_element(width, height, false);
+    let mut visited = DMatrix::from_element(width, height, false);
+    let mut order = Vec::with_capacity(width * height);
+    let mut heap = BinaryHeap::new();
+    let mut pushed = 0;
+
+    for y in 0..height {
+        for x in 0..width {
+            if tiles[(x, y)].terrain().is_water() {
+                visited[(x, y)] = true;
+                heap.push(FloodCell { elevation: filled[(x, y)], order: pushed, index: (x, y) });
+                pushed += 1;
+            }
+        }
+    }
+
+    if heap.is_empty() {
+        let lowest = (0..width * height)
+            .map(|i| (i % width, i / width))
+            .min_by(|&a, &b| filled[a].total_cmp(&filled[b]));
+        if let Some(lowest) = lowest {
+            visited[lowest] = true;
+            depressed[lowest] = true;
+            heap.push(FloodCell { elevation: filled[lowest], order: pushed, index: lowest });
+            pushed += 1;
+        }
+    }
+
+    while let Some(FloodCell { index: (x, y), .. }) = heap.pop() {
+        order.push((x, y));
+        let spill = filled[(x, y)];
+        let spill_level = level[(x, y)];
+        for neighbor in topology.neighbors(x, y, width, height) {
+            if visited[neighbor] {
+                continue;
+            }
+            visited[neighbor] = true;
+            downstream[neighbor] = Some((x, y));
+            depressed[neighbor] = level[neighbor] < spill_level;
+            level[neighbor] = level[neighbor].max(spill_level);
+            filled[neighbor] = filled[neighbor].max(spill.next_up());
+            heap.push(FloodCell { elevation: filled[neighbor], order: pushed, index: neighbor });
+            pushed += 1;
+        }
+    }
+
+    FlowField { filled, downstream, depressed, order }
+}
+
+/// Marks connected depressions of at least `min_size` tiles as lakes.
+fn find_lakes(depressed: &DMatrix<bool>, min_size: usize, topology: GridTopology) -> DMatrix<bool> {
+    let (width, height) = depressed.shape();
+    let mut lakes = DMatrix::from_element(width, height, false);
+    let mut seen = DMatrix::from_element(width, height, false);
+
+    for y in 0..height {
+        for x in 0..width {
+            if !depressed[(x, y)] || seen[(x, y)] {
+                continue;
+            }
+            seen[(x, y)] = true;
+            let mut region = vec![(x, y)];
+            let mut queue = VecDeque::from([(x, y)]);
+            while let Some((cx, cy)) = queue.pop_front() {
+                for neighbor in topology.neighbors(cx, cy, width, height) {
+                    if depressed[neighbor] && !seen[neighbor] {
+                        seen[neighbor] = true;
+                        region.push(neighbor);
+                        queue.push_back(neighbor);
+                    }
+                }
+            }
+            if region.len() >= min_size {
+                for index in region {
+                    lakes[index] = true;
+                }
+            }
+        }
+    }
+
+    lakes
+}
+
+/// Fills depressions, routes every land tile towards water and marks the
+/// tiles that collect enough flow as rivers. Filled depressions large enough
+/// to hold a lake become lakes, the rest stay land.
+///
+/// Land tiles keep the filled elevation, so rivers never run uphill. Water
+/// tiles are left untouched and nothing here is random.
+pub fn generate_hydrology(tiles: DMatrix<Tile>, options: &HydrologyOptions, topology: GridTopology) -> DMatrix<Tile> {
+    let flow = flood(&tiles, topology);
+    let lakes = find_lakes(&flow.depressed, options.min_lake_size.max(1), topology);
+
+    let mut accumulation = tiles.map(|tile| if tile.terrain().is_water() { 0.0 } else { 1.0 });
+    for &index in flow.order.iter().rev() {
+        if let Some(next) = flow.downstream[index] {
+            accumulation[next] += accumulation[index];
+        }
+    }
+
+    DMatrix::from_fn(tiles.nrows(), tiles.ncols(), |x, y| {
+        let tile = tiles[(x, y)];
+        if tile.terrain().is_water() {
+            return tile;
+        }
+        // Land without anywhere to drain only happens on maps without water.
+        let terrain = if lakes[(x, y)] || flow.downstream[(x, y)].is_none() {
+            TileType::Lake
+        } else if accumulation[(x, y)] >= options.river_threshold {
+            TileType::River
+        } else {
+            tile.terrain()
+        };
+        Tile::new(terrain, flow.filled[(x, y)]).with_climate(tile.temperature(), tile.moisture())
+    })
+}
+
+/// Hydrology as a pipeline pass. It doesn't draw from the seeder, so the
+/// rivers only depend on the elevation handed to it.
+pub struct HydrologyPass {
+    options: HydrologyOptions,
+}
+
+impl HydrologyPass {
+    pub fn new(options: HydrologyOptions) -> HydrologyPass {
+        HydrologyPass { options }
+    }
+}
+
+impl MapPass for HydrologyPass {
+    fn name(&self) -> &str {
+        "hydrology"
+    }
+
+    fn apply(
+        &self,
+        tiles: DMatrix<Tile>,
+        topology: GridTopology,
+        _seeder: &mut Seeder,
+        progress: &dyn ProgressSink,
+    ) -> Result<DMatrix<Tile>, MapGeneratorError> {
+        progress.report("hydrology", 0, 1);
+        let tiles = generate_hydrology(tiles, &self.options, topology);
+        progress.report("hydrology", 1, 1);
+        Ok(tiles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{flood, generate_hydrology, HydrologyOptions};
+    use crate::map_generators::{GridTopology, HexLayout, MapGenerator, NoiseMapGenerator, NoiseMapOptions, Tile, TileType};
+    use nalgebra::DMatrix;
+
+    #[test]
+    fn test_rivers_run_downhill_into_water() {
+        let tiles = NoiseMapGenerator::new(NoiseMapOptions::new(120, 80, 11))
+            .generate()
+            .expect("Failed to generate a map");
+        let options = HydrologyOptions::new(30.0);
+
+        for topology in [GridTopology::SQUARE, GridTopology::hex(HexLayout::OddRows)] {
+            let flow = flood(&tiles, topology);
+            let hydrology = generate_hydrology(tiles.clone(), &options, topology);
+
+            assert_eq!(hydrology, generate_hydrology(tiles.clone(), &options, topology));
+            assert!(hydrology.iter().any(|tile| tile.terrain() == TileType::River));
+
+            for y in 0..hydrology.ncols() {
+                for x in 0..hydrology.nrows() {
+                    if hydrology[(x, y)].terrain() != TileType::River {
+                        continue;
+                    }
+                    let mut current = (x, y);
+                    while let Some(next) = flow.downstream[current] {
+                        assert!(topology.neighbors(current.0, current.1, 120, 80).any(|neighbor| neighbor == next));
+                        assert!(hydrology[next].elevation() < hydrology[current].elevation());
+                        assert!(matches!(
+                            hydrology[next].terrain(),
+                            TileType::River | TileType::Lake | TileType::Water
+                        ));
+                        current = next;
+                    }
+                    assert!(hydrology[current].terrain().is_water());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_depressions_become_lakes() {
+        // A bowl of land with the sea along the left edge.
+        let tiles = DMatrix::from_fn(9, 9, |x, y| {
+            if x == 0 {
+                return Tile::new(TileType::Water, 0.0);
+            }
+            let bowl = (x as f64 - 5.0).abs().max((y as f64 - 4.0).abs());
+            Tile::new(TileType::Grassland, if bowl <= 1.0 { 0.5 } else { 2.0 })
+        });
+        let mut options = HydrologyOptions::new(1000.0);
+        options.set_min_lake_size(9);
+        let hydrology = generate_hydrology(tiles.clone(), &options, GridTopology::SQUARE);
+
+        assert_eq!(hydrology[(5, 4)].terrain(), TileType::Lake);
+        assert!(hydrology[(5, 4)].elevation() >= 2.0);
+        assert_eq!(hydrology[(0, 4)], tiles[(0, 4)]);
+
+        options.set_min_lake_size(10);
+        assert!(generate_hydrology(tiles, &options, GridTopology::SQUARE)
+            .iter()
+            .all(|tile| tile.terrain() != TileType::Lake));
+    }
+}
@@ -0,0 +1,291 @@
+use crate::map_generators::grid::GridTopology;
+use crate::map_generators::noise_map::{NoiseMapGenerator, NoiseMapOptions};
+use crate::map_generators::planet::{PlanetGenerator, PlanetOptions, PlanetProjection};
+use crate::map_generators::progress::{NoProgress, ProgressSink};
+use crate::map_generators::tile::Tile;
+use crate::map_generators::voronoi_continents::{ContinentOptions, VoronoiContinentError, VoronoiContinentsGenerator};
+use crate::map_generators::voronoi_heightmap::{HeightMapOptions, VoronoiError, VoronoiHeightmapGenerator};
+use nalgebra::DMatrix;
+use serde::{Deserialize, Serialize};
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum MapGeneratorError {
+    #[error(transparent)]
+    VoronoiError(#[from] VoronoiError),
+    #[error(transparent)]
+    VoronoiContinentError(#[from] VoronoiContinentError),
+    #[error("Map generation was cancelled")]
+    Cancelled,
+}
+
+impl MapGeneratorError {
+    /// Whether generation stopped because a [`ProgressSink`] asked it to.
+    pub fn is_cancelled(&self) -> bool {
+        matches!(
+            self,
+            Self::Cancelled
+                | Self::VoronoiError(VoronoiError::Cancelled)
+                | Self::VoronoiContinentError(VoronoiContinentError::Cancelled)
+        )
+    }
+}
+
+/// Shared entry point for every map generator.
+///
+/// The generated matrix is indexed as `(x, y)`, so `nrows()` is the map width
+/// and `ncols()` is the map height.
+pub trait MapGenerator {
+    type Options;
+
+    fn new(options: Self::Options) -> Self
+    where
+        Self: Sized;
+
+    fn name(&self) -> &'static str;
+
+    fn options(&self) -> &Self::Options;
+
+    fn generate(&self) -> Result<DMatrix<Tile>, MapGeneratorError> {
+        self.generate_with_progress(&NoProgress)
+    }
+
+    /// Generates while reporting each phase to `progress`, stopping with a
+    /// cancelled error when it asks to.
+    fn generate_with_progress(&self, progress: &dyn ProgressSink) -> Result<DMatrix<Tile>, MapGeneratorError> {
+        self.generate_on_grid(GridTopology::SQUARE, progress)
+    }
+
+    /// Like [`MapGenerator::generate_with_progress`] but lays the tiles out
+    /// on `topology`, sampling every tile at its center.
+    fn generate_on_grid(&self, topology: GridTopology, progress: &dyn ProgressSink) -> Result<DMatrix<Tile>, MapGeneratorError>;
+}
+
+/// The built in generators together with the options to run them.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum MapGeneratorStrategies {
+    NoiseMap(NoiseMapOptions),
+    VoronoiHeightmap(HeightMapOptions),
+    VoronoiContinents(ContinentOptions),
+    Planet(PlanetOptions),
+}
+
+impl Default for MapGeneratorStrategies {
+    fn default() -> Self {
+        MapGeneratorStrategies::NoiseMap(NoiseMapOptions::default())
+    }
+}
+
+impl MapGeneratorStrategies {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NoiseMap(_) => "noise_map",
+            Self::VoronoiHeightmap(_) => "voronoi_heightmap",
+            Self::VoronoiContinents(_) => "voronoi_continents",
+            Self::Planet(_) => "planet",
+        }
+    }
+
+    /// The projection of a planet, which gives the tiles their latitude.
+    pub fn projection(&self) -> Option<PlanetProjection> {
+        match self {
+            Self::Planet(options) => Some(options.projection()),
+            _ => None,
+        }
+    }
+
+    /// The grid the map is generated on when `topology` is asked for. A
+    /// planet decides for itself which edges wrap.
+    pub fn fit_topology(&self, topology: GridTopology) -> GridTopology {
+        match self.projection() {
+            Some(projection) => topology.with_wrap(projection.wrap()),
+            None => topology,
+        }
+    }
+
+    pub fn generate(self) -> Result<DMatrix<Tile>, MapGeneratorError> {
+        self.generate_with_progress(&NoProgress)
+    }
+
+    pub fn generate_with_progress(self, progress: &dyn ProgressSink) -> Result<DMatrix<Tile>, MapGeneratorError> {
+        self.generate_on_grid(GridTopology::SQUARE, progress)
+    }
+
+    pub fn generate_on_grid(
+        self,
+        topology: GridTopology,
+        progress: &dyn ProgressSink,
+    ) -> Result<DMatrix<Tile>, MapGeneratorError> {
+        match self {
+            Self::NoiseMap(options) => NoiseMapGenerator::new(options).generate_on_grid(topology, progress),
+            Self::VoronoiHeightmap(options) => VoronoiHeightmapGenerator::new(options).generate_on_grid(topology, progress),
+            Self::VoronoiContinents(options) => VoronoiContinentsGenerator::new(options).generate_on_grid(topology, progress),
+            Self::Planet(options) => PlanetGenerator::new(options).generate_on_grid(topology, progress),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MapGenerator, MapGeneratorStrategies};
+    use crate::map_generators::{
+        ContinentOptions, ErosionOptions, HeightMapOptions, HydrologyOptions, NoiseMapGenerator,
+        NoiseMapOptions, PlanetContinentOptions, PlanetGenerator, PlanetOptions, PlanetProjection, PostProcessOptions,
+        ProgressSink, VoronoiContinentsGenerator, VoronoiHeightmapGenerator,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    /// Remembers every phase in the order they first showed up.
+    #[derive(Default)]
+    struct RecordingProgress {
+        phases: Mutex<Vec<String>>,
+    }
+
+    impl ProgressSink for RecordingProgress {
+        fn report(&self, phase: &str, completed: usize, total: usize) {
+            assert!(completed <= total, "{phase}: {completed} of {total}");
+            let mut phases = self.phases.lock().unwrap();
+            if !phases.iter().any(|seen| seen == phase) {
+                phases.push(phase.to_string());
+            }
+        }
+    }
+
+    /// Asks for every map to be filled on the calling thread.
+    struct SingleThreaded;
+
+    impl ProgressSink for SingleThreaded {
+        fn report(&self, _phase: &str, _completed: usize, _total: usize) {}
+
+        fn single_threaded(&self) -> bool {
+            true
+        }
+    }
+
+    /// Cancels after a number of progress checks.
+    struct CancelAfter(AtomicUsize);
+
+    impl ProgressSink for CancelAfter {
+        fn report(&self, _phase: &str, _completed: usize, _total: usize) {}
+
+        fn is_cancelled(&self) -> bool {
+            self.0
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |checks| checks.checked_sub(1))
+                .is_err()
+        }
+    }
+
+    fn planet_with_continents(width: usize, height: usize) -> PlanetOptions {
+        let mut options = PlanetOptions::new(width, height, 3);
+        options.set_projection(PlanetProjection::CubeMap);
+        options.set_continents(Some(PlanetContinentOptions::new(200)));
+        options
+    }
+
+    fn generated_shape<G: MapGenerator>(options: G::Options) -> (usize, usize) {
+        G::new(options)
+            .generate()
+            .expect("Failed to generate a map")
+            .shape()
+    }
+
+    #[test]
+    fn test_generators_share_orientation() {
+        let seed = "shared orientation".to_string();
+
+        assert_eq!(generated_shape::<NoiseMapGenerator>(NoiseMapOptions::new(40, 30, 7)), (40, 30));
+        assert_eq!(
+            generated_shape::<VoronoiContinentsGenerator>(ContinentOptions::new(40, 30, seed.clone(), 30)),
+            (40, 30)
+        );
+        assert_eq!(
+            generated_shape::<VoronoiHeightmapGenerator>(HeightMapOptions::new(40, 30, seed, 30)),
+            (40, 30)
+        );
+        assert_eq!(generated_shape::<PlanetGenerator>(PlanetOptions::new(40, 30, 7)), (40, 30));
+    }
+
+    #[test]
+    fn test_parallel_generation_matches_single_threaded() {
+        let seed = "parallel".to_string();
+        let strategies = [
+            MapGeneratorStrategies::NoiseMap(NoiseMapOptions::new(90, 70, 3)),
+            MapGeneratorStrategies::VoronoiHeightmap(HeightMapOptions::new(90, 70, seed.clone(), 40)),
+            MapGeneratorStrategies::VoronoiContinents(ContinentOptions::new(90, 70, seed, 40)),
+            MapGeneratorStrategies::Planet(planet_with_continents(90, 70)),
+        ];
+
+        for strategy in strategies {
+            let strategy_name = strategy.name();
+            let single_threaded = strategy.clone().generate_with_progress(&SingleThreaded).expect("Failed to generate a map");
+            let parallel = strategy.generate().expect("Failed to generate a map");
+            assert_eq!(parallel, single_threaded, "{}", strategy_name);
+        }
+    }
+
+    #[test]
+    fn test_generators_report_their_phases() {
+        let seed = "progress".to_string();
+        let cases = [
+            (MapGeneratorStrategies::NoiseMap(NoiseMapOptions::new(40, 30, 3)), vec!["tiles"]),
+            (
+                MapGeneratorStrategies::VoronoiHeightmap(HeightMapOptions::new(40, 30, seed.clone(), 30)),
+                vec!["lloyd_relaxation", "cell_heights", "tiles"],
+            ),
+            (
+                MapGeneratorStrategies::VoronoiContinents(ContinentOptions::new(40, 30, seed, 30)),
+                vec!["lloyd_relaxation", "continent_growth", "tiles"],
+            ),
+            (MapGeneratorStrategies::Planet(PlanetOptions::new(40, 30, 3)), vec!["tiles"]),
+            (MapGeneratorStrategies::Planet(planet_with_continents(40, 30)), vec!["continent_growth", "tiles"]),
+        ];
+
+        for (strategy, expected) in cases {
+            let progress = RecordingProgress::default();
+            strategy.generate_with_progress(&progress).expect("Failed to generate a map");
+            assert_eq!(*progress.phases.lock().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_post_processing_reports_its_phases() {
+        let tiles = MapGeneratorStrategies::NoiseMap(NoiseMapOptions::new(40, 30, 3))
+            .generate()
+            .expect("Failed to generate a map");
+        let mut erosion = ErosionOptions::new(5);
+        erosion.set_droplet_count(2000);
+        let mut options = PostProcessOptions::new();
+        options.set_erosion(Some(erosion));
+        options.set_hydrology(Some(HydrologyOptions::default()));
+
+        let progress = RecordingProgress::default();
+        options
+            .pipeline("progress".to_string(), 40, 30)
+            .apply_with_progress(tiles, &progress)
+            .expect("Post processing was cancelled");
+        assert_eq!(*progress.phases.lock().unwrap(), ["pipeline", "hydraulic_erosion", "thermal_erosion", "hydrology"]);
+    }
+
+    #[test]
+    fn test_generators_stop_when_cancelled() {
+        let seed = "cancel".to_string();
+        let strategies = [
+            MapGeneratorStrategies::NoiseMap(NoiseMapOptions::new(40, 30, 3)),
+            MapGeneratorStrategies::VoronoiHeightmap(HeightMapOptions::new(40, 30, seed.clone(), 30)),
+            MapGeneratorStrategies::VoronoiContinents(ContinentOptions::new(40, 30, seed, 30)),
+            MapGeneratorStrategies::Planet(planet_with_continents(40, 30)),
+        ];
+
+        for strategy in strategies {
+            for checks in [0, 5] {
+                let error = strategy
+                    .clone()
+                    .generate_with_progress(&CancelAfter(AtomicUsize::new(checks)))
+                    .expect_err("Generation wasn't cancelled");
+                assert!(error.is_cancelled(), "{error}");
+            }
+        }
+    }
+}
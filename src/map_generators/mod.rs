@@ -1,12 +1,42 @@
 //pub mod tile_types::*;
 //pub mod wave_function_generator::*;
+mod biomes;
+mod continent_growth;
+mod domain_warp;
+mod erosion;
+mod falloff;
+mod grid;
+mod hydrology;
+mod map_generator;
 mod noise_map;
+mod parallel;
+mod pipeline;
+mod planet;
+mod post_processing;
+mod progress;
+mod stencil;
 mod tile;
 mod tile_types;
+mod tiled_diagram;
 mod voronoi_continents;
 mod voronoi_heightmap;
+pub use biomes::*;
+pub use continent_growth::*;
+pub use domain_warp::*;
+pub use erosion::*;
+pub use falloff::*;
+pub use grid::*;
+pub use hydrology::*;
+pub use map_generator::*;
 pub use noise_map::*;
+pub use parallel::*;
+pub use pipeline::*;
+pub use planet::*;
+pub use post_processing::*;
+pub use progress::*;
+pub use stencil::*;
 pub use tile::*;
 pub use tile_types::*;
+pub use tiled_diagram::*;
 pub use voronoi_continents::*;
 pub use voronoi_heightmap::*;
@@ -1,18 +1,51 @@
 use crate::map_generators::tile_types::TileType;
+use crate::map_generators::parallel::par_from_fn_with_progress;
+use crate::map_generators::progress::{NoProgress, ProgressSink};
 use crate::map_generators::tile::Tile;
+use crate::map_generators::falloff::{Falloff, FalloffOptions};
+use crate::map_generators::grid::{GridTopology, SeamlessNoise};
+use crate::map_generators::stencil::StencilOptions;
 //use fastnoise_lite::*;
+use bevy::log::{debug, info_span};
 use nalgebra::DMatrix;
 //use simdnoise::*;
-use noise::{Fbm, MultiFractal, NoiseFn, OpenSimplex, RidgedMulti, Seedable};
-use lerp::Lerp;
+use noise::{Billow, Fbm, HybridMulti, MultiFractal, OpenSimplex, RidgedMulti};
 use rand_seeder::Seeder;
+use serde::{Deserialize, Serialize};
 use crate::math_helpers::create_new_seed32;
+use crate::map_generators::map_generator::{MapGenerator, MapGeneratorError};
+use crate::map_generators::pipeline::MapPass;
 
 
+/// The fractal the noise map is built from, all of them layering octaves of
+/// OpenSimplex noise.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum NoiseFunction {
+    /// Plain fractal brownian motion, rolling hills everywhere.
+    #[default]
+    Fbm,
+    /// Sharp ridges along the zero crossings, good for mountain chains.
+    Ridged,
+    /// Rounded bumps, like fbm folded at zero.
+    Billow,
+    /// Smooth lowlands that get rougher the higher they are.
+    Hybrid,
+}
+
+/// Fractal noise that is land wherever it rises above the sea level.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct NoiseMapOptions {
     map_width: usize,
     map_height: usize,
     seed: u32,
+    noise_function: NoiseFunction,
+    octaves: usize,
+    frequency: f64,
+    lacunarity: f64,
+    persistence: f64,
+    falloff: FalloffOptions,
+    sea_level: f64,
+    stencil: Option<StencilOptions>,
 }
 
 impl NoiseMapOptions {
@@ -21,19 +54,128 @@ impl NoiseMapOptions {
             map_width,
             map_height,
             seed,
+            noise_function: NoiseFunction::Fbm,
+            octaves: Fbm::<OpenSimplex>::MAX_OCTAVES,
+            frequency: 0.002,
+            lacunarity: Fbm::<OpenSimplex>::DEFAULT_LACUNARITY,
+            persistence: Fbm::<OpenSimplex>::DEFAULT_PERSISTENCE,
+            falloff: FalloffOptions::new(),
+            sea_level: 0.0,
+            stencil: None,
         }
     }
+
+    pub fn set_noise_function(&mut self, noise_function: NoiseFunction) {
+        self.noise_function = noise_function;
+    }
+
+    /// Clamped to between 1 and 32 when the noise is built.
+    pub fn set_octaves(&mut self, octaves: usize) {
+        self.octaves = octaves;
+    }
+
+    /// Frequency of the first octave in cycles per tile.
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.frequency = frequency;
+    }
+
+    /// How much the frequency grows from one octave to the next.
+    pub fn set_lacunarity(&mut self, lacunarity: f64) {
+        self.lacunarity = lacunarity;
+    }
+
+    /// How much the amplitude shrinks from one octave to the next.
+    pub fn set_persistence(&mut self, persistence: f64) {
+        self.persistence = persistence;
+    }
+
+    /// The mask that pulls the noise towards land in some places and sea
+    /// in others.
+    pub fn set_falloff(&mut self, falloff: FalloffOptions) {
+        self.falloff = falloff;
+    }
+
+    pub fn set_sea_level(&mut self, sea_level: f64) {
+        self.sea_level = sea_level;
+    }
+
+    /// A sketch of where land should be. Elevation is raised where the
+    /// sketch is bright and lowered where it is dark.
+    pub fn set_stencil(&mut self, stencil: Option<StencilOptions>) {
+        self.stencil = stencil;
+    }
+
+    pub fn noise_function(&self) -> NoiseFunction {
+        self.noise_function
+    }
+
+    pub fn octaves(&self) -> usize {
+        self.octaves
+    }
+
+    pub fn frequency(&self) -> f64 {
+        self.frequency
+    }
+
+    pub fn lacunarity(&self) -> f64 {
+        self.lacunarity
+    }
+
+    pub fn persistence(&self) -> f64 {
+        self.persistence
+    }
+
+    pub fn falloff(&self) -> &FalloffOptions {
+        &self.falloff
+    }
+
+    pub fn map_width(&self) -> usize {
+        self.map_width
+    }
+
+    pub fn map_height(&self) -> usize {
+        self.map_height
+    }
+
+    pub fn seed(&self) -> u32 {
+        self.seed
+    }
+
+    pub fn sea_level(&self) -> f64 {
+        self.sea_level
+    }
+
+    pub fn stencil(&self) -> Option<&StencilOptions> {
+        self.stencil.as_ref()
+    }
 }
 
 impl Default for NoiseMapOptions {
     fn default() -> Self {
         let mut seeder = Seeder::from("Initial Seed");
         let seed = create_new_seed32(&mut seeder);
-        NoiseMapOptions {
-            map_width: 1920,
-            map_height: 1080,
-            seed: seed,
-        }
+        NoiseMapOptions::new(1920, 1080, seed)
+    }
+}
+
+pub(crate) type BaseNoise = Box<dyn SeamlessNoise + Send + Sync>;
+
+pub(crate) fn base_noise(options: &NoiseMapOptions) -> BaseNoise {
+    fn configure<T: MultiFractal + SeamlessNoise + Send + Sync + 'static>(noise: T, options: &NoiseMapOptions) -> BaseNoise {
+        Box::new(
+            noise
+                .set_octaves(options.octaves)
+                .set_frequency(options.frequency)
+                .set_lacunarity(options.lacunarity)
+                .set_persistence(options.persistence),
+        )
+    }
+
+    match options.noise_function {
+        NoiseFunction::Fbm => configure(Fbm::<OpenSimplex>::new(options.seed), options),
+        NoiseFunction::Ridged => configure(RidgedMulti::<OpenSimplex>::new(options.seed), options),
+        NoiseFunction::Billow => configure(Billow::<OpenSimplex>::new(options.seed), options),
+        NoiseFunction::Hybrid => configure(HybridMulti::<OpenSimplex>::new(options.seed), options),
     }
 }
 
@@ -43,9 +185,61 @@ pub fn distance_from_center(x: f64, y: f64, width: f64, height: f64) -> f64 {
     1.0 - (1.0 - nx.powi(2)) * (1.0 - ny.powi(2))
 }
 
-pub fn noise_map(options: NoiseMapOptions) -> DMatrix<Tile> {
-    println!("map_size_x: {}", options.map_width);
-    println!("map_size_y: {}", options.map_height);
+pub struct NoiseMapGenerator {
+    options: NoiseMapOptions,
+}
+
+impl MapGenerator for NoiseMapGenerator {
+    type Options = NoiseMapOptions;
+
+    fn new(options: NoiseMapOptions) -> NoiseMapGenerator {
+        NoiseMapGenerator { options }
+    }
+
+    fn name(&self) -> &'static str {
+        "noise_map"
+    }
+
+    fn options(&self) -> &NoiseMapOptions {
+        &self.options
+    }
+
+    fn generate_on_grid(&self, topology: GridTopology, progress: &dyn ProgressSink) -> Result<DMatrix<Tile>, MapGeneratorError> {
+        noise_map_with_progress(&self.options, topology, progress)
+    }
+}
+
+impl MapPass for NoiseMapGenerator {
+    fn name(&self) -> &str {
+        MapGenerator::name(self)
+    }
+
+    fn apply(
+        &self,
+        tiles: DMatrix<Tile>,
+        topology: GridTopology,
+        seeder: &mut Seeder,
+        progress: &dyn ProgressSink,
+    ) -> Result<DMatrix<Tile>, MapGeneratorError> {
+        let mut options = self.options.clone();
+        options.map_width = tiles.nrows();
+        options.map_height = tiles.ncols();
+        options.seed = create_new_seed32(seeder);
+        noise_map_with_progress(&options, topology, progress)
+    }
+}
+
+pub fn noise_map(options: &NoiseMapOptions) -> DMatrix<Tile> {
+    noise_map_with_progress(options, GridTopology::SQUARE, &NoProgress).expect("Nothing cancels without a sink")
+}
+
+pub fn noise_map_with_progress(
+    options: &NoiseMapOptions,
+    topology: GridTopology,
+    progress: &dyn ProgressSink,
+) -> Result<DMatrix<Tile>, MapGeneratorError> {
+    let _span = info_span!("noise_map", seed = options.seed).entered();
+    debug!(width = options.map_width, height = options.map_height, "Generating noise map");
 
     //let noise = NoiseBuilder::cellular_2d(map_size_x  as usize, map_size_y as usize)
     /* 
@@ -59,38 +253,70 @@ pub fn noise_map(options: NoiseMapOptions) -> DMatrix<Tile> {
     .generate_scaled(-1.0, 1.0);
     */
     //let noise = OpenSimplex::new(seed as u32);
-    let noise: Fbm<OpenSimplex> = Fbm::new(options.seed);
-    let noise = noise.set_octaves(80);
-    let noise = noise.set_frequency(0.002);
-
-    DMatrix::from_fn(options.map_height, options.map_width, |row, column| {
-        //println!("row: {row}");
-        //println!("column: {column}");
-        //println!("row_x: {column_x}");
-        //println!("column_y: {row_y}");
-        let distance = distance_from_center(column as f64, row as f64, options.map_width as f64, options.map_height as f64);
-        //println!("distance: {distance}");
-        //let noise_val = noise.get([row as f64, column as f64]);
-        let noise_val = noise.get([column as f64, row as f64]);
+    let noise = base_noise(options);
+    let falloff = Falloff::new(&options.falloff, options.map_width, options.map_height, options.seed);
+
+    par_from_fn_with_progress(options.map_width, options.map_height, "tiles", progress, |x, y| {
+        let noise_val = topology.sample_noise(noise.as_ref(), x, y, options.map_width, options.map_height);
         //println!("noise_val: {noise_val}");
-        let new_noise_val = noise_val.lerp(1.0 - distance, 0.50);
+        let mut new_noise_val = falloff.apply(noise_val, x, y);
+        if let Some(stencil) = &options.stencil {
+            let u = x as f64 / (options.map_width as f64 - 1.0).max(1.0);
+            let v = y as f64 / (options.map_height as f64 - 1.0).max(1.0);
+            new_noise_val += stencil.elevation_bias(u, v);
+        }
         //println!("lerped_noise_val: {new_noise_val}");
 
 
         let mut tile_type = TileType::Grassland;
-        if new_noise_val < 0.0 {
+        if new_noise_val < options.sea_level {
             tile_type = TileType::Water;
         }
 
         Tile::new(tile_type, new_noise_val)
     })
+    .ok_or(MapGeneratorError::Cancelled)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::distance_from_center;
+    use super::{distance_from_center, noise_map, NoiseFunction, NoiseMapOptions};
+    use crate::map_generators::{GrayscaleMask, StencilOptions, TileType};
+    use image::GrayImage;
     use lerp::Lerp;
 
+    #[test]
+    fn test_noise_functions_respect_sea_level() {
+        for noise_function in [NoiseFunction::Fbm, NoiseFunction::Ridged, NoiseFunction::Billow, NoiseFunction::Hybrid] {
+            let mut options = NoiseMapOptions::new(48, 32, 5);
+            options.set_noise_function(noise_function);
+            options.set_octaves(6);
+            options.set_frequency(0.05);
+            options.set_sea_level(0.1);
+            let tiles = noise_map(&options);
+
+            assert!(tiles.iter().all(|tile| tile.elevation().is_finite()));
+            for tile in tiles.iter() {
+                assert_eq!(tile.terrain() == TileType::Water, tile.elevation() < 0.1);
+            }
+            assert_ne!(tiles, noise_map(&NoiseMapOptions::new(48, 32, 5)), "{noise_function:?}");
+        }
+    }
+
+    #[test]
+    fn test_stencil_biases_elevation() {
+        let image = GrayImage::from_fn(2, 2, |x, _| [if x == 0 { 255 } else { 0 }].into());
+        let plain = NoiseMapOptions::new(40, 30, 2);
+        let mut options = plain.clone();
+        options.set_stencil(Some(StencilOptions::new(GrayscaleMask::from_image(&image))));
+        let (plain, stenciled) = (noise_map(&plain), noise_map(&options));
+
+        assert!(stenciled[(0, 10)].elevation() > plain[(0, 10)].elevation());
+        assert!(stenciled[(39, 10)].elevation() < plain[(39, 10)].elevation());
+        assert!(stenciled.column(10).iter().take(5).all(|tile| tile.terrain() == TileType::Grassland));
+        assert!(stenciled.column(10).iter().skip(35).all(|tile| tile.terrain() == TileType::Water));
+    }
+
     #[test]
     fn test_lerp() {
         let lerped = 0.0.lerp(1.0 - 1.0, 0.50);
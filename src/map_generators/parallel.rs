@@ -0,0 +1,85 @@
+use crate::map_generators::progress::{NoProgress, ProgressSink};
+use bevy::tasks::{ComputeTaskPool, TaskPool};
+use nalgebra::{DMatrix, Scalar};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Like `DMatrix::from_fn` but evaluates bands of map rows on the compute
+/// task pool. `f` gets `(x, y)` and has to be a pure function of them, then
+/// the result is the same as the single threaded one bit for bit.
+pub fn par_from_fn<T, F>(width: usize, height: usize, f: F) -> DMatrix<T>
+where
+    T: Scalar + Send,
+    F: Fn(usize, usize) -> T + Sync,
+{
+    par_from_fn_with_progress(width, height, "tiles", &NoProgress, f).expect("Nothing cancels without a sink")
+}
+
+/// [`par_from_fn`] that reports every finished map row under `phase` and
+/// gives back `None` if it's cancelled. Fills the rows on the calling thread
+/// when `progress` asks for it, and always for wasm.
+pub fn par_from_fn_with_progress<T, F>(
+    width: usize,
+    height: usize,
+    phase: &str,
+    progress: &dyn ProgressSink,
+    f: F,
+) -> Option<DMatrix<T>>
+where
+    T: Scalar + Send,
+    F: Fn(usize, usize) -> T + Sync,
+{
+    let rows_done = AtomicUsize::new(0);
+    let row = |y: usize| {
+        if progress.is_cancelled() {
+            return None;
+        }
+        let row: Vec<T> = (0..width).map(|x| f(x, y)).collect();
+        progress.report(phase, rows_done.fetch_add(1, Ordering::Relaxed) + 1, height);
+        Some(row)
+    };
+    progress.report(phase, 0, height);
+
+    if progress.single_threaded() || cfg!(target_arch = "wasm32") || width * height == 0 {
+        let rows: Option<Vec<Vec<T>>> = (0..height).map(row).collect();
+        return Some(DMatrix::from_vec(width, height, rows?.into_iter().flatten().collect()));
+    }
+
+    let pool = ComputeTaskPool::get_or_init(TaskPool::default);
+    let band_height = height.div_ceil(pool.thread_num().max(1) * 4).max(1);
+    let row = &row;
+
+    // Matrices are column major and a column is one map row, so every band
+    // is a contiguous run of the final storage.
+    let bands = pool.scope(|scope| {
+        for band_start in (0..height).step_by(band_height) {
+            scope.spawn(async move {
+                let band_end = (band_start + band_height).min(height);
+                let mut band = Vec::with_capacity(width * (band_end - band_start));
+                for y in band_start..band_end {
+                    band.extend(row(y)?);
+                }
+                Some(band)
+            });
+        }
+    });
+
+    let bands: Option<Vec<Vec<T>>> = bands.into_iter().collect();
+    Some(DMatrix::from_vec(width, height, bands?.into_iter().flatten().collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::par_from_fn;
+    use nalgebra::DMatrix;
+
+    #[test]
+    fn test_par_from_fn_matches_from_fn() {
+        let f = |x: usize, y: usize| (x as f64 * 0.37).sin() + (y as f64 * 1.13).cos();
+
+        for (width, height) in [(1, 1), (7, 3), (40, 97), (128, 1)] {
+            assert_eq!(par_from_fn(width, height, f), DMatrix::from_fn(width, height, f));
+        }
+        assert_eq!(par_from_fn(0, 5, f).shape(), (0, 5));
+    }
+}
@@ -0,0 +1,177 @@
+use crate::map_generators::grid::GridTopology;
+use crate::map_generators::map_generator::MapGeneratorError;
+use crate::map_generators::progress::{NoProgress, ProgressSink};
+use crate::map_generators::tile::Tile;
+use crate::math_helpers::create_new_seed64;
+use nalgebra::DMatrix;
+use rand_seeder::Seeder;
+
+use std::collections::HashMap;
+
+/// A single step of a [`MapPipeline`].
+///
+/// Base passes (like the noise and voronoi generators) ignore the incoming
+/// tiles and only use their shape, later passes transform them. Every pass
+/// lays its tiles out on the pipeline's [`GridTopology`].
+pub trait MapPass {
+    fn name(&self) -> &str;
+
+    fn apply(
+        &self,
+        tiles: DMatrix<Tile>,
+        topology: GridTopology,
+        seeder: &mut Seeder,
+        progress: &dyn ProgressSink,
+    ) -> Result<DMatrix<Tile>, MapGeneratorError>;
+}
+
+/// Runs an ordered list of passes over one tile matrix.
+///
+/// Every pass gets a child seeder derived from the master seed and the pass
+/// name (plus how many passes with that name came before it), so adding or
+/// removing a pass doesn't change what the other passes generate.
+pub struct MapPipeline {
+    map_width: usize,
+    map_height: usize,
+    seed: String,
+    topology: GridTopology,
+    passes: Vec<Box<dyn MapPass + Send + Sync>>,
+}
+
+impl MapPipeline {
+    pub fn new(map_width: usize, map_height: usize, seed: String) -> MapPipeline {
+        MapPipeline {
+            map_width,
+            map_height,
+            seed,
+            topology: GridTopology::SQUARE,
+            passes: Vec::new(),
+        }
+    }
+
+    pub fn set_topology(&mut self, topology: GridTopology) {
+        self.topology = topology;
+    }
+
+    pub fn add_pass(&mut self, pass: impl MapPass + Send + Sync + 'static) {
+        self.passes.push(Box::new(pass));
+    }
+
+    pub fn pass_names(&self) -> Vec<&str> {
+        self.passes.iter().map(|pass| pass.name()).collect()
+    }
+
+    pub fn run(&self) -> Result<DMatrix<Tile>, MapGeneratorError> {
+        self.run_with_progress(&NoProgress)
+    }
+
+    pub fn run_with_progress(&self, progress: &dyn ProgressSink) -> Result<DMatrix<Tile>, MapGeneratorError> {
+        let tiles = DMatrix::from_element(self.map_width, self.map_height, Tile::default());
+        self.apply_with_progress(tiles, progress)
+    }
+
+    /// Runs the passes over `tiles`, which have the size of the pipeline.
+    /// Reports every pass under the `"pipeline"` phase and checks for
+    /// cancellation before each of them.
+    pub fn apply_with_progress(
+        &self,
+        mut tiles: DMatrix<Tile>,
+        progress: &dyn ProgressSink,
+    ) -> Result<DMatrix<Tile>, MapGeneratorError> {
+        debug_assert_eq!(tiles.shape(), (self.map_width, self.map_height));
+        let mut master = Seeder::from(self.seed.as_str());
+        let master_seed = create_new_seed64(&mut master);
+        let mut name_counts: HashMap<&str, usize> = HashMap::new();
+
+        for (index, pass) in self.passes.iter().enumerate() {
+            if progress.is_cancelled() {
+                return Err(MapGeneratorError::Cancelled);
+            }
+            progress.report("pipeline", index, self.passes.len());
+            let occurrence = name_counts.entry(pass.name()).or_insert(0);
+            let mut seeder = Seeder::from((master_seed, pass.name(), *occurrence));
+            *occurrence += 1;
+
+            tiles = pass.apply(tiles, self.topology, &mut seeder, progress)?;
+        }
+        progress.report("pipeline", self.passes.len(), self.passes.len());
+
+        Ok(tiles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MapPass, MapPipeline};
+    use crate::map_generators::{GridTopology, MapGeneratorError, NoiseMapGenerator, NoiseMapOptions, MapGenerator, ProgressSink, Tile};
+    use nalgebra::DMatrix;
+    use rand::Rng;
+    use rand_pcg::Pcg64;
+    use rand_seeder::Seeder;
+
+    struct JitterPass(&'static str);
+
+    impl MapPass for JitterPass {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn apply(
+            &self,
+            tiles: DMatrix<Tile>,
+            _topology: GridTopology,
+            seeder: &mut Seeder,
+            _progress: &dyn ProgressSink,
+        ) -> Result<DMatrix<Tile>, MapGeneratorError> {
+            let mut rng: Pcg64 = seeder.make_rng();
+            Ok(tiles.map(|tile| Tile::new(tile.terrain(), tile.elevation() + rng.gen::<f64>())))
+        }
+    }
+
+    fn noise_pass() -> NoiseMapGenerator {
+        NoiseMapGenerator::new(NoiseMapOptions::new(16, 12, 0))
+    }
+
+    #[test]
+    fn test_pipeline_is_deterministic() {
+        let mut pipeline = MapPipeline::new(16, 12, "pipeline".to_string());
+        pipeline.add_pass(noise_pass());
+        pipeline.add_pass(JitterPass("jitter"));
+
+        let first = pipeline.run().expect("Failed to run pipeline");
+        let second = pipeline.run().expect("Failed to run pipeline");
+        assert_eq!(first.shape(), (16, 12));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_inserting_a_pass_keeps_other_seeds() {
+        let mut pipeline = MapPipeline::new(16, 12, "pipeline".to_string());
+        pipeline.add_pass(JitterPass("jitter"));
+        let without = pipeline.run().expect("Failed to run pipeline");
+
+        let mut pipeline = MapPipeline::new(16, 12, "pipeline".to_string());
+        pipeline.add_pass(JitterPass("other"));
+        pipeline.add_pass(JitterPass("jitter"));
+        let with = pipeline.run().expect("Failed to run pipeline");
+
+        let mut pipeline = MapPipeline::new(16, 12, "pipeline".to_string());
+        pipeline.add_pass(JitterPass("other"));
+        let other_only = pipeline.run().expect("Failed to run pipeline");
+
+        let combined = without.zip_map(&other_only, |a, b| a.elevation() + b.elevation());
+        for (expected, tile) in combined.iter().zip(with.iter()) {
+            assert!((expected - tile.elevation()).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_base_pass_is_reseeded_by_the_pipeline() {
+        let mut first = MapPipeline::new(16, 12, "first".to_string());
+        first.add_pass(noise_pass());
+        let mut second = MapPipeline::new(16, 12, "second".to_string());
+        second.add_pass(noise_pass());
+
+        assert_ne!(first.run().expect("Failed to run pipeline"), second.run().expect("Failed to run pipeline"));
+    }
+}
@@ -0,0 +1,819 @@
+use crate::map_generators::grid::{GridTopology, WrapMode};
+use crate::map_generators::map_generator::{MapGenerator, MapGeneratorError};
+use crate::map_generators::noise_map::{base_noise, NoiseFunction, NoiseMapOptions};
+use crate::map_generators::parallel::par_from_fn_with_progress;
+use crate::map_generators::pipeline::MapPass;
+use crate::map_generators::progress::{NoProgress, ProgressSink};
+use crate::map_generators::tile::Tile;
+use crate::map_generators::tile_types::TileType;
+use crate::math_helpers::create_new_seed32;
+use bevy::log::{debug, info_span};
+use nalgebra::{DMatrix, Vector3};
+use noise::{Fbm, MultiFractal, NoiseFn, OpenSimplex};
+use rand::prelude::*;
+use rand_pcg::Pcg64;
+use rand_seeder::Seeder;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeSet;
+use std::f64::consts::{FRAC_PI_2, PI, TAU};
+
+/// Names of the faces of a cube map, in the order they're laid out in and
+/// [`cube_map_faces`] cuts them out.
+pub const CUBE_FACE_NAMES: [&str; 6] = ["front", "right", "back", "left", "north", "south"];
+
+/// Outward normal, right and up of every cube face as seen from outside the
+/// planet. The side faces have north up, the north pole is seen with the
+/// front face below it and the south pole with the front face above it.
+const CUBE_FACES: [[[f64; 3]; 3]; 6] = [
+    [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+    [[0.0, 1.0, 0.0], [-1.0, 0.0, 0.0], [0.0, 0.0, 1.0]],
+    [[-1.0, 0.0, 0.0], [0.0, -1.0, 0.0], [0.0, 0.0, 1.0]],
+    [[0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]],
+    [[0.0, 0.0, 1.0], [0.0, 1.0, 0.0], [-1.0, 0.0, 0.0]],
+    [[0.0, 0.0, -1.0], [0.0, 1.0, 0.0], [1.0, 0.0, 0.0]],
+];
+
+/// How many times the coastline warp noise repeats across the radius of the
+/// planet.
+const COAST_WARP_FREQUENCY: f64 = 3.0;
+
+/// How the sphere of a planet is flattened onto the rectangle of tiles.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum PlanetProjection {
+    /// Longitude and latitude straight onto x and y, the poles stretched
+    /// over the whole top and bottom rows. A map twice as wide as it is high
+    /// keeps the tiles on the equator square.
+    #[default]
+    Equirectangular,
+    /// Keeps shapes but blows areas up towards the poles, which it never
+    /// reaches. The width of the map sets the scale, so a map twice as wide
+    /// as it is high ends at 66 degrees and a square one at 85.
+    Mercator,
+    /// The six faces of a cube blown up onto the sphere, three across and two
+    /// down: front, right and back along the equator, then left and the two
+    /// poles. Tiles left over on maps that aren't 3:2 are open sea.
+    CubeMap,
+}
+
+impl PlanetProjection {
+    /// The edges of the map that meet on the planet. East and west meet on
+    /// the flat projections, cube faces are cut apart.
+    pub fn wrap(&self) -> WrapMode {
+        match self {
+            Self::Equirectangular | Self::Mercator => WrapMode::Horizontal,
+            Self::CubeMap => WrapMode::None,
+        }
+    }
+
+    /// The point on the unit sphere shown at `(x, y)`, in tiles from the top
+    /// left corner of a `width` by `height` map. North is `+z` and longitude
+    /// zero is `+x`. `None` where a cube map has no face.
+    pub fn sphere_point(&self, x: f64, y: f64, width: usize, height: usize) -> Option<Vector3<f64>> {
+        let (width, height) = (width as f64, height as f64);
+        let on_sphere = |latitude: f64| {
+            let longitude = x / width * TAU - PI;
+            let (sin_latitude, cos_latitude) = latitude.sin_cos();
+            let (sin_longitude, cos_longitude) = longitude.sin_cos();
+            Vector3::new(cos_latitude * cos_longitude, cos_latitude * sin_longitude, sin_latitude)
+        };
+        match self {
+            Self::Equirectangular => Some(on_sphere(FRAC_PI_2 - y / height * PI)),
+            Self::Mercator => Some(on_sphere(((height / 2.0 - y) * TAU / width).sinh().atan())),
+            Self::CubeMap => {
+                let face_size = cube_face_size(width as usize, height as usize) as f64;
+                if face_size == 0.0 {
+                    return None;
+                }
+                let (column, row) = ((x / face_size).floor(), (y / face_size).floor());
+                if !(0.0..3.0).contains(&column) || !(0.0..2.0).contains(&row) {
+                    return None;
+                }
+                let [normal, right, up] = CUBE_FACES[row as usize * 3 + column as usize].map(Vector3::from);
+                let u = 2.0 * (x / face_size - column) - 1.0;
+                let v = 1.0 - 2.0 * (y / face_size - row);
+                Some((normal + u * right + v * up).normalize())
+            }
+        }
+    }
+
+    /// The point on the unit sphere at the center of a tile.
+    pub fn tile_point(&self, topology: GridTopology, x: usize, y: usize, width: usize, height: usize) -> Option<Vector3<f64>> {
+        let (center_x, center_y) = topology.tile_center(x, y);
+        // Tile centers are half a tile in from the edges of the map.
+        self.sphere_point(center_x + 0.5, center_y + 0.5, width, height)
+    }
+
+    /// Latitude of a tile in radians, positive to the north.
+    pub fn tile_latitude(&self, topology: GridTopology, x: usize, y: usize, width: usize, height: usize) -> Option<f64> {
+        self.tile_point(topology, x, y, width, height)
+            .map(|point| point.z.clamp(-1.0, 1.0).asin())
+    }
+
+    /// Tiles per radius of the planet around the equator, or in the middle
+    /// of a cube face. Scaling the sphere by it keeps noise frequencies in
+    /// cycles per tile.
+    pub fn tiles_per_radius(&self, width: usize, height: usize) -> f64 {
+        match self {
+            Self::Equirectangular | Self::Mercator => width as f64 / TAU,
+            Self::CubeMap => cube_face_size(width, height) as f64 / 2.0,
+        }
+    }
+}
+
+/// Edge length in tiles of the faces of a cube map laid out on a `width` by
+/// `height` map.
+pub fn cube_face_size(width: usize, height: usize) -> usize {
+    (width / 3).min(height / 2)
+}
+
+/// Cuts the six faces out of a cube map, in the order of
+/// [`CUBE_FACE_NAMES`].
+pub fn cube_map_faces(tiles: &DMatrix<Tile>) -> Vec<DMatrix<Tile>> {
+    let face_size = cube_face_size(tiles.nrows(), tiles.ncols());
+    (0..CUBE_FACE_NAMES.len())
+        .map(|face| {
+            tiles
+                .view(((face % 3) * face_size, (face / 3) * face_size), (face_size, face_size))
+                .into_owned()
+        })
+        .collect()
+}
+
+/// `count` points spread almost evenly over the unit sphere, on a spiral
+/// from the north to the south pole where every point is turned by the
+/// golden angle from the one before.
+pub fn fibonacci_sphere(count: usize) -> Vec<Vector3<f64>> {
+    let golden_angle = PI * (3.0 - 5.0_f64.sqrt());
+    (0..count)
+        .map(|index| {
+            let z = 1.0 - (2.0 * index as f64 + 1.0) / count as f64;
+            let radius = (1.0 - z * z).sqrt();
+            let (sin, cos) = (golden_angle * index as f64).sin_cos();
+            Vector3::new(radius * cos, radius * sin, z)
+        })
+        .collect()
+}
+
+/// Continents grown over cells spread across the whole sphere, the same way
+/// [`crate::map_generators::ContinentOptions`] grows them on a flat map.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct PlanetContinentOptions {
+    cell_count: usize,
+    land_area_percentage: f64,
+    num_continents: usize,
+    ocean_gap: bool,
+    coast_warp: f64,
+    continent_height: f64,
+}
+
+impl PlanetContinentOptions {
+    pub fn new(cell_count: usize) -> PlanetContinentOptions {
+        PlanetContinentOptions {
+            cell_count,
+            land_area_percentage: 29.0,
+            num_continents: 8,
+            ocean_gap: false,
+            coast_warp: 0.05,
+            continent_height: 0.3,
+        }
+    }
+
+    /// Cells on the Fibonacci sphere. At least one is used.
+    pub fn set_cell_count(&mut self, cell_count: usize) {
+        self.cell_count = cell_count;
+    }
+
+    pub fn set_land_area_percentage(&mut self, land_area_percentage: f64) {
+        self.land_area_percentage = land_area_percentage;
+    }
+
+    pub fn set_num_continents(&mut self, num_continents: usize) {
+        self.num_continents = num_continents;
+    }
+
+    /// Keeps a cell of water between any two continents.
+    pub fn set_ocean_gap(&mut self, ocean_gap: bool) {
+        self.ocean_gap = ocean_gap;
+    }
+
+    /// How far coastlines are pushed around, in radii of the planet.
+    pub fn set_coast_warp(&mut self, coast_warp: f64) {
+        self.coast_warp = coast_warp;
+    }
+
+    /// How far the continents are raised above the noise, and the sea
+    /// lowered below it. Noise still sinks lakes into the continents and
+    /// raises islands out of the sea where it beats this.
+    pub fn set_continent_height(&mut self, continent_height: f64) {
+        self.continent_height = continent_height;
+    }
+
+    pub fn cell_count(&self) -> usize {
+        self.cell_count
+    }
+
+    pub fn land_area_percentage(&self) -> f64 {
+        self.land_area_percentage
+    }
+
+    pub fn num_continents(&self) -> usize {
+        self.num_continents
+    }
+
+    pub fn ocean_gap(&self) -> bool {
+        self.ocean_gap
+    }
+
+    pub fn coast_warp(&self) -> f64 {
+        self.coast_warp
+    }
+
+    pub fn continent_height(&self) -> f64 {
+        self.continent_height
+    }
+}
+
+impl Default for PlanetContinentOptions {
+    fn default() -> Self {
+        PlanetContinentOptions::new(2000)
+    }
+}
+
+/// A whole planet, with its elevation sampled from 3D noise on the sphere
+/// and projected onto the map.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct PlanetOptions {
+    map_width: usize,
+    map_height: usize,
+    seed: u32,
+    projection: PlanetProjection,
+    noise_function: NoiseFunction,
+    octaves: usize,
+    frequency: f64,
+    lacunarity: f64,
+    persistence: f64,
+    sea_level: f64,
+    continents: Option<PlanetContinentOptions>,
+}
+
+impl PlanetOptions {
+    pub fn new(map_width: usize, map_height: usize, seed: u32) -> PlanetOptions {
+        PlanetOptions {
+            map_width,
+            map_height,
+            seed,
+            projection: PlanetProjection::Equirectangular,
+            noise_function: NoiseFunction::Fbm,
+            octaves: 8,
+            frequency: 0.004,
+            lacunarity: Fbm::<OpenSimplex>::DEFAULT_LACUNARITY,
+            persistence: Fbm::<OpenSimplex>::DEFAULT_PERSISTENCE,
+            sea_level: 0.0,
+            continents: None,
+        }
+    }
+
+    pub fn set_projection(&mut self, projection: PlanetProjection) {
+        self.projection = projection;
+    }
+
+    pub fn set_noise_function(&mut self, noise_function: NoiseFunction) {
+        self.noise_function = noise_function;
+    }
+
+    /// Clamped to between 1 and 32 when the noise is built.
+    pub fn set_octaves(&mut self, octaves: usize) {
+        self.octaves = octaves;
+    }
+
+    /// Frequency of the first octave in cycles per tile on the equator.
+    pub fn set_frequency(&mut self, frequency: f64) {
+        self.frequency = frequency;
+    }
+
+    pub fn set_lacunarity(&mut self, lacunarity: f64) {
+        self.lacunarity = lacunarity;
+    }
+
+    pub fn set_persistence(&mut self, persistence: f64) {
+        self.persistence = persistence;
+    }
+
+    pub fn set_sea_level(&mut self, sea_level: f64) {
+        self.sea_level = sea_level;
+    }
+
+    /// Continents grown on the sphere underneath the noise. Without them
+    /// the noise alone decides where land is.
+    pub fn set_continents(&mut self, continents: Option<PlanetContinentOptions>) {
+        self.continents = continents;
+    }
+
+    pub fn map_width(&self) -> usize {
+        self.map_width
+    }
+
+    pub fn map_height(&self) -> usize {
+        self.map_height
+    }
+
+    pub fn seed(&self) -> u32 {
+        self.seed
+    }
+
+    pub fn projection(&self) -> PlanetProjection {
+        self.projection
+    }
+
+    pub fn noise_function(&self) -> NoiseFunction {
+        self.noise_function
+    }
+
+    pub fn octaves(&self) -> usize {
+        self.octaves
+    }
+
+    pub fn frequency(&self) -> f64 {
+        self.frequency
+    }
+
+    pub fn lacunarity(&self) -> f64 {
+        self.lacunarity
+    }
+
+    pub fn persistence(&self) -> f64 {
+        self.persistence
+    }
+
+    pub fn sea_level(&self) -> f64 {
+        self.sea_level
+    }
+
+    pub fn continents(&self) -> Option<&PlanetContinentOptions> {
+        self.continents.as_ref()
+    }
+
+    /// The noise map options with the same fractal, so both generators
+    /// build their noise the same way.
+    fn terrain_noise(&self) -> NoiseMapOptions {
+        let mut noise = NoiseMapOptions::new(self.map_width, self.map_height, self.seed);
+        noise.set_noise_function(self.noise_function);
+        noise.set_octaves(self.octaves);
+        noise.set_frequency(self.frequency);
+        noise.set_lacunarity(self.lacunarity);
+        noise.set_persistence(self.persistence);
+        noise
+    }
+}
+
+impl Default for PlanetOptions {
+    fn default() -> Self {
+        let mut seeder = Seeder::from("Initial Seed");
+        let seed = create_new_seed32(&mut seeder);
+        PlanetOptions::new(1920, 960, seed)
+    }
+}
+
+/// Sites spread over the unit sphere, with a grid of buckets over the cube
+/// around it to find the sites close to a point.
+struct SphereCells {
+    sites: Vec<Vector3<f64>>,
+    neighbors: Vec<Vec<usize>>,
+    bucket_size: f64,
+    buckets_per_axis: usize,
+    buckets: Vec<Vec<usize>>,
+}
+
+impl SphereCells {
+    /// Sites on the Fibonacci sphere, each pushed up to a third of the
+    /// average spacing off the spiral so every seed gets its own cells.
+    fn new<R: Rng>(count: usize, rng: &mut R) -> SphereCells {
+        let spacing = (4.0 * PI / count as f64).sqrt();
+        let sites: Vec<Vector3<f64>> = fibonacci_sphere(count)
+            .into_iter()
+            .map(|site| {
+                let jitter = Vector3::from_fn(|_, _| rng.gen_range(-1.0..1.0)) * spacing / 3.0;
+                (site + jitter).normalize()
+            })
+            .collect();
+        let bucket_size = 2.0 * spacing;
+        let buckets_per_axis = ((2.0 / bucket_size).ceil() as usize).max(1);
+        let mut cells = SphereCells {
+            sites,
+            neighbors: Vec::new(),
+            bucket_size,
+            buckets_per_axis,
+            buckets: vec![Vec::new(); buckets_per_axis.pow(3)],
+        };
+        for site_index in 0..cells.sites.len() {
+            let bucket = cells.bucket(&cells.sites[site_index]);
+            cells.buckets[bucket].push(site_index);
+        }
+
+        // The sites sit on a nearly hexagonal lattice, so the six closest
+        // are the cells the site's cell shares an edge with.
+        let mut neighbors = vec![BTreeSet::new(); cells.sites.len()];
+        for (site_index, site) in cells.sites.iter().enumerate() {
+            let mut nearby: Vec<(usize, f64)> = cells
+                .nearby(site)
+                .filter(|other| *other != site_index)
+                .map(|other| (other, (cells.sites[other] - site).norm_squared()))
+                .collect();
+            nearby.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+            for (other, _) in nearby.into_iter().take(6) {
+                neighbors[site_index].insert(other);
+                neighbors[other].insert(site_index);
+            }
+        }
+        cells.neighbors = neighbors.into_iter().map(|neighbors| neighbors.into_iter().collect()).collect();
+        cells
+    }
+
+    fn len(&self) -> usize {
+        self.sites.len()
+    }
+
+    fn bucket_coordinates(&self, point: &Vector3<f64>) -> [usize; 3] {
+        [point.x, point.y, point.z].map(|coordinate| {
+            (((coordinate + 1.0) / self.bucket_size).max(0.0) as usize).min(self.buckets_per_axis - 1)
+        })
+    }
+
+    fn bucket(&self, point: &Vector3<f64>) -> usize {
+        let [x, y, z] = self.bucket_coordinates(point);
+        (x * self.buckets_per_axis + y) * self.buckets_per_axis + z
+    }
+
+    /// Sites in the bucket of `point` and the buckets around it.
+    fn nearby<'a>(&'a self, point: &Vector3<f64>) -> impl Iterator<Item = usize> + 'a {
+        let last = self.buckets_per_axis - 1;
+        let around = move |coordinate: usize| coordinate.saturating_sub(1)..=(coordinate + 1).min(last);
+        let [x, y, z] = self.bucket_coordinates(point);
+        around(x)
+            .flat_map(move |x| around(y).flat_map(move |y| around(z).map(move |z| (x, y, z))))
+            .flat_map(move |(x, y, z)| self.buckets[(x * self.buckets_per_axis + y) * self.buckets_per_axis + z].iter().copied())
+    }
+
+    /// The site closest to `point`.
+    fn closest(&self, point: &Vector3<f64>) -> usize {
+        let distance = |site_index: &usize| (self.sites[*site_index] - point).norm_squared();
+        let closest = self.nearby(point).min_by(|a, b| distance(a).total_cmp(&distance(b)));
+        match closest {
+            // Sites outside the buckets around the point are more than a
+            // bucket away from it.
+            Some(closest) if distance(&closest) <= self.bucket_size * self.bucket_size => closest,
+            _ => (0..self.len())
+                .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+                .expect("There's always at least one cell"),
+        }
+    }
+}
+
+/// Grows the continents over the cells, picking a continent that can still
+/// grow and a free cell along its coast every step. Gives back the continent
+/// that owns each cell.
+fn grow_continents<R: Rng>(
+    cells: &SphereCells,
+    options: &PlanetContinentOptions,
+    rng: &mut R,
+    progress: &dyn ProgressSink,
+) -> Result<Vec<Option<usize>>, MapGeneratorError> {
+    let land_target = ((options.land_area_percentage / 100.0).clamp(0.0, 1.0) * cells.len() as f64) as usize;
+    let mut owners: Vec<Option<usize>> = vec![None; cells.len()];
+    let is_free = |owners: &[Option<usize>], cell_index: usize, continent: usize| {
+        owners[cell_index].is_none()
+            && !(options.ocean_gap
+                && cells.neighbors[cell_index]
+                    .iter()
+                    .any(|neighbor| owners[*neighbor].is_some_and(|owner| owner != continent)))
+    };
+
+    let mut frontiers: Vec<BTreeSet<usize>> = Vec::new();
+    let mut initial_cells = (0..cells.len()).choose_multiple(rng, options.num_continents.min(land_target));
+    initial_cells.shuffle(rng);
+    for (continent, cell_index) in initial_cells.into_iter().enumerate() {
+        owners[cell_index] = Some(continent);
+        frontiers.push(cells.neighbors[cell_index].iter().copied().collect());
+    }
+
+    let mut land = frontiers.len();
+    progress.report("continent_growth", land, land_target);
+    while land < land_target {
+        if progress.is_cancelled() {
+            return Err(MapGeneratorError::Cancelled);
+        }
+        let growing: Vec<usize> = (0..frontiers.len()).filter(|continent| !frontiers[*continent].is_empty()).collect();
+        let Some(&continent) = growing.choose(rng) else {
+            break;
+        };
+        let cell_index = *frontiers[continent].iter().choose(rng).expect("Only continents with a coast grow");
+        frontiers[continent].remove(&cell_index);
+        if is_free(&owners, cell_index, continent) {
+            owners[cell_index] = Some(continent);
+            land += 1;
+            frontiers[continent].extend(cells.neighbors[cell_index].iter().filter(|neighbor| owners[**neighbor].is_none()));
+            progress.report("continent_growth", land, land_target);
+        }
+    }
+    Ok(owners)
+}
+
+/// Continents grown on the sphere and the noise that frays their coasts.
+struct SphereContinents<'a> {
+    options: &'a PlanetContinentOptions,
+    cells: SphereCells,
+    owners: Vec<Option<usize>>,
+    warp: Fbm<OpenSimplex>,
+}
+
+impl SphereContinents<'_> {
+    /// How far the continents raise or the sea lowers the point.
+    fn elevation_bias(&self, point: &Vector3<f64>) -> f64 {
+        // Moving the point before looking up its cell frays the straight
+        // cell edges into coastlines.
+        let offset = Vector3::new(
+            self.warp.get([point.x, point.y, point.z]),
+            self.warp.get([point.y + 31.4, point.z, point.x]),
+            self.warp.get([point.z, point.x - 27.1, point.y]),
+        );
+        let cell_index = self.cells.closest(&(point + offset * self.options.coast_warp).normalize());
+        match self.owners[cell_index] {
+            Some(_) => self.options.continent_height,
+            None => -self.options.continent_height,
+        }
+    }
+}
+
+pub struct PlanetGenerator {
+    options: PlanetOptions,
+}
+
+impl MapGenerator for PlanetGenerator {
+    type Options = PlanetOptions;
+
+    fn new(options: PlanetOptions) -> PlanetGenerator {
+        PlanetGenerator { options }
+    }
+
+    fn name(&self) -> &'static str {
+        "planet"
+    }
+
+    fn options(&self) -> &PlanetOptions {
+        &self.options
+    }
+
+    fn generate_on_grid(&self, topology: GridTopology, progress: &dyn ProgressSink) -> Result<DMatrix<Tile>, MapGeneratorError> {
+        planet_map_with_progress(&self.options, topology, progress)
+    }
+}
+
+impl MapPass for PlanetGenerator {
+    fn name(&self) -> &str {
+        MapGenerator::name(self)
+    }
+
+    fn apply(
+        &self,
+        tiles: DMatrix<Tile>,
+        topology: GridTopology,
+        seeder: &mut Seeder,
+        progress: &dyn ProgressSink,
+    ) -> Result<DMatrix<Tile>, MapGeneratorError> {
+        let mut options = self.options.clone();
+        options.map_width = tiles.nrows();
+        options.map_height = tiles.ncols();
+        options.seed = create_new_seed32(seeder);
+        planet_map_with_progress(&options, topology, progress)
+    }
+}
+
+pub fn planet_map(options: &PlanetOptions) -> DMatrix<Tile> {
+    planet_map_with_progress(options, GridTopology::SQUARE, &NoProgress).expect("Nothing cancels without a sink")
+}
+
+/// Samples every tile at its point on the sphere. Only the shape of the
+/// tiles is taken from `topology`, the projection decides which edges meet.
+pub fn planet_map_with_progress(
+    options: &PlanetOptions,
+    topology: GridTopology,
+    progress: &dyn ProgressSink,
+) -> Result<DMatrix<Tile>, MapGeneratorError> {
+    let _span = info_span!("planet", seed = options.seed).entered();
+    let (width, height) = (options.map_width, options.map_height);
+    debug!(width, height, projection = ?options.projection, "Generating planet");
+
+    let continents = match &options.continents {
+        Some(continent_options) => {
+            let mut seeder = Seeder::from((options.seed, "continents"));
+            let mut rng: Pcg64 = seeder.make_rng();
+            let cells = SphereCells::new(continent_options.cell_count.max(1), &mut rng);
+            let owners = grow_continents(&cells, continent_options, &mut rng, progress)?;
+            let warp = Fbm::<OpenSimplex>::new(create_new_seed32(&mut seeder))
+                .set_octaves(4)
+                .set_frequency(COAST_WARP_FREQUENCY);
+            Some(SphereContinents { options: continent_options, cells, owners, warp })
+        }
+        None => None,
+    };
+
+    let noise = base_noise(&options.terrain_noise());
+    let radius = options.projection.tiles_per_radius(width, height);
+    par_from_fn_with_progress(width, height, "tiles", progress, |x, y| {
+        let Some(point) = options.projection.tile_point(topology, x, y, width, height) else {
+            return Tile::new(TileType::Water, options.sea_level - 1.0);
+        };
+        let scaled = point * radius;
+        let mut elevation = NoiseFn::<f64, 3>::get(noise.as_ref(), [scaled.x, scaled.y, scaled.z]);
+        if let Some(continents) = &continents {
+            elevation += continents.elevation_bias(&point);
+        }
+
+        let terrain = if elevation < options.sea_level { TileType::Water } else { TileType::Grassland };
+        Tile::new(terrain, elevation)
+    })
+    .ok_or(MapGeneratorError::Cancelled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        cube_face_size, cube_map_faces, fibonacci_sphere, grow_continents, planet_map, PlanetContinentOptions,
+        PlanetOptions, PlanetProjection, SphereCells,
+    };
+    use crate::map_generators::{GridTopology, NoProgress, TileType};
+    use nalgebra::Vector3;
+    use rand_pcg::Pcg64;
+    use rand_seeder::Seeder;
+
+    use std::f64::consts::{FRAC_PI_2, PI};
+
+    fn assert_close(a: Vector3<f64>, b: Vector3<f64>) {
+        assert!((a - b).norm() < 1e-6, "{a:?} {b:?}");
+    }
+
+    #[test]
+    fn test_fibonacci_sphere_spreads_points_evenly() {
+        let points = fibonacci_sphere(500);
+        let spacing = (4.0 * PI / 500.0).sqrt();
+
+        for (index, point) in points.iter().enumerate() {
+            assert!((point.norm() - 1.0).abs() < 1e-9);
+            let closest = points
+                .iter()
+                .enumerate()
+                .filter(|(other, _)| *other != index)
+                .map(|(_, other)| (other - point).norm())
+                .fold(f64::MAX, f64::min);
+            assert!(closest > 0.5 * spacing && closest < 1.5 * spacing, "{index}: {closest}");
+        }
+        let center: Vector3<f64> = points.iter().sum::<Vector3<f64>>() / 500.0;
+        assert!(center.norm() < 0.01);
+    }
+
+    #[test]
+    fn test_closest_cell_matches_brute_force() {
+        let mut rng: Pcg64 = Seeder::from("closest").make_rng();
+        let cells = SphereCells::new(300, &mut rng);
+
+        for point in fibonacci_sphere(2000) {
+            let expected = (0..cells.len())
+                .min_by(|a, b| (cells.sites[*a] - point).norm().total_cmp(&(cells.sites[*b] - point).norm()))
+                .unwrap();
+            assert_eq!(cells.closest(&point), expected);
+        }
+        for (cell_index, neighbors) in cells.neighbors.iter().enumerate() {
+            assert!((5..=9).contains(&neighbors.len()), "{cell_index}: {neighbors:?}");
+            assert!(neighbors.iter().all(|neighbor| cells.neighbors[*neighbor].contains(&cell_index)));
+        }
+    }
+
+    #[test]
+    fn test_flat_projections_run_from_pole_to_pole() {
+        let topology = GridTopology::SQUARE;
+        let latitude = |projection: PlanetProjection, y: usize, width: usize, height: usize| {
+            projection.tile_latitude(topology, 0, y, width, height).unwrap()
+        };
+
+        let equirectangular = PlanetProjection::Equirectangular;
+        assert!((latitude(equirectangular, 0, 200, 100) - FRAC_PI_2).abs() < 0.02);
+        assert!((latitude(equirectangular, 99, 200, 100) + FRAC_PI_2).abs() < 0.02);
+        assert!(latitude(equirectangular, 49, 200, 100).abs() < 0.02);
+
+        // Mercator never gets to the poles, and its rows bunch up towards
+        // the equator.
+        let mercator = PlanetProjection::Mercator;
+        assert!((latitude(mercator, 0, 100, 100).to_degrees() - 85.0).abs() < 1.0);
+        assert!((latitude(mercator, 0, 200, 100).to_degrees() - 66.0).abs() < 1.0);
+        assert!(latitude(mercator, 25, 200, 100) < latitude(equirectangular, 25, 200, 100));
+
+        for projection in [equirectangular, mercator] {
+            for y in [0.5, 20.0, 50.0, 99.5] {
+                let west = projection.sphere_point(0.0, y, 200, 100).unwrap();
+                let east = projection.sphere_point(200.0, y, 200, 100).unwrap();
+                assert_close(west, east);
+            }
+        }
+    }
+
+    #[test]
+    fn test_cube_map_faces_meet_along_their_edges() {
+        let projection = PlanetProjection::CubeMap;
+        let point = |x: f64, y: f64| projection.sphere_point(x, y, 30, 20).unwrap();
+
+        for (face, normal) in [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [-1.0, 0.0, 0.0], [0.0, -1.0, 0.0], [0.0, 0.0, 1.0], [0.0, 0.0, -1.0]]
+            .into_iter()
+            .enumerate()
+        {
+            let center = point((face % 3) as f64 * 10.0 + 5.0, (face / 3) as f64 * 10.0 + 5.0);
+            assert_close(center, Vector3::from(normal));
+        }
+        for along in [0.0, 2.5, 7.0, 9.5] {
+            // Front to right and right to back along the equator.
+            assert_close(point(10.0 - 1e-9, along), point(10.0, along));
+            assert_close(point(20.0 - 1e-9, along), point(20.0, along));
+            // The north pole sits on top of the front face.
+            assert_close(point(along, 0.0), point(10.0 + along, 20.0 - 1e-9));
+        }
+        assert_eq!(projection.sphere_point(31.0, 5.0, 32, 20), None);
+    }
+
+    #[test]
+    fn test_cube_map_faces_are_cut_out_of_the_atlas() {
+        let mut options = PlanetOptions::new(32, 21, 4);
+        options.set_projection(PlanetProjection::CubeMap);
+        let tiles = planet_map(&options);
+
+        assert_eq!(cube_face_size(32, 21), 10);
+        let faces = cube_map_faces(&tiles);
+        assert_eq!(faces.len(), 6);
+        for (face, tiles_of_face) in faces.iter().enumerate() {
+            assert_eq!(tiles_of_face.shape(), (10, 10));
+            assert_eq!(tiles_of_face[(3, 7)], tiles[((face % 3) * 10 + 3, (face / 3) * 10 + 7)]);
+        }
+        assert!(tiles.column(20).iter().all(|tile| tile.terrain() == TileType::Water));
+        assert!(tiles.row(31).iter().all(|tile| tile.terrain() == TileType::Water));
+    }
+
+    #[test]
+    fn test_equirectangular_planet_is_seamless() {
+        let mut options = PlanetOptions::new(256, 128, 8);
+        options.set_frequency(0.02);
+        let tiles = planet_map(&options);
+        let step = |from: (usize, usize), to: (usize, usize)| (tiles[from].elevation() - tiles[to].elevation()).abs();
+
+        let across_seam: f64 = (0..128).map(|y| step((255, y), (0, y))).sum();
+        let inland: f64 = (0..128).map(|y| step((127, y), (128, y))).sum();
+        assert!(across_seam < 2.0 * inland, "{across_seam} {inland}");
+
+        // The whole top row lies around the north pole.
+        let top: Vec<f64> = tiles.column(0).iter().map(|tile| tile.elevation()).collect();
+        let spread = top.iter().fold(f64::MIN, |max, elevation| max.max(*elevation))
+            - top.iter().fold(f64::MAX, |min, elevation| min.min(*elevation));
+        assert!(spread < 0.1, "{spread}");
+    }
+
+    #[test]
+    fn test_continents_grow_to_the_land_area() {
+        for ocean_gap in [false, true] {
+            let mut rng: Pcg64 = Seeder::from("sphere continents").make_rng();
+            let cells = SphereCells::new(400, &mut rng);
+            let mut options = PlanetContinentOptions::new(400);
+            options.set_land_area_percentage(30.0);
+            options.set_num_continents(5);
+            options.set_ocean_gap(ocean_gap);
+            let owners = grow_continents(&cells, &options, &mut rng, &NoProgress).expect("Nothing cancels without a sink");
+
+            let land = owners.iter().filter(|owner| owner.is_some()).count();
+            assert!(land <= 120 && (ocean_gap || land == 120), "{land}");
+            assert_eq!(owners.iter().flatten().max(), Some(&4));
+            if ocean_gap {
+                for (cell_index, owner) in owners.iter().enumerate() {
+                    let Some(owner) = owner else { continue };
+                    assert!(cells.neighbors[cell_index]
+                        .iter()
+                        .all(|neighbor| owners[*neighbor].is_none_or(|other| other == *owner)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_continents_raise_land_out_of_the_sea() {
+        let mut options = PlanetOptions::new(120, 60, 2);
+        options.set_sea_level(0.2);
+        let without = planet_map(&options);
+        let mut continents = PlanetContinentOptions::new(300);
+        continents.set_continent_height(0.6);
+        options.set_continents(Some(continents));
+        let with = planet_map(&options);
+
+        let land = |tiles: &nalgebra::DMatrix<crate::map_generators::Tile>| {
+            tiles.iter().filter(|tile| tile.terrain() != TileType::Water).count() as f64 / tiles.len() as f64
+        };
+        assert!(land(&with) > land(&without));
+        assert!(land(&with) > 0.15 && land(&with) < 0.6, "{}", land(&with));
+        assert!(with.iter().all(|tile| tile.elevation().is_finite()));
+    }
+}
@@ -0,0 +1,89 @@
+use crate::map_generators::biomes::{BiomeOptions, BiomePass};
+use crate::map_generators::erosion::{ErosionOptions, ErosionPass};
+use crate::map_generators::grid::GridTopology;
+use crate::map_generators::hydrology::{HydrologyOptions, HydrologyPass};
+use crate::map_generators::pipeline::MapPipeline;
+use crate::map_generators::planet::PlanetProjection;
+
+/// The optional passes that run on top of a generated map.
+///
+/// They always run in the same order: erosion reshapes the elevation, the
+/// hydrology routes water over the eroded terrain and the biomes are picked
+/// last so they see the rivers and lakes.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct PostProcessOptions {
+    erosion: Option<ErosionOptions>,
+    hydrology: Option<HydrologyOptions>,
+    biomes: Option<BiomeOptions>,
+    topology: GridTopology,
+    projection: Option<PlanetProjection>,
+}
+
+impl PostProcessOptions {
+    pub fn new() -> PostProcessOptions {
+        PostProcessOptions::default()
+    }
+
+    pub fn set_erosion(&mut self, erosion: Option<ErosionOptions>) {
+        self.erosion = erosion;
+    }
+
+    pub fn set_hydrology(&mut self, hydrology: Option<HydrologyOptions>) {
+        self.hydrology = hydrology;
+    }
+
+    pub fn set_biomes(&mut self, biomes: Option<BiomeOptions>) {
+        self.biomes = biomes;
+    }
+
+    /// The grid the tiles were generated on, which decides what the passes
+    /// count as neighbors.
+    pub fn set_topology(&mut self, topology: GridTopology) {
+        self.topology = topology;
+    }
+
+    /// The projection of a planet, which gives every tile its latitude for
+    /// the biomes. Flat maps leave it out and run from pole to pole.
+    pub fn set_projection(&mut self, projection: Option<PlanetProjection>) {
+        self.projection = projection;
+    }
+
+    pub fn erosion(&self) -> Option<&ErosionOptions> {
+        self.erosion.as_ref()
+    }
+
+    pub fn hydrology(&self) -> Option<&HydrologyOptions> {
+        self.hydrology.as_ref()
+    }
+
+    pub fn biomes(&self) -> Option<&BiomeOptions> {
+        self.biomes.as_ref()
+    }
+
+    pub fn topology(&self) -> GridTopology {
+        self.topology
+    }
+
+    pub fn projection(&self) -> Option<PlanetProjection> {
+        self.projection
+    }
+
+    /// The passes as a [`MapPipeline`] for a map of the given size, seeded
+    /// by the seed of the map so every pass draws from its own child seed.
+    pub fn pipeline(&self, seed: String, map_width: usize, map_height: usize) -> MapPipeline {
+        let mut pipeline = MapPipeline::new(map_width, map_height, seed);
+        pipeline.set_topology(self.topology);
+        if let Some(erosion) = &self.erosion {
+            pipeline.add_pass(ErosionPass::new(erosion.clone()));
+        }
+        if let Some(hydrology) = &self.hydrology {
+            pipeline.add_pass(HydrologyPass::new(hydrology.clone()));
+        }
+        if let Some(biomes) = &self.biomes {
+            let mut pass = BiomePass::new(biomes.clone());
+            pass.set_projection(self.projection);
+            pipeline.add_pass(pass);
+        }
+        pipeline
+    }
+}
@@ -0,0 +1,34 @@
+/// Receives progress from a running generator or pass.
+///
+/// `report` can be called from several threads at once while tiles are
+/// filled in parallel, and `completed` isn't guaranteed to only ever grow
+/// within a phase.
+pub trait ProgressSink: Sync {
+    fn report(&self, phase: &str, completed: usize, total: usize);
+
+    /// Checked between steps. Once it returns `true` the generator stops and
+    /// returns a cancelled error.
+    fn is_cancelled(&self) -> bool {
+        false
+    }
+
+    /// Asks for the tiles to be filled on the calling thread. The output
+    /// doesn't change, this is only for debugging.
+    fn single_threaded(&self) -> bool {
+        false
+    }
+}
+
+/// Ignores all progress and never cancels.
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn report(&self, _phase: &str, _completed: usize, _total: usize) {}
+}
+
+/// Plain callbacks work as sinks that can't cancel.
+impl<F: Fn(&str, usize, usize) + Sync> ProgressSink for F {
+    fn report(&self, phase: &str, completed: usize, total: usize) {
+        self(phase, completed, total)
+    }
+}
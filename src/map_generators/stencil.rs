@@ -0,0 +1,68 @@
+use crate::map_generators::falloff::GrayscaleMask;
+use serde::{Deserialize, Serialize};
+
+/// A designer's sketch of where land should go, stretched over the map.
+///
+/// White is land and black is water. Indexed images work too, they are read
+/// through their palette colors.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct StencilOptions {
+    mask: GrayscaleMask,
+    strength: f64,
+}
+
+impl StencilOptions {
+    pub fn new(mask: GrayscaleMask) -> StencilOptions {
+        StencilOptions { mask, strength: 0.75 }
+    }
+
+    /// How closely the map follows the sketch. `0.0` ignores it and at
+    /// `1.0` land only grows outside the sketch once the inside is full.
+    pub fn set_strength(&mut self, strength: f64) {
+        self.strength = strength;
+    }
+
+    pub fn mask(&self) -> &GrayscaleMask {
+        &self.mask
+    }
+
+    pub fn strength(&self) -> f64 {
+        self.strength
+    }
+
+    /// How likely `(u, v)` is to be land, `u` and `v` going from `0.0` to
+    /// `1.0` across the map. Never quite `0.0` so land can still spill over
+    /// the sketch when it asks for more land than it has room for.
+    pub fn land_probability(&self, u: f64, v: f64) -> f64 {
+        (1.0 - self.strength.clamp(0.0, 1.0) * (1.0 - self.mask.sample(u, v))).max(0.01)
+    }
+
+    /// How far an elevation around sea level gets pushed up or down at
+    /// `(u, v)`, between `-strength` and `strength`.
+    pub fn elevation_bias(&self, u: f64, v: f64) -> f64 {
+        self.strength * (2.0 * self.mask.sample(u, v) - 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StencilOptions;
+    use crate::map_generators::GrayscaleMask;
+    use image::GrayImage;
+
+    #[test]
+    fn test_stencil_follows_the_sketch() {
+        let image = GrayImage::from_fn(2, 1, |x, _| [if x == 0 { 0 } else { 255 }].into());
+        let mut stencil = StencilOptions::new(GrayscaleMask::from_image(&image));
+        stencil.set_strength(1.0);
+
+        assert_eq!(stencil.land_probability(1.0, 0.0), 1.0);
+        assert_eq!(stencil.land_probability(0.0, 0.0), 0.01);
+        assert_eq!(stencil.elevation_bias(0.0, 0.0), -1.0);
+        assert_eq!(stencil.elevation_bias(1.0, 0.0), 1.0);
+
+        stencil.set_strength(0.0);
+        assert_eq!(stencil.land_probability(0.0, 0.0), 1.0);
+        assert_eq!(stencil.elevation_bias(0.0, 0.0), 0.0);
+    }
+}
@@ -1,15 +1,31 @@
 use crate::map_generators::tile_types::TileType;
 use bevy::ecs::component::Component;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, PartialEq, Clone, Component, Default, Copy)]
+#[derive(Debug, PartialEq, Clone, Component, Default, Copy, Serialize, Deserialize)]
 pub struct Tile {
     terrain: TileType,
     elevation: f64,
+    temperature: f64,
+    moisture: f64,
 }
 
 impl Tile {
     pub fn new(terrain: TileType, elevation: f64) -> Tile {
-        Tile { terrain, elevation }
+        Tile {
+            terrain,
+            elevation,
+            temperature: 0.0,
+            moisture: 0.0,
+        }
+    }
+
+    pub fn with_climate(self, temperature: f64, moisture: f64) -> Tile {
+        Tile {
+            temperature,
+            moisture,
+            ..self
+        }
     }
 
     pub fn terrain(&self) -> TileType {
@@ -19,4 +35,12 @@ impl Tile {
     pub fn elevation(&self) -> f64 {
         self.elevation
     }
+
+    pub fn temperature(&self) -> f64 {
+        self.temperature
+    }
+
+    pub fn moisture(&self) -> f64 {
+        self.moisture
+    }
 }
@@ -1,13 +1,22 @@
-#[derive(Debug, PartialEq, Clone, Copy, Default)]
+use serde::{Deserialize, Serialize};
+
+// New variants go at the end so saved binary maps keep their indices.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
 pub enum TileType {
     #[default]
     Water,
     Grassland,
-    //Coast,
-    //ThickForest,
-    //LightForest,
-    //Desert,
-    //Ice,
+    Coast,
+    ThickForest,
+    LightForest,
+    Desert,
+    Ice,
+    Tundra,
+    Mountain,
+    River,
+    Lake,
+    /// Frozen sea, which still counts as water.
+    SeaIce,
 }
 
 impl TileType {
@@ -15,6 +24,20 @@ impl TileType {
         match self {
             Self::Grassland => 0,
             Self::Water => 1,
+            Self::Ice => 2,
+            Self::Coast => 3,
+            Self::ThickForest => 4,
+            Self::LightForest => 5,
+            Self::Desert => 6,
+            Self::Tundra => 7,
+            Self::Mountain => 8,
+            Self::River => 9,
+            Self::Lake => 10,
+            Self::SeaIce => 11,
         }
     }
+
+    pub fn is_water(&self) -> bool {
+        matches!(self, Self::Water | Self::River | Self::Lake | Self::SeaIce)
+    }
 }
@@ -0,0 +1,297 @@
+use crate::map_generators::grid::WrapMode;
+use crate::map_generators::progress::ProgressSink;
+use crate::math_helpers::polygon_centroid;
+use thiserror::Error;
+use voronoice::{BoundingBox, NeighborSiteIterator, Point, Voronoi, VoronoiBuilder, VoronoiCell};
+
+#[derive(Error, Debug)]
+pub enum DiagramError {
+    #[error("Failed to generate a diagram")]
+    CreationFailed,
+    #[error("Relaxing the diagram was cancelled")]
+    Cancelled,
+}
+
+/// A Voronoi diagram of the sites of a map, repeated across the edges of the
+/// map that wrap so cells reach over the seam.
+///
+/// The sites are copied one map width to the left and to the right, and on a
+/// torus one map height up and down as well. The first
+/// [`TiledDiagram::site_count`] cells are the map's own and the copies only
+/// shape them. Neighbors are folded back onto the map's own cells, so a cell
+/// on the left edge of a wrapping map has neighbors on the right edge.
+///
+/// Without wrapping this is just the diagram of the sites.
+pub struct TiledDiagram {
+    diagram: Voronoi,
+    site_count: usize,
+    map_box: BoundingBox,
+    wrap: WrapMode,
+}
+
+impl TiledDiagram {
+    /// Builds the diagram of `sites`, which lie in a `width` by `height` box
+    /// centered on the origin, and relaxes it `iterations` times. Without
+    /// wrapping this is the builder's own relaxed diagram. Fails with
+    /// [`DiagramError::Cancelled`] as soon as `progress` is cancelled.
+    pub fn relaxed(
+        sites: Vec<Point>,
+        width: f64,
+        height: f64,
+        wrap: WrapMode,
+        iterations: usize,
+        progress: &dyn ProgressSink,
+    ) -> Result<TiledDiagram, DiagramError> {
+        let map_box = BoundingBox::new_centered(width, height);
+        if wrap == WrapMode::None {
+            let builder = VoronoiBuilder::default().set_sites(sites).set_bounding_box(map_box.clone());
+            let diagram = relaxed_voronoi(builder, iterations, progress)?;
+            return Ok(TiledDiagram { site_count: diagram.sites().len(), diagram, map_box, wrap });
+        }
+
+        let site_count = sites.len();
+        let diagram = tile_sites(&sites, &map_box, wrap).ok_or(DiagramError::CreationFailed)?;
+        let mut tiled = TiledDiagram { diagram, site_count, map_box, wrap };
+        progress.report("lloyd_relaxation", 0, iterations);
+        for iteration in 0..iterations {
+            if progress.is_cancelled() {
+                return Err(DiagramError::Cancelled);
+            }
+            // Moving every site to the middle of its cell, the same as the
+            // builder's Lloyd relaxation but with the copies moving along.
+            let relaxed: Vec<Point> = (0..site_count)
+                .map(|cell_index| {
+                    let cell = tiled.cell(cell_index);
+                    let vertices: Vec<&Point> = cell.iter_vertices().collect();
+                    tiled.wrap_point(polygon_centroid(&vertices))
+                })
+                .collect();
+            tiled.diagram = tile_sites(&relaxed, &tiled.map_box, wrap).ok_or(DiagramError::CreationFailed)?;
+            progress.report("lloyd_relaxation", iteration + 1, iterations);
+        }
+        Ok(tiled)
+    }
+
+    /// The whole diagram, copies included.
+    pub fn diagram(&self) -> &Voronoi {
+        &self.diagram
+    }
+
+    /// Number of cells that belong to the map.
+    pub fn site_count(&self) -> usize {
+        self.site_count
+    }
+
+    /// Sites of the cells that belong to the map.
+    pub fn sites(&self) -> &[Point] {
+        &self.diagram.sites()[..self.site_count]
+    }
+
+    pub fn cell(&self, cell_index: usize) -> VoronoiCell<'_> {
+        self.diagram.cell(cell_index)
+    }
+
+    /// The map's own cell that a cell of the whole diagram is a copy of.
+    pub fn own_cell(&self, cell_index: usize) -> usize {
+        cell_index % self.site_count
+    }
+
+    /// Neighbors of one of the map's own cells, folded onto the map's own
+    /// cells.
+    pub fn neighbors(&self, cell_index: usize) -> impl Iterator<Item = usize> + '_ {
+        NeighborSiteIterator::new(&self.diagram, cell_index)
+            .map(|neighbor| self.own_cell(neighbor))
+            .filter(move |neighbor| *neighbor != cell_index)
+    }
+
+    /// The map itself, centered on the origin.
+    pub fn bounding_box(&self) -> &BoundingBox {
+        &self.map_box
+    }
+
+    /// The box land has to stay inside of, leaving a water padding along the
+    /// edges of the map that don't wrap. Across the seams it reaches as far
+    /// as the copies do.
+    pub fn land_box(&self, water_padding_percentage: f64) -> BoundingBox {
+        let tiled_box = self.diagram.bounding_box();
+        let width = if self.wrap.wraps_x() {
+            tiled_box.width()
+        } else {
+            water_padding_percentage * self.map_box.width()
+        };
+        let height = if self.wrap.wraps_y() {
+            tiled_box.height()
+        } else {
+            water_padding_percentage * self.map_box.height()
+        };
+        BoundingBox::new(self.map_box.center().clone(), width, height)
+    }
+
+    /// How far `to` is from `from`, going the short way around the map.
+    pub fn offset(&self, from: &Point, to: &Point) -> (f64, f64) {
+        let around = |delta: f64, period: f64, wraps: bool| {
+            if wraps {
+                (delta + period / 2.0).rem_euclid(period) - period / 2.0
+            } else {
+                delta
+            }
+        };
+        (
+            around(to.x - from.x, self.map_box.width(), self.wrap.wraps_x()),
+            around(to.y - from.y, self.map_box.height(), self.wrap.wraps_y()),
+        )
+    }
+
+    /// Brings a point that drifted over a seam back onto the map.
+    pub fn wrap_point(&self, point: Point) -> Point {
+        let (x, y) = self.offset(self.map_box.center(), &point);
+        Point { x: self.map_box.center().x + x, y: self.map_box.center().y + y }
+    }
+}
+
+impl From<Voronoi> for TiledDiagram {
+    /// A diagram of a map that doesn't wrap.
+    fn from(diagram: Voronoi) -> Self {
+        TiledDiagram {
+            site_count: diagram.sites().len(),
+            map_box: diagram.bounding_box().clone(),
+            diagram,
+            wrap: WrapMode::None,
+        }
+    }
+}
+
+/// The diagram of `sites` and their copies across the seams, the map's own
+/// sites coming first.
+/// Builds the diagram and then relaxes it one Lloyd iteration at a time so
+/// every iteration can be reported. Gives the same diagram as letting the
+/// builder run all iterations itself.
+fn relaxed_voronoi(
+    builder: VoronoiBuilder,
+    iterations: usize,
+    progress: &dyn ProgressSink,
+) -> Result<Voronoi, DiagramError> {
+    let mut diagram = builder.build().ok_or(DiagramError::CreationFailed)?;
+    progress.report("lloyd_relaxation", 0, iterations);
+    for iteration in 0..iterations {
+        if progress.is_cancelled() {
+            return Err(DiagramError::Cancelled);
+        }
+        diagram = VoronoiBuilder::from(diagram)
+            .set_lloyd_relaxation_iterations(1)
+            .build()
+            .ok_or(DiagramError::CreationFailed)?;
+        progress.report("lloyd_relaxation", iteration + 1, iterations);
+    }
+    Ok(diagram)
+}
+
+fn tile_sites(sites: &[Point], map_box: &BoundingBox, wrap: WrapMode) -> Option<Voronoi> {
+    let (width, height) = (map_box.width(), map_box.height());
+    let columns: &[f64] = if wrap.wraps_x() { &[0.0, -1.0, 1.0] } else { &[0.0] };
+    let rows: &[f64] = if wrap.wraps_y() { &[0.0, -1.0, 1.0] } else { &[0.0] };
+    let tiled_sites = rows
+        .iter()
+        .flat_map(|row| columns.iter().map(move |column| (column * width, row * height)))
+        .flat_map(|(offset_x, offset_y)| sites.iter().map(move |site| Point { x: site.x + offset_x, y: site.y + offset_y }))
+        .collect();
+    VoronoiBuilder::default()
+        .set_sites(tiled_sites)
+        .set_bounding_box(BoundingBox::new(
+            map_box.center().clone(),
+            width * columns.len() as f64,
+            height * rows.len() as f64,
+        ))
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{relaxed_voronoi, DiagramError, TiledDiagram};
+    use crate::map_generators::progress::{NoProgress, ProgressSink};
+    use crate::map_generators::WrapMode;
+    use crate::math_helpers::shoelace_area_of_cell;
+    use voronoice::{BoundingBox, Point, VoronoiBuilder};
+
+    fn sites() -> Vec<Point> {
+        (0..60)
+            .map(|index| Point {
+                x: ((index * 37) % 100) as f64 - 49.5,
+                y: ((index * 61) % 80) as f64 - 39.5,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_relaxed_voronoi_matches_builder() {
+        let builder = || VoronoiBuilder::default().set_sites(sites()).set_bounding_box(BoundingBox::new_centered(100.0, 80.0));
+
+        let relaxed = relaxed_voronoi(builder(), 5, &NoProgress).expect("Failed to create a voronoi diagram");
+        let expected = builder()
+            .set_lloyd_relaxation_iterations(5)
+            .build()
+            .expect("Failed to create a voronoi diagram");
+        assert_eq!(relaxed.sites(), expected.sites());
+    }
+
+    #[test]
+    fn test_unwrapped_diagram_matches_relaxed_voronoi() {
+        let tiled = TiledDiagram::relaxed(sites(), 100.0, 80.0, WrapMode::None, 3, &NoProgress)
+            .expect("Failed to create a voronoi diagram");
+        let builder = VoronoiBuilder::default().set_sites(sites()).set_bounding_box(BoundingBox::new_centered(100.0, 80.0));
+        let expected = relaxed_voronoi(builder, 3, &NoProgress).expect("Failed to create a voronoi diagram");
+
+        assert_eq!(tiled.sites(), expected.sites());
+        for cell_index in 0..tiled.site_count() {
+            let neighbors: Vec<usize> = tiled.neighbors(cell_index).collect();
+            assert_eq!(neighbors, expected.cell(cell_index).iter_neighbors().collect::<Vec<usize>>());
+        }
+    }
+
+    #[test]
+    fn test_wrapped_cells_reach_across_the_seam() {
+        for wrap in [WrapMode::Horizontal, WrapMode::Torus] {
+            let tiled = TiledDiagram::relaxed(sites(), 100.0, 80.0, wrap, 3, &NoProgress)
+                .expect("Failed to create a voronoi diagram");
+
+            assert_eq!(tiled.site_count(), 60);
+            assert!(tiled.sites().iter().all(|site| site.x.abs() <= 50.0 && site.y.abs() <= 40.0));
+            let total_area: f64 = (0..tiled.site_count()).map(|cell_index| shoelace_area_of_cell(tiled.cell(cell_index))).sum();
+            assert!((total_area - 8000.0).abs() < 1e-6, "{wrap:?} {total_area}");
+
+            // Some cell on the left edge touches one on the right edge.
+            let crosses = (0..tiled.site_count()).any(|cell_index| {
+                tiled.sites()[cell_index].x < -30.0
+                    && tiled.neighbors(cell_index).any(|neighbor| tiled.sites()[neighbor].x > 30.0)
+            });
+            assert!(crosses, "{wrap:?}");
+            for cell_index in 0..tiled.site_count() {
+                for neighbor in tiled.neighbors(cell_index) {
+                    assert!(tiled.neighbors(neighbor).any(|back| back == cell_index));
+                    let (x, y) = tiled.offset(&tiled.sites()[cell_index], &tiled.sites()[neighbor]);
+                    assert!(x.abs() <= 50.0 && y.abs() <= 40.0);
+                }
+            }
+        }
+    }
+
+    /// Cancels right away.
+    struct Cancelled;
+
+    impl ProgressSink for Cancelled {
+        fn report(&self, _phase: &str, _completed: usize, _total: usize) {}
+
+        fn is_cancelled(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_cancelling_fails_the_relaxation() {
+        for wrap in [WrapMode::None, WrapMode::Horizontal] {
+            let result = TiledDiagram::relaxed(sites(), 100.0, 80.0, wrap, 3, &Cancelled);
+            assert!(matches!(result, Err(DiagramError::Cancelled)), "{wrap:?}");
+        }
+        assert!(TiledDiagram::relaxed(sites(), 100.0, 80.0, WrapMode::None, 0, &Cancelled).is_ok());
+    }
+}
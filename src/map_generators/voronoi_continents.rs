@@ -1,18 +1,27 @@
 //use core::num;
 
+use crate::map_generators::continent_growth::{ContinentGrowth, GrowthMap};
+use crate::map_generators::domain_warp::{DomainWarp, DomainWarpOptions};
+use crate::map_generators::grid::GridTopology;
+use crate::map_generators::stencil::StencilOptions;
+use crate::map_generators::parallel::par_from_fn_with_progress;
+use crate::map_generators::progress::{NoProgress, ProgressSink};
 use crate::map_generators::tile::Tile;
+use crate::map_generators::tiled_diagram::{DiagramError, TiledDiagram};
 use crate::map_generators::tile_types::TileType;
 use crate::math_helpers::*;
+use crate::map_generators::map_generator::{MapGenerator, MapGeneratorError};
+use crate::map_generators::pipeline::MapPass;
+use bevy::log::{debug, info_span, warn};
 use nalgebra::DMatrix;
 use rand::prelude::*;
 use rand_pcg::Pcg64;
 use rand_seeder::Seeder;
+use serde::{Deserialize, Serialize};
 
 use voronoice::*;
 
-use std::collections::HashSet;
-
-use fastnoise_lite::*;
+use std::collections::{BTreeMap, BTreeSet};
 
 use thiserror::Error;
 use rand::distributions::WeightedError;
@@ -23,191 +32,845 @@ pub enum VoronoiContinentError {
     DiagramCreationError,
     #[error(transparent)]
     DiagramChooseError(#[from] WeightedError),
+    #[error("Can't generate a {width}x{height} map")]
+    EmptyMap { width: usize, height: usize },
+    #[error("A map needs at least one continent")]
+    NoContinents,
+    #[error("Continent {continent} has a weight of {weight}, weights have to be finite and not negative")]
+    InvalidContinentWeight { continent: usize, weight: f64 },
+    #[error("Can't place {requested} continents in {available} land cells")]
+    TooManyContinents { requested: usize, available: usize },
+    #[error("Continent generation was cancelled")]
+    Cancelled,
+    #[error("Continent {continent} has no cells")]
+    EmptyContinent { continent: usize },
+    #[error("Cell {cell_index} has fewer than three vertices")]
+    DegenerateCell { cell_index: usize },
 }
 
+impl From<DiagramError> for VoronoiContinentError {
+    fn from(error: DiagramError) -> Self {
+        match error {
+            DiagramError::CreationFailed => VoronoiContinentError::DiagramCreationError,
+            DiagramError::Cancelled => VoronoiContinentError::Cancelled,
+        }
+    }
+}
 
+/// Area of a diagram cell, which always has to be a polygon.
+fn cell_area(diagram: &TiledDiagram, cell_index: usize) -> Result<f64, VoronoiContinentError> {
+    let cell = diagram.cell(cell_index);
+    let vertices: Vec<&Point> = cell.iter_vertices().collect();
+    if vertices.len() < 3 {
+        return Err(VoronoiContinentError::DegenerateCell { cell_index });
+    }
+    Ok(shoelace_area(vertices))
+}
+
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct ContinentOptions {
-    total_area: usize,
+    map_width: usize,
+    map_height: usize,
+    seed: String,
+    cell_count: usize,
     land_area_percentage: f64,
     num_continents: usize,
     water_padding_percentage: f64,
+    lloyd_iterations: usize,
+    growth: ContinentGrowth,
+    continent_weights: Vec<f64>,
+    ocean_gap: bool,
+    warp: DomainWarpOptions,
+    stencil: Option<StencilOptions>,
 }
 
 impl ContinentOptions {
-    pub fn new(total_area: usize, land_area_percentage: f64, num_continents: usize, water_padding_percentage: f64) -> ContinentOptions {
+    pub fn new(map_width: usize, map_height: usize, seed: String, cell_count: usize) -> ContinentOptions {
         ContinentOptions {
-            total_area,
-            land_area_percentage,
-            num_continents,
-            water_padding_percentage: (100.0 - water_padding_percentage) / 100.0,
+            map_width,
+            map_height,
+            seed,
+            cell_count,
+            land_area_percentage: 29.0,
+            num_continents: 8,
+            water_padding_percentage: (100.0 - 7.0) / 100.0,
+            lloyd_iterations: 30,
+            growth: ContinentGrowth::Random,
+            continent_weights: Vec::new(),
+            ocean_gap: false,
+            warp: DomainWarpOptions::new(),
+            stencil: None,
         }
     }
+
+    pub fn cell_count(&self) -> usize {
+        self.cell_count
+    }
+
+    pub fn land_area_percentage(&self) -> f64 {
+        self.land_area_percentage
+    }
+
+    pub fn set_land_area_percentage(&mut self, land_area_percentage: f64) {
+        self.land_area_percentage = land_area_percentage;
+    }
+
+    pub fn num_continents(&self) -> usize {
+        self.num_continents
+    }
+
+    pub fn set_num_continents(&mut self, num_continents: usize) {
+        self.num_continents = num_continents;
+    }
+
+    /// Percentage of the map along the edges that stays water, as passed to
+    /// `set_water_padding_percentage`.
+    pub fn water_padding_percentage(&self) -> f64 {
+        100.0 - self.water_padding_percentage * 100.0
+    }
+
+    pub fn set_water_padding_percentage(&mut self, water_padding_percentage: f64) {
+        self.water_padding_percentage = (100.0 - water_padding_percentage) / 100.0;
+    }
+
+    pub fn lloyd_iterations(&self) -> usize {
+        self.lloyd_iterations
+    }
+
+    pub fn set_lloyd_iterations(&mut self, lloyd_iterations: usize) {
+        self.lloyd_iterations = lloyd_iterations;
+    }
+
+    pub fn growth(&self) -> ContinentGrowth {
+        self.growth
+    }
+
+    pub fn set_growth(&mut self, growth: ContinentGrowth) {
+        self.growth = growth;
+    }
+
+    /// Relative sizes of the continents, ordered by the index of the cell
+    /// they start from. Continents past the end get a weight of `1.0`, so
+    /// leaving this empty grows them all equally, and a continent with a
+    /// weight of `0.0` keeps its starting cell only.
+    pub fn set_continent_weights(&mut self, continent_weights: Vec<f64>) {
+        self.continent_weights = continent_weights;
+    }
+
+    /// Keeps at least one cell of water between continents so they never
+    /// merge.
+    pub fn set_ocean_gap(&mut self, ocean_gap: bool) {
+        self.ocean_gap = ocean_gap;
+    }
+
+    pub fn ocean_gap(&self) -> bool {
+        self.ocean_gap
+    }
+
+    /// Warp applied to the coastlines, so they don't follow the straight
+    /// cell edges.
+    pub fn set_warp(&mut self, warp: DomainWarpOptions) {
+        self.warp = warp;
+    }
+
+    pub fn warp(&self) -> &DomainWarpOptions {
+        &self.warp
+    }
+
+    /// A sketch of where the continents should be. Continents start and
+    /// grow more readily where the sketch is bright.
+    pub fn set_stencil(&mut self, stencil: Option<StencilOptions>) {
+        self.stencil = stencil;
+    }
+
+    pub fn stencil(&self) -> Option<&StencilOptions> {
+        self.stencil.as_ref()
+    }
+
+    fn total_area(&self) -> usize {
+        self.map_width * self.map_height
+    }
+}
+
+impl Default for ContinentOptions {
+    fn default() -> Self {
+        ContinentOptions::new(1920, 1080, "Initial Seed".to_string(), 120)
+    }
+}
+
+pub struct VoronoiContinentsGenerator {
+    options: ContinentOptions,
+}
+
+impl MapGenerator for VoronoiContinentsGenerator {
+    type Options = ContinentOptions;
+
+    fn new(options: ContinentOptions) -> VoronoiContinentsGenerator {
+        VoronoiContinentsGenerator { options }
+    }
+
+    fn name(&self) -> &'static str {
+        "voronoi_continents"
+    }
+
+    fn options(&self) -> &ContinentOptions {
+        &self.options
+    }
+
+    fn generate_on_grid(&self, topology: GridTopology, progress: &dyn ProgressSink) -> Result<DMatrix<Tile>, MapGeneratorError> {
+        Ok(voronoi_continents_with_progress(&self.options, topology, progress)?)
+    }
 }
 
+impl MapPass for VoronoiContinentsGenerator {
+    fn name(&self) -> &str {
+        MapGenerator::name(self)
+    }
+
+    fn apply(
+        &self,
+        tiles: DMatrix<Tile>,
+        topology: GridTopology,
+        seeder: &mut Seeder,
+        progress: &dyn ProgressSink,
+    ) -> Result<DMatrix<Tile>, MapGeneratorError> {
+        let mut options = self.options.clone();
+        options.map_width = tiles.nrows();
+        options.map_height = tiles.ncols();
+        options.seed = create_new_seed64(seeder).to_string();
+        Ok(voronoi_continents_with_progress(&options, topology, progress)?)
+    }
+}
+
+/// Ordered sets, so the cells picked while growing the continents only
+/// depend on the seed.
 pub struct Continents {
-    pub all_continent_cells: HashSet<usize>,
-    pub continents: Vec<HashSet<usize>>,
-    pub initial_cells: HashSet<usize>,
+    pub all_continent_cells: BTreeSet<usize>,
+    pub continents: Vec<BTreeSet<usize>>,
+    pub initial_cells: BTreeSet<usize>,
+    /// Land actually grown. Falls short of the target when the continents
+    /// run out of room inside the water padding.
+    pub land_area_percentage: f64,
 }
 impl Continents {
-    fn new(all_continent_cells: HashSet<usize>, continents: Vec<HashSet<usize>>, initial_cells: HashSet<usize>) -> Continents {
+    fn new(
+        all_continent_cells: BTreeSet<usize>,
+        continents: Vec<BTreeSet<usize>>,
+        initial_cells: BTreeSet<usize>,
+        land_area_percentage: f64,
+    ) -> Continents {
         Continents {
             all_continent_cells,
             continents,
             initial_cells,
+            land_area_percentage,
         }
     }
 }
 
-pub fn make_continent_cells(diagram: &Voronoi, options: &ContinentOptions, rng: &mut Pcg64) -> Result<Continents, VoronoiContinentError> {
-    let land_box = land_box(&diagram.bounding_box(), options.water_padding_percentage);
+
+pub fn make_continent_cells(
+    diagram: &TiledDiagram,
+    options: &ContinentOptions,
+    rng: &mut Pcg64,
+    progress: &dyn ProgressSink,
+) -> Result<Continents, VoronoiContinentError> {
+    let land_box = diagram.land_box(options.water_padding_percentage);
     
-    let all_cells: Vec<VoronoiCell> = diagram.iter_cells().collect();
+    let land_probability: Option<Vec<f64>> = options.stencil.as_ref().map(|stencil| {
+        diagram
+            .sites()
+            .iter()
+            .map(|site| {
+                stencil.land_probability(
+                    site.x / options.map_width as f64 + 0.5,
+                    site.y / options.map_height as f64 + 0.5,
+                )
+            })
+            .collect()
+    });
+
+    if let Some((continent, &weight)) =
+        options.continent_weights.iter().enumerate().find(|(_, weight)| !(weight.is_finite() && **weight >= 0.0))
+    {
+        return Err(VoronoiContinentError::InvalidContinentWeight { continent, weight });
+    }
+    let weight = |continent: usize| options.continent_weights.get(continent).copied().unwrap_or(1.0);
+
+    let all_cells: Vec<VoronoiCell> = (0..diagram.site_count()).map(|cell_index| diagram.cell(cell_index)).collect();
+    let available = all_cells.iter().filter(|cell| is_cell_in_box(cell, &land_box)).count();
+    if options.num_continents == 0 {
+        return Err(VoronoiContinentError::NoContinents);
+    }
+    if options.num_continents > available {
+        return Err(VoronoiContinentError::TooManyContinents { requested: options.num_continents, available });
+    }
     let initial_continent_cells = all_cells.choose_multiple_weighted(rng, options.num_continents, |cell| {
         if is_cell_in_box(cell, &land_box) {
-            1.0
+            land_probability.as_ref().map_or(1.0, |land_probability| land_probability[cell.site()])
         } else {
             0.0
         }
     }).map_err(VoronoiContinentError::DiagramChooseError)?;
-    let initial_continent_cells: HashSet<usize> = initial_continent_cells.map(|cell| cell.site()).collect();
+    let initial_continent_cells: BTreeSet<usize> = initial_continent_cells.map(|cell| cell.site()).collect();
 
-    let initial_cells: HashSet<usize> = initial_continent_cells.iter().copied().collect();
+    let initial_cells: BTreeSet<usize> = initial_continent_cells.iter().copied().collect();
 
-    let mut used_cells: HashSet<usize> = initial_continent_cells.into_iter().collect();
+    let mut used_cells: BTreeSet<usize> = initial_continent_cells.into_iter().collect();
 
-    let mut continents: Vec<HashSet<usize>> = used_cells
+    let mut continents: Vec<BTreeSet<usize>> = used_cells
         .iter()
-        .map(|cell| HashSet::from([*cell]))
+        .map(|cell| BTreeSet::from([*cell]))
         .collect();
+    let origins: Vec<usize> = used_cells.iter().copied().collect();
+    let mut growth_map = GrowthMap::new(diagram, &land_box, options.ocean_gap);
+    if let Some(land_probability) = land_probability {
+        growth_map.set_land_probability(land_probability);
+    }
+    for (continent, origin) in origins.iter().enumerate() {
+        growth_map.claim(*origin, continent);
+    }
 
-    let mut land_area: f64 = continents
-        .iter()
-        .map(|continent_cells| {
-            continent_cells
-                .iter()
-                .map(|cell_index| shoelace_area_of_cell(diagram.cell(*cell_index)))
-                .sum::<f64>()
-        })
-        .sum();
+    let mut land_area = 0.0;
+    for cell_index in &used_cells {
+        land_area += cell_area(diagram, *cell_index)?;
+    }
 
-    let mut land_area_percentage: f64 = (land_area / options.total_area as f64) * 100.0;
+    let mut land_area_percentage: f64 = (land_area / options.total_area() as f64) * 100.0;
 
+    // Progress is counted in hundredths of a percent of land.
+    let land_target = (options.land_area_percentage * 100.0) as usize;
+    // Continents that are boxed in by other continents and the water
+    // padding drop out of this, and growing stops once none are left.
+    // Continents weighted zero never take part.
+    let mut growing: Vec<usize> = (0..continents.len()).filter(|continent| weight(*continent) > 0.0).collect();
     while land_area_percentage <= options.land_area_percentage {
-        let continent_cell_set = continents.choose_mut(rng);
-        match continent_cell_set {
-            Some(cell_set) => {
-                let cell_index = cell_set.iter().choose(rng);
-                match cell_index {
-                    Some(index) => {
-                        let cell = diagram.cell(*index);
-                        let mut neighbors: Vec<usize> = cell.iter_neighbors().collect();
-                        neighbors.shuffle(rng);
-
-                        for neighbor in neighbors {
-                            let neighbor_cell = diagram.cell(neighbor);
-                            if !used_cells.contains(&neighbor) && land_box.is_inside(neighbor_cell.site_position()){
-                                used_cells.insert(neighbor);
-                                cell_set.insert(neighbor);
-                                let cell_area =
-                                    shoelace_area_of_cell(diagram.cell(neighbor));
-                                land_area += cell_area;
-                                land_area_percentage = (land_area / options.total_area as f64) * 100.0;
-                                break;
-                            }
-                        }
-                    }
-                    None => {
-                        println!("somehow we got an empty continent cell")
-                    }
-                }
+        if progress.is_cancelled() {
+            return Err(VoronoiContinentError::Cancelled);
+        }
+        progress.report("continent_growth", (land_area_percentage * 100.0) as usize, land_target);
+        let continent = if options.continent_weights.is_empty() {
+            growing.choose(rng)
+        } else if growing.is_empty() {
+            None
+        } else {
+            Some(growing.choose_weighted(rng, |continent| weight(*continent))?)
+        };
+        let Some(&continent) = continent else {
+            warn!(
+                target = options.land_area_percentage,
+                achieved = land_area_percentage,
+                "Continents ran out of room before reaching the land target"
+            );
+            break;
+        };
+        // Continents start with one cell each and only ever grow.
+        let cell_set = &mut continents[continent];
+        if cell_set.is_empty() {
+            return Err(VoronoiContinentError::EmptyContinent { continent });
+        }
+
+        match growth_map.next_cell(options.growth, cell_set, continent, origins[continent], rng) {
+            Some(neighbor) => {
+                growth_map.claim(neighbor, continent);
+                used_cells.insert(neighbor);
+                cell_set.insert(neighbor);
+                land_area += cell_area(diagram, neighbor)?;
+                land_area_percentage = (land_area / options.total_area() as f64) * 100.0;
             }
             None => {
-                println!("somehow we got an empty continent set")
+                if !growth_map.can_grow(cell_set, continent) {
+                    growing.retain(|index| *index != continent);
+                }
             }
         }
     }
+    debug!(land_area_percentage, cells = used_cells.len(), "Grew continents");
 
-    Ok(Continents::new(used_cells, continents, initial_cells))
+    Ok(Continents::new(used_cells, continents, initial_cells, land_area_percentage))
 
 }
 
-pub fn voronoi_continents(
-    map_size_x: usize,
-    map_size_y: usize,
-    seeder: &mut Seeder,
-    cell_count: usize,
-) -> Result<Box<DMatrix<Tile>>, VoronoiContinentError> {
+/// Statistics of one continent, in tile coordinates. On maps that wrap, a
+/// continent that crosses a seam is measured in one piece, so its bounds can
+/// reach past the edges of the map.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ContinentInfo {
+    /// Index into [`ContinentMap::continents`], also used in
+    /// [`ContinentMap::continent_ids`].
+    pub id: usize,
+    /// Diagram cell the continent grew from.
+    pub seed_cell: usize,
+    pub cell_count: usize,
+    /// Area of the continent's cells, before the coast gets warped.
+    pub area: f64,
+    pub min_x: f64,
+    pub min_y: f64,
+    pub max_x: f64,
+    pub max_y: f64,
+    pub centroid_x: f64,
+    pub centroid_y: f64,
+    /// Length of the cell edges between the continent and the sea.
+    pub coastline_length: f64,
+}
+
+/// Tiles of a continents map with the continent every tile belongs to.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ContinentMap {
+    pub tiles: DMatrix<Tile>,
+    /// `None` for water.
+    pub continent_ids: DMatrix<Option<usize>>,
+    pub continents: Vec<ContinentInfo>,
+}
+
+/// Picks for every cell of a continent the copy in the tiled diagram that
+/// lies next to the rest of the continent, walking out from `seed_cell`.
+/// Without wrapping every cell is its own copy.
+fn contiguous_copies(diagram: &TiledDiagram, cells: &BTreeSet<usize>, seed_cell: usize) -> BTreeMap<usize, usize> {
+    let mut copies = BTreeMap::from([(seed_cell, seed_cell)]);
+    let mut queue = vec![seed_cell];
+    while let Some(copy) = queue.pop() {
+        for neighbor in diagram.cell(copy).iter_neighbors() {
+            let own_cell = diagram.own_cell(neighbor);
+            if cells.contains(&own_cell) && !copies.contains_key(&own_cell) {
+                copies.insert(own_cell, neighbor);
+                queue.push(neighbor);
+            }
+        }
+    }
+    // Cells only connected through the far edge of the copies.
+    for cell_index in cells {
+        copies.entry(*cell_index).or_insert(*cell_index);
+    }
+    copies
+}
+
+fn continent_info(
+    diagram: &TiledDiagram,
+    continents: &Continents,
+    owners: &[Option<usize>],
+    half_x: f64,
+    half_y: f64,
+) -> Result<Vec<ContinentInfo>, VoronoiContinentError> {
+    let mut infos = Vec::with_capacity(continents.continents.len());
+    for (id, (cells, seed_cell)) in continents.continents.iter().zip(&continents.initial_cells).enumerate() {
+        let mut area = 0.0;
+        let (mut centroid_x, mut centroid_y) = (0.0, 0.0);
+        let (mut min_x, mut min_y) = (f64::INFINITY, f64::INFINITY);
+        let (mut max_x, mut max_y) = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+        let mut coastline_length = 0.0;
+
+        let copies = contiguous_copies(diagram, cells, *seed_cell);
+        for cell_index in cells {
+            let copy = copies[cell_index];
+            let cell = diagram.cell(copy);
+            let vertices: Vec<&Point> = cell.iter_vertices().collect();
+            let cell_area = cell_area(diagram, copy)?;
+            let cell_centroid = polygon_centroid(&vertices);
+            area += cell_area;
+            centroid_x += cell_centroid.x * cell_area;
+            centroid_y += cell_centroid.y * cell_area;
+            for vertex in &vertices {
+                min_x = min_x.min(vertex.x);
+                min_y = min_y.min(vertex.y);
+                max_x = max_x.max(vertex.x);
+                max_y = max_y.max(vertex.y);
+            }
+
+            for neighbor in cell.iter_neighbors().filter(|neighbor| owners[diagram.own_cell(*neighbor)].is_none()) {
+                let neighbor_cell = diagram.cell(neighbor);
+                let shared: Vec<usize> = cell
+                    .triangles()
+                    .iter()
+                    .filter(|vertex| neighbor_cell.triangles().contains(vertex))
+                    .copied()
+                    .collect();
+                if let [start, end] = shared[..] {
+                    coastline_length += distance(&diagram.diagram().vertices()[start], &diagram.diagram().vertices()[end]);
+                }
+            }
+        }
+
+        let centroid = diagram.wrap_point(Point { x: centroid_x / area, y: centroid_y / area });
+        infos.push(ContinentInfo {
+            id,
+            seed_cell: *seed_cell,
+            cell_count: cells.len(),
+            area,
+            min_x: min_x + half_x,
+            min_y: min_y + half_y,
+            max_x: max_x + half_x,
+            max_y: max_y + half_y,
+            centroid_x: centroid.x + half_x,
+            centroid_y: centroid.y + half_y,
+            coastline_length,
+        });
+    }
+    Ok(infos)
+}
+
+pub fn voronoi_continents(options: &ContinentOptions) -> Result<DMatrix<Tile>, VoronoiContinentError> {
+    voronoi_continents_with_progress(options, GridTopology::SQUARE, &NoProgress)
+}
+
+pub fn voronoi_continents_with_progress(
+    options: &ContinentOptions,
+    topology: GridTopology,
+    progress: &dyn ProgressSink,
+) -> Result<DMatrix<Tile>, VoronoiContinentError> {
+    Ok(voronoi_continent_map(options, topology, progress)?.tiles)
+}
+
+/// Generates the continents and keeps track of which tile belongs to which
+/// of them.
+pub fn voronoi_continent_map(
+    options: &ContinentOptions,
+    topology: GridTopology,
+    progress: &dyn ProgressSink,
+) -> Result<ContinentMap, VoronoiContinentError> {
+    let _span = info_span!("voronoi_continents", seed = options.seed.as_str()).entered();
+    let map_size_x = options.map_width;
+    let map_size_y = options.map_height;
+    if map_size_x == 0 || map_size_y == 0 {
+        return Err(VoronoiContinentError::EmptyMap { width: map_size_x, height: map_size_y });
+    }
     let half_x = map_size_x as f64 / 2.0;
     let half_y = map_size_y as f64 / 2.0;
+    let mut seeder = Seeder::from(options.seed.as_str());
     let mut rng: Pcg64 = seeder.make_rng();
 
     let x_range = rand::distributions::Uniform::new(-half_x, half_x);
     let y_range = rand::distributions::Uniform::new(-half_y, half_y);
-    let sites: Vec<Point> = (0..cell_count)
+    let sites: Vec<Point> = (0..options.cell_count)
         .map(|_| Point {
             x: rng.sample(x_range),
             y: rng.sample(y_range),
         })
         .collect();
  
-    let voronoi_diagram = VoronoiBuilder::default()
-        .set_sites(sites)
-        .set_bounding_box(BoundingBox::new_centered(
-            map_size_x as f64,
-            map_size_y as f64,
-        ))
-        .set_lloyd_relaxation_iterations(30)
-        .build()
-        .ok_or(VoronoiContinentError::DiagramCreationError)?;
-
-    let total_area = map_size_x * map_size_y;
-    let ideal_land_area_percentage_lower_bound = 29.0;
-    let num_continents: usize = 8;
-
-    let options = ContinentOptions::new(total_area, ideal_land_area_percentage_lower_bound, num_continents, 7.0);
-    let continents = make_continent_cells(&voronoi_diagram, &options, &mut rng)?;
-
-    let noise_x_seed: [u8; 4] = seeder.make_seed();
-    let noise_x_seed = u32::from_be_bytes(noise_x_seed);
-
-    let mut noise_x = FastNoiseLite::with_seed(noise_x_seed as i32);
-    noise_x.set_noise_type(Some(NoiseType::OpenSimplex2));
-    noise_x.set_fractal_type(Some(FractalType::FBm));
-    noise_x.set_fractal_octaves(Some(2));
-
-    let noise_y_seed: [u8; 4] = seeder.make_seed();
-    let noise_y_seed = u32::from_be_bytes(noise_y_seed);
-    let mut noise_y = FastNoiseLite::with_seed(noise_y_seed as i32);
-    noise_y.set_noise_type(Some(NoiseType::OpenSimplex2));
-    noise_y.set_fractal_type(Some(FractalType::FBm));
-    noise_y.set_fractal_octaves(Some(2));
-
-
-    Ok(Box::new(DMatrix::from_fn(map_size_x, map_size_y, |row, column| {
-        let row_x: f64 = (row as f64) - half_x;
-        let column_y: f64 = (column as f64) - half_y;
-
-        let noise_x_value = noise_x.get_noise_2d(row_x as f32, column_y as f32);
-        let noise_y_value = noise_y.get_noise_2d(row_x as f32, column_y as f32);
-        let point_x = row_x + noise_x_value as f64;
-        let point_y = column_y + noise_y_value as f64;
+    let voronoi_diagram = TiledDiagram::relaxed(
+        sites,
+        map_size_x as f64,
+        map_size_y as f64,
+        topology.wrap(),
+        options.lloyd_iterations,
+        progress,
+    )?;
+
+    let continents = make_continent_cells(&voronoi_diagram, options, &mut rng, progress)?;
+
+    let noise_x_seed = create_new_seed32(&mut seeder);
+    let noise_y_seed = create_new_seed32(&mut seeder);
+    let mut warp = DomainWarp::new(&options.warp, noise_x_seed, noise_y_seed);
+    warp.set_wrap(topology.wrap(), map_size_x as f64, map_size_y as f64);
+
+    let mut owners = vec![None; voronoi_diagram.site_count()];
+    for (continent, cells) in continents.continents.iter().enumerate() {
+        for cell_index in cells {
+            owners[*cell_index] = Some(continent);
+        }
+    }
+    let infos = continent_info(&voronoi_diagram, &continents, &owners, half_x, half_y)?;
+
+    let closest_cells = ClosestCellIndex::new(voronoi_diagram.diagram());
+    let tiles = par_from_fn_with_progress(map_size_x, map_size_y, "tiles", progress, |row, column| {
+        let (center_x, center_y) = topology.tile_center(row, column);
+        let row_x: f64 = center_x - half_x;
+        let column_y: f64 = center_y - half_y;
+
+        let (point_x, point_y) = warp.warp(row_x, column_y);
 
         let point = Point {
             x: point_x,
             y: point_y,
         };
-        let closest_index = closest_cell(&point, &voronoi_diagram);
-        let closest_cell = voronoi_diagram.cell(closest_index);
+        let closest_index = voronoi_diagram.own_cell(closest_cells.closest_cell(&point));
 
-        if continents.all_continent_cells.contains(&closest_cell.site()) {
-            Tile::new(TileType::Grassland, 0.0)
-        } else {
-            Tile::new(TileType::Water, 0.0)
+        match owners[closest_index] {
+            Some(continent) => (Tile::new(TileType::Grassland, 0.0), Some(continent)),
+            None => (Tile::new(TileType::Water, 0.0), None),
         }
-    })))
+    })
+    .ok_or(VoronoiContinentError::Cancelled)?;
+
+    Ok(ContinentMap {
+        tiles: tiles.map(|(tile, _)| tile),
+        continent_ids: tiles.map(|(_, continent)| continent),
+        continents: infos,
+    })
 }
 
 #[cfg(test)]
 mod tests {
+    use super::{
+        cell_area, make_continent_cells, voronoi_continent_map, voronoi_continents, ContinentOptions, VoronoiContinentError,
+    };
+    use crate::map_generators::{
+        ContinentGrowth, DomainWarpOptions, GrayscaleMask, GridTopology, NoProgress, StencilOptions, TileType, TiledDiagram,
+        WrapMode,
+    };
+    use image::GrayImage;
+    use rand_pcg::Pcg64;
+    use rand_seeder::Seeder;
+    use voronoice::{BoundingBox, Point, VoronoiBuilder};
+
+    fn test_diagram() -> TiledDiagram {
+        let sites: Vec<Point> = (0..30)
+            .map(|index| Point {
+                x: ((index * 37) % 100) as f64 - 50.0,
+                y: ((index * 61) % 80) as f64 - 40.0,
+            })
+            .collect();
+        VoronoiBuilder::default()
+            .set_sites(sites)
+            .set_bounding_box(BoundingBox::new_centered(100.0, 80.0))
+            .build()
+            .expect("Failed to create a voronoi diagram")
+            .into()
+    }
+
+    #[test]
+    fn test_voronoi_continents_has_land_and_water() {
+        let mut options = ContinentOptions::new(80, 60, "continent test".to_string(), 60);
+        options.set_num_continents(3);
+        options.set_lloyd_iterations(5);
+        let tiles = voronoi_continents(&options).expect("Failed to generate continents");
+
+        assert_eq!(tiles.shape(), (80, 60));
+        assert!(tiles.iter().any(|tile| tile.terrain() == TileType::Grassland));
+        assert!(tiles.iter().any(|tile| tile.terrain() == TileType::Water));
+    }
+
+    #[test]
+    fn test_voronoi_continents_too_many_continents() {
+        let mut options = ContinentOptions::new(80, 60, "continent test".to_string(), 20);
+        options.set_num_continents(100);
+        options.set_lloyd_iterations(0);
+        let result = voronoi_continents(&options);
+
+        assert!(matches!(result, Err(VoronoiContinentError::TooManyContinents { requested: 100, .. })));
+    }
+
+    #[test]
+    fn test_voronoi_continents_no_continents() {
+        let mut options = ContinentOptions::new(80, 60, "continent test".to_string(), 20);
+        options.set_num_continents(0);
+        options.set_lloyd_iterations(0);
+
+        assert!(matches!(voronoi_continents(&options), Err(VoronoiContinentError::NoContinents)));
+    }
+
+    #[test]
+    fn test_voronoi_continents_empty_map() {
+        for (width, height) in [(0, 60), (80, 0)] {
+            let options = ContinentOptions::new(width, height, "continent test".to_string(), 20);
+            let result = voronoi_continents(&options);
+            assert!(matches!(result, Err(VoronoiContinentError::EmptyMap { width: w, height: h }) if w == width && h == height));
+        }
+    }
+
+    #[test]
+    fn test_cell_areas_cover_the_diagram() {
+        let diagram = test_diagram();
+
+        let total: f64 = (0..diagram.sites().len())
+            .map(|cell_index| cell_area(&diagram, cell_index).expect("Every cell is a polygon"))
+            .sum();
+        assert!((total - 100.0 * 80.0).abs() < 1e-6, "{total}");
+    }
+
+    #[test]
+    fn test_unreachable_land_target_stops_growing() {
+        let diagram = test_diagram();
+        let mut options = ContinentOptions::new(100, 80, "boxed in".to_string(), 30);
+        options.set_num_continents(2);
+        options.set_land_area_percentage(100.0);
+        options.set_water_padding_percentage(40.0);
+        let mut rng: Pcg64 = Seeder::from("boxed in").make_rng();
+
+        let continents =
+            make_continent_cells(&diagram, &options, &mut rng, &NoProgress).expect("Failed to grow continents");
+        assert!(continents.land_area_percentage > 0.0);
+        assert!(continents.land_area_percentage < 100.0);
+        assert!(continents.all_continent_cells.len() < diagram.sites().len());
+    }
+
+    #[test]
+    fn test_growth_strategies_reach_the_land_target() {
+        let diagram = test_diagram();
+        let strategies = [
+            ContinentGrowth::Random,
+            ContinentGrowth::Frontier,
+            ContinentGrowth::Directional { angle: 30.0, elongation: 8.0 },
+        ];
+
+        for growth in strategies {
+            for ocean_gap in [false, true] {
+                let mut options = ContinentOptions::new(100, 80, "growth".to_string(), 30);
+                options.set_num_continents(2);
+                options.set_land_area_percentage(25.0);
+                options.set_growth(growth);
+                options.set_ocean_gap(ocean_gap);
+                let mut rng: Pcg64 = Seeder::from("growth").make_rng();
+
+                let continents =
+                    make_continent_cells(&diagram, &options, &mut rng, &NoProgress).expect("Failed to grow continents");
+                assert!(continents.land_area_percentage > 25.0, "{growth:?} {ocean_gap}");
+            }
+        }
+    }
+
+    fn weights_diagram() -> TiledDiagram {
+        VoronoiBuilder::default()
+            .set_sites(
+                (0..300)
+                    .map(|index| Point {
+                        x: ((index * 37) % 200) as f64 - 100.0 + (index % 7) as f64 * 0.3,
+                        y: ((index * 61) % 150) as f64 - 75.0 + (index % 5) as f64 * 0.3,
+                    })
+                    .collect(),
+            )
+            .set_bounding_box(BoundingBox::new_centered(200.0, 150.0))
+            .build()
+            .expect("Failed to create a voronoi diagram")
+            .into()
+    }
+
+    fn weighted_options(continent_weights: Vec<f64>) -> ContinentOptions {
+        let mut options = ContinentOptions::new(200, 150, "weights".to_string(), 300);
+        options.set_num_continents(2);
+        options.set_land_area_percentage(30.0);
+        options.set_growth(ContinentGrowth::Frontier);
+        options.set_ocean_gap(true);
+        options.set_continent_weights(continent_weights);
+        options
+    }
+
+    #[test]
+    fn test_continent_weights_set_relative_sizes() {
+        let mut rng: Pcg64 = Seeder::from("weights").make_rng();
+        let continents = make_continent_cells(&weights_diagram(), &weighted_options(vec![6.0, 1.0]), &mut rng, &NoProgress)
+            .expect("Failed to grow continents");
+        assert!(continents.continents[0].len() > 2 * continents.continents[1].len());
+    }
+
+    #[test]
+    fn test_zero_weight_continents_stay_put() {
+        let diagram = weights_diagram();
+        let mut rng: Pcg64 = Seeder::from("weights").make_rng();
+        let continents = make_continent_cells(&diagram, &weighted_options(vec![0.0, 1.0]), &mut rng, &NoProgress)
+            .expect("Failed to grow continents");
+        assert_eq!(continents.continents[0].len(), 1);
+        assert!(continents.continents[1].len() > 1);
+
+        let mut rng: Pcg64 = Seeder::from("weights").make_rng();
+        let continents = make_continent_cells(&diagram, &weighted_options(vec![0.0, 0.0]), &mut rng, &NoProgress)
+            .expect("Failed to grow continents");
+        assert!(continents.continents.iter().all(|continent| continent.len() == 1));
+    }
+
+    #[test]
+    fn test_rejects_invalid_continent_weights() {
+        let diagram = weights_diagram();
+        for weight in [-1.0, f64::NAN, f64::INFINITY] {
+            let mut rng: Pcg64 = Seeder::from("weights").make_rng();
+            let result = make_continent_cells(&diagram, &weighted_options(vec![1.0, weight]), &mut rng, &NoProgress);
+            assert!(matches!(result, Err(VoronoiContinentError::InvalidContinentWeight { continent: 1, .. })));
+        }
+    }
+
+    #[test]
+    fn test_warp_moves_coastlines() {
+        let mut options = ContinentOptions::new(80, 60, "warp".to_string(), 60);
+        options.set_lloyd_iterations(5);
+        let mut straight = DomainWarpOptions::new();
+        straight.set_amplitude(0.0);
+        options.set_warp(straight);
+        let straight_tiles = voronoi_continents(&options).expect("Failed to generate continents");
+        options.set_warp(DomainWarpOptions::new());
+        let warped_tiles = voronoi_continents(&options).expect("Failed to generate continents");
+
+        let moved = straight_tiles
+            .iter()
+            .zip(warped_tiles.iter())
+            .filter(|(straight, warped)| straight.terrain() != warped.terrain())
+            .count();
+        assert!(moved > 0);
+        assert!(warped_tiles.iter().any(|tile| tile.terrain() == TileType::Grassland));
+    }
+
+    #[test]
+    fn test_stencil_steers_the_continents() {
+        let image = GrayImage::from_fn(4, 4, |x, _| [if x < 2 { 255 } else { 0 }].into());
+        let mut options = ContinentOptions::new(120, 80, "stencil".to_string(), 150);
+        options.set_num_continents(3);
+        options.set_lloyd_iterations(5);
+        let mut stencil = StencilOptions::new(GrayscaleMask::from_image(&image));
+        stencil.set_strength(1.0);
+        options.set_stencil(Some(stencil));
+        let mut warp = DomainWarpOptions::new();
+        warp.set_amplitude(1.0);
+        warp.set_frequency(0.01);
+        warp.set_octaves(2);
+        options.set_warp(warp);
+        let tiles = voronoi_continents(&options).expect("Failed to generate continents");
+
+        let land_in = |columns: std::ops::Range<usize>| {
+            columns
+                .flat_map(|x| (0..80).map(move |y| (x, y)))
+                .filter(|position| tiles[*position].terrain() == TileType::Grassland)
+                .count()
+        };
+        assert!(land_in(0..60) > 3 * land_in(60..120));
+    }
+
+    #[test]
+    fn test_continent_map_matches_tiles() {
+        let mut options = ContinentOptions::new(120, 80, "metadata".to_string(), 80);
+        options.set_num_continents(3);
+        options.set_lloyd_iterations(5);
+        let map = voronoi_continent_map(&options, GridTopology::SQUARE, &NoProgress).expect("Failed to generate continents");
+
+        assert_eq!(map.tiles, voronoi_continents(&options).expect("Failed to generate continents"));
+        assert_eq!(map.continents.len(), 3);
+        for (tile, continent) in map.tiles.iter().zip(map.continent_ids.iter()) {
+            assert_eq!(tile.terrain() == TileType::Grassland, continent.is_some());
+        }
+
+        let total_area: f64 = map.continents.iter().map(|continent| continent.area).sum();
+        let land_tiles = map.continent_ids.iter().filter(|continent| continent.is_some()).count() as f64;
+        assert!((total_area - land_tiles).abs() < land_tiles * 0.2, "{total_area} {land_tiles}");
+        for (id, continent) in map.continents.iter().enumerate() {
+            assert_eq!(continent.id, id);
+            assert!(continent.min_x <= continent.centroid_x && continent.centroid_x <= continent.max_x);
+            assert!(continent.min_y <= continent.centroid_y && continent.centroid_y <= continent.max_y);
+            assert!(continent.coastline_length > 0.0);
+            assert!(map.continent_ids.iter().any(|tile_continent| *tile_continent == Some(id)));
+        }
+    }
+
+    #[test]
+    fn test_continents_cross_the_seam() {
+        let mut options = ContinentOptions::new(120, 80, "seam".to_string(), 150);
+        options.set_num_continents(2);
+        options.set_land_area_percentage(45.0);
+        options.set_lloyd_iterations(5);
+        let topology = GridTopology::SQUARE.with_wrap(WrapMode::Torus);
+        let map = voronoi_continent_map(&options, topology, &NoProgress).expect("Failed to generate continents");
+
+        let seam_tiles = (0..80).map(|y| ((0, y), (119, y))).chain((0..120).map(|x| ((x, 0), (x, 79))));
+        let mut crossings = 0;
+        for (start, end) in seam_tiles {
+            if map.continent_ids[start].is_some() && map.continent_ids[end].is_some() {
+                assert_eq!(map.continent_ids[start], map.continent_ids[end]);
+                crossings += 1;
+            }
+        }
+        assert!(crossings > 0);
+        for continent in &map.continents {
+            assert!((0.0..120.0).contains(&continent.centroid_x) && (0.0..80.0).contains(&continent.centroid_y));
+            assert!(continent.max_x - continent.min_x < 120.0 && continent.max_y - continent.min_y < 80.0);
+        }
+    }
 }
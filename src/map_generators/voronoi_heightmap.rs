@@ -1,19 +1,22 @@
+use crate::map_generators::grid::GridTopology;
+use crate::map_generators::parallel::par_from_fn_with_progress;
+use crate::map_generators::progress::{NoProgress, ProgressSink};
 use crate::map_generators::tile::Tile;
+use crate::map_generators::tiled_diagram::{DiagramError, TiledDiagram};
 use crate::map_generators::tile_types::TileType;
 use crate::math_helpers::*;
-use bevy::ecs::component::TickCells;
-use bevy_inspector_egui::egui::special_emojis::GIT;
+use crate::map_generators::map_generator::{MapGenerator, MapGeneratorError};
+use bevy::log::info_span;
 use nalgebra::DMatrix;
 use rand::prelude::*;
 use rand_pcg::Pcg64Mcg;
 use rand_seeder::Seeder;
+use serde::{Deserialize, Serialize};
 
 use voronoice::*;
 use std::collections::HashSet;
 use std::collections::HashMap;
 use std::collections::VecDeque;
-use std::sync::Arc;
-use fastnoise_lite::*;
 
 use thiserror::Error;
 use rand::distributions::WeightedError;
@@ -24,6 +27,21 @@ pub enum VoronoiError {
     DiagramCreationError,
     #[error(transparent)]
     DiagramChooseError(#[from] WeightedError),
+    #[error("Can't generate a {width}x{height} heightmap")]
+    EmptyMap { width: usize, height: usize },
+    #[error("Heightmap generation was cancelled")]
+    Cancelled,
+    #[error("Only {reached} of {total} cells are connected to the peaks")]
+    UnreachableCells { reached: usize, total: usize },
+}
+
+impl From<DiagramError> for VoronoiError {
+    fn from(error: DiagramError) -> Self {
+        match error {
+            DiagramError::CreationFailed => VoronoiError::DiagramCreationError,
+            DiagramError::Cancelled => VoronoiError::Cancelled,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -41,32 +59,107 @@ impl MapCell {
     }
 }
 
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct HeightMapOptions {
+    map_width: usize,
+    map_height: usize,
+    seed: String,
+    cell_count: usize,
     max_height: f64,
     height_step: f64,
     variance: Option<f64>,
     water_padding_percentage: f64,
     initial_sites: usize,
+    sea_level: f64,
 }
 
 impl HeightMapOptions {
-    pub fn new(max_height: f64, height_step: f64, variance: Option<f64>, water_padding_percentage: f64, initial_sites: usize) -> HeightMapOptions {
+    pub fn new(map_width: usize, map_height: usize, seed: String, cell_count: usize) -> HeightMapOptions {
         HeightMapOptions {
-            max_height,
-            height_step,
-            variance,
-            water_padding_percentage,
-            initial_sites,
+            map_width,
+            map_height,
+            seed,
+            cell_count,
+            max_height: 1.0,
+            height_step: 0.99,
+            variance: Some(0.2),
+            water_padding_percentage: 0.93,
+            initial_sites: 3,
+            sea_level: 0.2,
         }
     }
+
+    pub fn cell_count(&self) -> usize {
+        self.cell_count
+    }
+
+    pub fn set_max_height(&mut self, max_height: f64) {
+        self.max_height = max_height;
+    }
+
+    pub fn set_height_step(&mut self, height_step: f64) {
+        self.height_step = height_step;
+    }
+
+    pub fn set_variance(&mut self, variance: Option<f64>) {
+        self.variance = variance;
+    }
+
+    pub fn set_water_padding_percentage(&mut self, water_padding_percentage: f64) {
+        self.water_padding_percentage = water_padding_percentage;
+    }
+
+    pub fn set_initial_sites(&mut self, initial_sites: usize) {
+        self.initial_sites = initial_sites;
+    }
+
+    pub fn sea_level(&self) -> f64 {
+        self.sea_level
+    }
+
+    pub fn set_sea_level(&mut self, sea_level: f64) {
+        self.sea_level = sea_level;
+    }
 }
 
+impl Default for HeightMapOptions {
+    fn default() -> Self {
+        HeightMapOptions::new(1920, 1080, "Initial Seed".to_string(), 120)
+    }
+}
 
+pub struct VoronoiHeightmapGenerator {
+    options: HeightMapOptions,
+}
+
+impl MapGenerator for VoronoiHeightmapGenerator {
+    type Options = HeightMapOptions;
 
+    fn new(options: HeightMapOptions) -> VoronoiHeightmapGenerator {
+        VoronoiHeightmapGenerator { options }
+    }
 
-pub fn diagram_to_heightmap(diagram: &Voronoi, rng: &mut Pcg64Mcg, options: HeightMapOptions) -> Result<HashMap<usize, MapCell>, VoronoiError> {
-    let land_box = land_box(&diagram.bounding_box(), options.water_padding_percentage);
-    let cells: Vec<VoronoiCell> = diagram.iter_cells().collect();
+    fn name(&self) -> &'static str {
+        "voronoi_heightmap"
+    }
+
+    fn options(&self) -> &HeightMapOptions {
+        &self.options
+    }
+
+    fn generate_on_grid(&self, topology: GridTopology, progress: &dyn ProgressSink) -> Result<DMatrix<Tile>, MapGeneratorError> {
+        Ok(voronoi_heightmap_with_progress(&self.options, topology, progress)?)
+    }
+}
+
+pub fn diagram_to_heightmap(
+    diagram: &TiledDiagram,
+    rng: &mut Pcg64Mcg,
+    options: &HeightMapOptions,
+    progress: &dyn ProgressSink,
+) -> Result<HashMap<usize, MapCell>, VoronoiError> {
+    let land_box = diagram.land_box(options.water_padding_percentage);
+    let cells: Vec<VoronoiCell> = (0..diagram.site_count()).map(|cell_index| diagram.cell(cell_index)).collect();
     let initial_cells = cells.choose_multiple_weighted(rng, options.initial_sites,  |cell| {
         if is_cell_in_box(cell, &land_box) {
             1.0
@@ -76,7 +169,7 @@ pub fn diagram_to_heightmap(diagram: &Voronoi, rng: &mut Pcg64Mcg, options: Heig
         }
     }).map_err(VoronoiError::DiagramChooseError)?;
     
-    let total_sites = diagram.sites().len();
+    let total_sites = diagram.site_count();
     let mut cells = HashMap::with_capacity(total_sites);
     let mut used_cells = HashSet::with_capacity(total_sites);
 
@@ -86,79 +179,177 @@ pub fn diagram_to_heightmap(diagram: &Voronoi, rng: &mut Pcg64Mcg, options: Heig
         let cell_index = cell.site();
         used_cells.insert(cell_index);
         cells.insert(cell_index, MapCell::new(cell_index, options.max_height));
-        cell_queue.extend(cell.iter_neighbors());
+        cell_queue.extend(diagram.neighbors(cell_index));
     }
     
-    while used_cells.len() < total_sites {
-        while let Some(cell_index) = cell_queue.pop_front() {
-            let cell = diagram.cell(cell_index);
-            let in_water_buffer= !is_cell_in_box(&cell, &land_box);
-            if !in_water_buffer {
-                current_height = current_height * options.height_step;
-            }
+    while let Some(cell_index) = cell_queue.pop_front() {
+        if progress.is_cancelled() {
+            return Err(VoronoiError::Cancelled);
+        }
+        progress.report("cell_heights", used_cells.len(), total_sites);
+        let cell = diagram.cell(cell_index);
+        let in_water_buffer= !is_cell_in_box(&cell, &land_box);
+        if !in_water_buffer {
+            current_height *= options.height_step;
+        }
 
-            let height_variance = if !in_water_buffer {
-                options.variance.map_or(1.0, |variance| rng.gen::<f64>() * variance + 1.1  - variance)
-            } else {
-                0.0
-            };
-
-            let new_height = current_height * height_variance;
-            match cells.get(&cell_index) {
-                Some(map_cell) => {
-                    if map_cell.height < new_height {
-                        cells.remove(&cell_index);
-                        cells.insert(cell_index, MapCell::new(cell_index, new_height));
-                    }
-                }
-                None => {
+        let height_variance = if !in_water_buffer {
+            options.variance.map_or(1.0, |variance| rng.gen::<f64>() * variance + 1.1  - variance)
+        } else {
+            0.0
+        };
+
+        let new_height = current_height * height_variance;
+        match cells.get(&cell_index) {
+            Some(map_cell) => {
+                if map_cell.height < new_height {
+                    cells.remove(&cell_index);
                     cells.insert(cell_index, MapCell::new(cell_index, new_height));
-                    used_cells.insert(cell_index);
                 }
             }
-            for neighbor in cell.iter_neighbors() {
-                if !used_cells.contains(&neighbor) {
-                    cell_queue.push_back(neighbor);
-                }
+            None => {
+                cells.insert(cell_index, MapCell::new(cell_index, new_height));
+                used_cells.insert(cell_index);
+            }
+        }
+        for neighbor in diagram.neighbors(cell_index) {
+            if !used_cells.contains(&neighbor) {
+                cell_queue.push_back(neighbor);
             }
         }
     }
+    if used_cells.len() < total_sites {
+        return Err(VoronoiError::UnreachableCells { reached: used_cells.len(), total: total_sites });
+    }
 
     Ok(cells)
 }
 
-pub fn voronoi_heightmap(
-    map_size_x: u64,
-    map_size_y: u64,
-    seeder: &mut Seeder,
-    cell_count: u64,
-    options: HeightMapOptions,
+pub fn voronoi_heightmap(options: &HeightMapOptions) -> Result<DMatrix<Tile>, VoronoiError> {
+    voronoi_heightmap_with_progress(options, GridTopology::SQUARE, &NoProgress)
+}
+
+pub fn voronoi_heightmap_with_progress(
+    options: &HeightMapOptions,
+    topology: GridTopology,
+    progress: &dyn ProgressSink,
 ) -> Result<DMatrix<Tile>, VoronoiError> {
+    let _span = info_span!("voronoi_heightmap", seed = options.seed.as_str()).entered();
+    let map_size_x = options.map_width;
+    let map_size_y = options.map_height;
+    if map_size_x == 0 || map_size_y == 0 {
+        return Err(VoronoiError::EmptyMap { width: map_size_x, height: map_size_y });
+    }
     let half_x = map_size_x as f64 / 2.0;
     let half_y = map_size_y as f64 / 2.0;
+    let mut seeder = Seeder::from(options.seed.as_str());
     let mut rng: Pcg64Mcg = seeder.make_rng();
 
     let x_range = rand::distributions::Uniform::new(-half_x, half_x);
     let y_range = rand::distributions::Uniform::new(-half_y, half_y);
-    let sites: Vec<Point> = (0..cell_count)
+    let sites: Vec<Point> = (0..options.cell_count)
         .map(|_| Point {
             x: rng.sample(x_range),
             y: rng.sample(y_range),
         })
         .collect();
 
-    let voronoi_diagram = VoronoiBuilder::default()
-        .set_sites(sites)
-        .set_bounding_box(BoundingBox::new_centered(
-            map_size_x as f64,
-            map_size_y as f64,
-        ))
-        .set_lloyd_relaxation_iterations(30)
-        .build()
-        .ok_or(VoronoiError::DiagramCreationError)?;
+    let voronoi_diagram = TiledDiagram::relaxed(sites, map_size_x as f64, map_size_y as f64, topology.wrap(), 30, progress)?;
 
+    let heights = diagram_to_heightmap(&voronoi_diagram, &mut rng, options, progress)?;
 
-    //let cell_indexes = voronoi_diagram.sites().choose_multiple(rng, 2);
-    todo!()
+    let closest_cells = ClosestCellIndex::new(voronoi_diagram.diagram());
+    par_from_fn_with_progress(map_size_x, map_size_y, "tiles", progress, |row, column| {
+        let (center_x, center_y) = topology.tile_center(row, column);
+        let point = Point {
+            x: center_x - half_x,
+            y: center_y - half_y,
+        };
+        let closest_index = closest_cells.closest_cell(&point);
+        let elevation = smoothed_height(&point, &voronoi_diagram, &heights, closest_index) / options.max_height;
 
-}
\ No newline at end of file
+        if elevation < options.sea_level {
+            Tile::new(TileType::Water, elevation)
+        } else {
+            Tile::new(TileType::Grassland, elevation)
+        }
+    })
+    .ok_or(VoronoiError::Cancelled)
+}
+
+/// Blends the height of the closest cell with its neighbors using inverse
+/// distance weighting so elevation doesn't step at every cell edge. The
+/// cells can be copies across a seam, which take the height of their
+/// original.
+fn smoothed_height(point: &Point, diagram: &TiledDiagram, heights: &HashMap<usize, MapCell>, closest_index: usize) -> f64 {
+    let height_of = |cell_index: usize| {
+        heights.get(&diagram.own_cell(cell_index)).map_or(0.0, |map_cell| map_cell.height)
+    };
+    let closest = diagram.cell(closest_index);
+
+    let mut weighted_height = 0.0;
+    let mut total_weight = 0.0;
+    for cell_index in std::iter::once(closest_index).chain(closest.iter_neighbors()) {
+        let cell_distance = distance(point, diagram.cell(cell_index).site_position());
+        if cell_distance < f64::EPSILON {
+            return height_of(cell_index);
+        }
+        let weight = 1.0 / cell_distance.powi(2);
+        weighted_height += weight * height_of(cell_index);
+        total_weight += weight;
+    }
+
+    weighted_height / total_weight
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{voronoi_heightmap, voronoi_heightmap_with_progress, HeightMapOptions, VoronoiError};
+    use crate::map_generators::{GridTopology, NoProgress, TileType, WrapMode};
+
+    #[test]
+    fn test_voronoi_heightmap_shape_and_sea_level() {
+        let options = HeightMapOptions::new(64, 48, "heightmap test".to_string(), 60);
+        let tiles = voronoi_heightmap(&options).expect("Failed to generate a heightmap");
+
+        assert_eq!(tiles.shape(), (64, 48));
+        for tile in tiles.iter() {
+            assert!(tile.elevation().is_finite());
+            assert_eq!(tile.terrain() == TileType::Water, tile.elevation() < options.sea_level());
+        }
+        assert!(tiles.iter().any(|tile| tile.terrain() == TileType::Grassland));
+        assert!(tiles.iter().any(|tile| tile.terrain() == TileType::Water));
+    }
+
+    #[test]
+    fn test_voronoi_heightmap_is_deterministic() {
+        let options = HeightMapOptions::new(32, 32, "same seed".to_string(), 40);
+        let first = voronoi_heightmap(&options).expect("Failed to generate a heightmap");
+        let second = voronoi_heightmap(&options).expect("Failed to generate a heightmap");
+
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_rejects_empty_maps() {
+        for (width, height) in [(0, 48), (64, 0)] {
+            let options = HeightMapOptions::new(width, height, "empty".to_string(), 60);
+            let result = voronoi_heightmap(&options);
+            assert!(matches!(result, Err(VoronoiError::EmptyMap { width: w, height: h }) if w == width && h == height));
+        }
+    }
+
+    #[test]
+    fn test_wrapped_heightmap_is_seamless() {
+        let options = HeightMapOptions::new(64, 48, "heightmap test".to_string(), 60);
+        let topology = GridTopology::SQUARE.with_wrap(WrapMode::Horizontal);
+        let tiles = voronoi_heightmap_with_progress(&options, topology, &NoProgress).expect("Failed to generate a heightmap");
+
+        let step = |x: usize, next_x: usize| {
+            (0..48).map(|y| (tiles[(x, y)].elevation() - tiles[(next_x, y)].elevation()).abs()).fold(0.0, f64::max)
+        };
+        let steepest = (0..63).map(|x| step(x, x + 1)).fold(0.0, f64::max);
+        assert!(step(63, 0) <= steepest);
+        assert!((0..48).any(|y| tiles[(0, y)].terrain() == TileType::Grassland));
+    }
+}
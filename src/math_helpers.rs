@@ -1,3 +1,4 @@
+use bevy::log::warn;
 use voronoice::*;
 use rand_seeder::Seeder;
 
@@ -11,7 +12,7 @@ pub fn shoelace_area(points: Vec<&Point>) -> f64 {
     let first_point = match points.first() {
         Some(point) => point,
         None => {
-            println!("Empty vector??????");
+            warn!("Shoelace area of a polygon without points");
             return area;
         }
     };
@@ -27,6 +28,29 @@ pub fn shoelace_area(points: Vec<&Point>) -> f64 {
 
     (area / 2.0).abs()
 }
+/// Center of mass of a polygon, or the mean of its points when it has no
+/// area.
+pub fn polygon_centroid(points: &[&Point]) -> Point {
+    let mut twice_area = 0.0;
+    let (mut x, mut y) = (0.0, 0.0);
+    for (index, point) in points.iter().enumerate() {
+        let next_point = points[(index + 1) % points.len()];
+        let cross = point.x * next_point.y - next_point.x * point.y;
+        twice_area += cross;
+        x += (point.x + next_point.x) * cross;
+        y += (point.y + next_point.y) * cross;
+    }
+
+    if twice_area.abs() < f64::EPSILON {
+        let count = points.len().max(1) as f64;
+        return Point {
+            x: points.iter().map(|point| point.x).sum::<f64>() / count,
+            y: points.iter().map(|point| point.y).sum::<f64>() / count,
+        };
+    }
+    Point { x: x / (3.0 * twice_area), y: y / (3.0 * twice_area) }
+}
+
 pub fn shoelace_area_of_cell(cell: VoronoiCell) -> f64 {
     shoelace_area(cell.iter_vertices().collect())
 }
@@ -57,7 +81,7 @@ pub fn is_cell_in_box(cell: &VoronoiCell, bounding_box: &BoundingBox) -> bool {
             return false;
         }
     }
-    return true;
+    true
 }
 
 pub fn closest_cell(point: &Point, diagram: &Voronoi) -> usize {
@@ -76,12 +100,125 @@ pub fn closest_cell(point: &Point, diagram: &Voronoi) -> usize {
     closest_cell.site()
 }
 
+/// Nearest site lookup over a uniform grid of buckets.
+///
+/// Returns exactly what [`closest_cell`] returns, including picking the lowest
+/// index when several sites are equally close, but only looks at the buckets
+/// around the point instead of every site.
+pub struct ClosestCellIndex {
+    sites: Vec<Point>,
+    min_x: f64,
+    min_y: f64,
+    bucket_size: f64,
+    columns: usize,
+    rows: usize,
+    buckets: Vec<Vec<usize>>,
+}
+
+impl ClosestCellIndex {
+    /// Aim for about this many sites per bucket.
+    const SITES_PER_BUCKET: f64 = 2.0;
+
+    pub fn new(diagram: &Voronoi) -> ClosestCellIndex {
+        let sites = diagram.sites().clone();
+        let (min_x, min_y, max_x, max_y) = sites.iter().fold(
+            (f64::MAX, f64::MAX, f64::MIN, f64::MIN),
+            |(min_x, min_y, max_x, max_y), site| (min_x.min(site.x), min_y.min(site.y), max_x.max(site.x), max_y.max(site.y)),
+        );
+        let width = (max_x - min_x).max(f64::EPSILON);
+        let height = (max_y - min_y).max(f64::EPSILON);
+        let bucket_size = (width * height * Self::SITES_PER_BUCKET / sites.len().max(1) as f64)
+            .sqrt()
+            .max(width.max(height) / 4096.0);
+        let columns = (width / bucket_size) as usize + 1;
+        let rows = (height / bucket_size) as usize + 1;
+
+        let mut index = ClosestCellIndex {
+            sites: Vec::new(),
+            min_x,
+            min_y,
+            bucket_size,
+            columns,
+            rows,
+            buckets: vec![Vec::new(); columns * rows],
+        };
+        for (site_index, site) in sites.iter().enumerate() {
+            let (column, row) = index.bucket_of(site);
+            index.buckets[column + row * columns].push(site_index);
+        }
+        index.sites = sites;
+        index
+    }
+
+    /// Bucket containing a point, clamped to the grid.
+    fn bucket_of(&self, point: &Point) -> (usize, usize) {
+        let column = ((point.x - self.min_x) / self.bucket_size).floor().max(0.0) as usize;
+        let row = ((point.y - self.min_y) / self.bucket_size).floor().max(0.0) as usize;
+        (column.min(self.columns - 1), row.min(self.rows - 1))
+    }
+
+    pub fn closest_cell(&self, point: &Point) -> usize {
+        let (column, row) = self.bucket_of(point);
+        let mut best = (f64::MAX, 0);
+
+        for ring in 0..self.columns.max(self.rows) {
+            let left = column.checked_sub(ring);
+            let right = Some(column + ring).filter(|&right| right < self.columns);
+            let bottom = row.checked_sub(ring);
+            let top = Some(row + ring).filter(|&top| top < self.rows);
+
+            for ring_row in row.saturating_sub(ring)..=(row + ring).min(self.rows - 1) {
+                let on_edge = Some(ring_row) == bottom || Some(ring_row) == top;
+                for ring_column in column.saturating_sub(ring)..=(column + ring).min(self.columns - 1) {
+                    if !on_edge && Some(ring_column) != left && Some(ring_column) != right {
+                        continue;
+                    }
+                    for &site_index in &self.buckets[ring_column + ring_row * self.columns] {
+                        let site_distance = distance(point, &self.sites[site_index]);
+                        if site_distance < best.0 || (site_distance == best.0 && site_index < best.1) {
+                            best = (site_distance, site_index);
+                        }
+                    }
+                }
+            }
+
+            // Anything in the next ring is at least as far away as the closest
+            // edge of this block that still has buckets behind it.
+            let mut next_ring_distance = f64::MAX;
+            if let Some(left) = left.filter(|&left| left > 0) {
+                next_ring_distance = next_ring_distance.min(point.x - (self.min_x + left as f64 * self.bucket_size));
+            }
+            if let Some(right) = right.filter(|&right| right + 1 < self.columns) {
+                next_ring_distance = next_ring_distance.min(self.min_x + (right + 1) as f64 * self.bucket_size - point.x);
+            }
+            if let Some(bottom) = bottom.filter(|&bottom| bottom > 0) {
+                next_ring_distance = next_ring_distance.min(point.y - (self.min_y + bottom as f64 * self.bucket_size));
+            }
+            if let Some(top) = top.filter(|&top| top + 1 < self.rows) {
+                next_ring_distance = next_ring_distance.min(self.min_y + (top + 1) as f64 * self.bucket_size - point.y);
+            }
+            // Keep going on near ties so rounding can't hide an equally close
+            // site with a lower index.
+            if next_ring_distance > best.0 * (1.0 + 1e-9) + 1e-9 {
+                break;
+            }
+        }
+
+        best.1
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
     use super::distance;
     use super::shoelace_area;
+    use super::polygon_centroid;
     use super::closest_cell;
+    use super::ClosestCellIndex;
+    use rand::Rng;
+    use rand_pcg::Pcg64Mcg;
+    use rand_seeder::Seeder;
     use voronoice::*;
 
     #[test]
@@ -124,6 +261,27 @@ mod tests {
         assert_eq!(2.0, shoelace_area(sides));
     }
 
+    #[test]
+    fn test_polygon_centroid() {
+        let points = [
+            Point { x: 0.0, y: 0.0 },
+            Point { x: 4.0, y: 0.0 },
+            Point { x: 4.0, y: 2.0 },
+            Point { x: 0.0, y: 2.0 },
+        ];
+        let centroid = polygon_centroid(&points.iter().collect::<Vec<&Point>>());
+        assert_eq!((centroid.x, centroid.y), (2.0, 1.0));
+
+        let line = [Point { x: 0.0, y: 0.0 }, Point { x: 2.0, y: 2.0 }];
+        let centroid = polygon_centroid(&line.iter().collect::<Vec<&Point>>());
+        assert_eq!((centroid.x, centroid.y), (1.0, 1.0));
+    }
+
+    #[test]
+    fn test_shoelace_empty() {
+        assert_eq!(0.0, shoelace_area(Vec::new()));
+    }
+
 
     #[test]
     fn test_closest_cell_site_point() {
@@ -188,4 +346,53 @@ mod tests {
         assert_eq!(closest, site_0_index);
     }
 
+    #[test]
+    fn test_closest_cell_index_matches_linear_scan() {
+        let mut rng: Pcg64Mcg = Seeder::from("closest cell index").make_rng();
+        let sites: Vec<Point> = (0..500)
+            .map(|_| Point {
+                x: rng.gen_range(-200.0..200.0),
+                y: rng.gen_range(-100.0..100.0),
+            })
+            .collect();
+        let diagram = VoronoiBuilder::default()
+            .set_sites(sites)
+            .set_bounding_box(BoundingBox::new_centered(400.0, 200.0))
+            .set_lloyd_relaxation_iterations(3)
+            .build()
+            .expect("Failed to create a voronoi diagram");
+        let index = ClosestCellIndex::new(&diagram);
+
+        for _ in 0..5000 {
+            // Reach past the bounding box so points outside the grid are covered too.
+            let point = Point {
+                x: rng.gen_range(-260.0..260.0),
+                y: rng.gen_range(-130.0..130.0),
+            };
+            assert_eq!(index.closest_cell(&point), closest_cell(&point, &diagram));
+        }
+    }
+
+    #[test]
+    fn test_closest_cell_index_breaks_ties_like_linear_scan() {
+        let sites: Vec<Point> = (0..25)
+            .map(|index| Point {
+                x: (index % 5) as f64 * 10.0 - 20.0,
+                y: (index / 5) as f64 * 10.0 - 20.0,
+            })
+            .collect();
+        let diagram = VoronoiBuilder::default()
+            .set_sites(sites)
+            .set_bounding_box(BoundingBox::new_centered(50.0, 50.0))
+            .build()
+            .expect("Failed to create a voronoi diagram");
+        let index = ClosestCellIndex::new(&diagram);
+
+        for x in -25..=25 {
+            for y in -25..=25 {
+                let point = Point { x: x as f64, y: y as f64 };
+                assert_eq!(index.closest_cell(&point), closest_cell(&point, &diagram));
+            }
+        }
+    }
 }